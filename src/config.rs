@@ -1,7 +1,5 @@
 use std::{
-    fs::File,
-    io::{BufReader, BufWriter},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::LazyLock,
 };
 
@@ -12,17 +10,77 @@ use color_eyre::eyre::{Context, Report};
 use directories::ProjectDirs;
 use tracing::instrument;
 
-#[derive(Default, Debug)]
-pub struct Config(serde_json::Value);
+/// How [`Config`] is stored on disk, the keys are the same whatever the format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConfigFormat {
+    #[default]
+    Json,
+    #[cfg(feature = "toml")]
+    Toml,
+}
+
+impl ConfigFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            #[cfg(feature = "toml")]
+            Self::Toml => "toml",
+        }
+    }
+
+    /// The format of the configuration of `profile`, TOML only if there is a file for it already.
+    fn detect(profile: Option<&str>) -> Self {
+        #[cfg(feature = "toml")]
+        if config_file(profile, Self::Toml).exists() {
+            return Self::Toml;
+        }
+        _ = profile;
+        Self::Json
+    }
+
+    fn parse(self, raw: &str) -> Option<serde_json::Value> {
+        match self {
+            Self::Json => serde_json::from_str(raw).ok(),
+            #[cfg(feature = "toml")]
+            Self::Toml => toml::from_str(raw).ok(),
+        }
+    }
+
+    fn to_string(self, value: &serde_json::Value) -> Result<String, Report> {
+        Ok(match self {
+            Self::Json => serde_json::to_string_pretty(value)?,
+            #[cfg(feature = "toml")]
+            Self::Toml => toml::to_string_pretty(value)?,
+        })
+    }
+}
+
+/// Whatever the format, the settings are kept as a tree of serde values, strings at the top
+/// level for the [`Session`] ones.
+#[derive(Debug)]
+pub struct Config {
+    path: PathBuf,
+    format: ConfigFormat,
+    value: serde_json::Value,
+}
 
-static CFG_FILE: LazyLock<PathBuf> = LazyLock::new(|| {
+static CFG_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     let dirs = ProjectDirs::from("dev", "shurizzle", "kobodown").unwrap();
-    dirs.config_dir().join("kobodown.json")
+    dirs.config_dir().to_path_buf()
 });
 
+fn config_file(profile: Option<&str>, format: ConfigFormat) -> PathBuf {
+    let ext = format.extension();
+    if let Some(profile) = profile {
+        CFG_DIR.join(format!("kobodown.{profile}.{ext}"))
+    } else {
+        CFG_DIR.join(format!("kobodown.{ext}"))
+    }
+}
+
 impl Config {
     fn gets<'a>(&'a self, name: &str) -> Option<&'a NonEmptyStr> {
-        if let serde_json::Value::Object(ref obj) = self.0 {
+        if let serde_json::Value::Object(ref obj) = self.value {
             if let serde_json::Value::String(ref obj) = obj.get(name)? {
                 NonEmptyStr::new(obj)
             } else {
@@ -34,7 +92,7 @@ impl Config {
     }
 
     fn dels(&mut self, name: &str) {
-        let serde_json::Value::Object(ref mut obj) = self.0 else {
+        let serde_json::Value::Object(ref mut obj) = self.value else {
             return;
         };
         obj.remove(name);
@@ -42,10 +100,10 @@ impl Config {
 
     fn set(&mut self, name: &str, v: serde_json::Value) {
         let obj = loop {
-            if let serde_json::Value::Object(ref mut obj) = self.0 {
+            if let serde_json::Value::Object(ref mut obj) = self.value {
                 break obj;
             } else {
-                self.0 = serde_json::Value::Object(Default::default());
+                self.value = serde_json::Value::Object(Default::default());
             }
         };
 
@@ -64,14 +122,37 @@ impl Config {
         }
     }
 
+    /// Loads the configuration of the given profile, or the default one if `profile` is `None`.
+    ///
+    /// With the `toml` feature `kobodown.toml` is preferred when it exists, `kobodown.json` is
+    /// used otherwise.
+    #[instrument]
+    pub fn load(profile: Option<&str>) -> Self {
+        Self::load_as(profile, ConfigFormat::detect(profile))
+    }
+
+    /// Like [`Config::load`], reading and saving the file of the given format.
     #[instrument]
-    pub fn load() -> Self {
-        Self(
-            File::open(&*CFG_FILE)
-                .ok()
-                .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
-                .unwrap_or_default(),
-        )
+    pub fn load_as(profile: Option<&str>, format: ConfigFormat) -> Self {
+        let path = config_file(profile, format);
+        let value = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|raw| format.parse(&raw))
+            .unwrap_or_default();
+        Self {
+            path,
+            format,
+            value,
+        }
+    }
+
+    /// Where the configuration is read from and saved to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn format(&self) -> ConfigFormat {
+        self.format
     }
 }
 
@@ -138,15 +219,20 @@ impl Session for Config {
         self.sets("UserKey", v)
     }
 
+    fn library_sync_token(&self) -> Option<&NonEmptyStr> {
+        self.gets("LibrarySyncToken")
+    }
+
+    fn set_library_sync_token<S: Into<String>>(&mut self, v: Option<S>) {
+        self.sets("LibrarySyncToken", v)
+    }
+
     #[instrument]
     fn save(&self) -> Result<(), Report> {
-        if let Some(d) = (*CFG_FILE).parent() {
+        if let Some(d) = self.path.parent() {
             std::fs::create_dir_all(d).wrap_err("cannot create configuration dir")?;
         }
-        serde_json::to_writer_pretty(
-            BufWriter::new(File::create(&*CFG_FILE).wrap_err("cannot create configuration file")?),
-            &self.0,
-        )
-        .wrap_err("cannot create configuration file")
+        let raw = self.format.to_string(&self.value)?;
+        std::fs::write(&self.path, raw).wrap_err("cannot create configuration file")
     }
 }
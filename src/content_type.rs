@@ -34,6 +34,11 @@ pub struct Iter<'a> {
     rest: &'a [u8],
 }
 
+/// Like [`Iter`] but skips malformed parameters instead of stopping.
+pub struct LossyIter<'a> {
+    rest: &'a [u8],
+}
+
 pub struct Value<'a> {
     copy: bool,
     inner: &'a [u8],
@@ -161,6 +166,24 @@ fn pull_parameter(buf: &[u8]) -> Option<(&str, Value<'_>, &[u8])> {
     Some((key, value, buf))
 }
 
+/// Skips to the next `;` that is not inside a quoted string.
+fn skip_parameter(mut buf: &[u8]) -> &[u8] {
+    let mut quoted = false;
+    while let Some((&c, b)) = buf.split_first() {
+        match c {
+            b';' if !quoted => break,
+            b'"' => quoted = !quoted,
+            b'\\' if quoted => {
+                buf = b.split_first().map_or(b, |(_, b)| b);
+                continue;
+            }
+            _ => (),
+        }
+        buf = b;
+    }
+    buf
+}
+
 impl MediaType<'_> {
     pub fn into_static(self) -> MediaType<'static> {
         MediaType {
@@ -168,6 +191,35 @@ impl MediaType<'_> {
             subtype: Cow::Owned(self.subtype.into_owned()),
         }
     }
+
+    /// Media types are case-insensitive.
+    pub fn eq_ignore_ascii_case(&self, type_: &str, subtype: &str) -> bool {
+        self.type_.eq_ignore_ascii_case(type_) && self.subtype.eq_ignore_ascii_case(subtype)
+    }
+
+    /// `application/json` or any `+json` structured syntax.
+    pub fn is_json(&self) -> bool {
+        let suffix = self.subtype.len().saturating_sub(5);
+        self.eq_ignore_ascii_case("application", "json")
+            || self
+                .subtype
+                .get(suffix..)
+                .is_some_and(|s| s.eq_ignore_ascii_case("+json"))
+    }
+
+    pub fn is_html(&self) -> bool {
+        self.eq_ignore_ascii_case("text", "html")
+    }
+
+    /// `type/subtype` in lowercase.
+    pub fn essence(&self) -> String {
+        let mut res = String::with_capacity(self.type_.len() + self.subtype.len() + 1);
+        res.push_str(&self.type_);
+        res.push('/');
+        res.push_str(&self.subtype);
+        res.make_ascii_lowercase();
+        res
+    }
 }
 
 impl core::fmt::Display for InvalidContentType {
@@ -209,27 +261,38 @@ impl<'a> ContentType<'a> {
     pub fn parameters(&self) -> Iter<'a> {
         Iter { rest: self.rest }
     }
+
+    #[inline(always)]
+    pub fn parameters_lossy(&self) -> LossyIter<'a> {
+        LossyIter { rest: self.rest }
+    }
+
+    /// The value of the first well formed parameter called `name`, ignoring case.
+    pub fn get_parameter(&self, name: &str) -> Option<Value<'a>> {
+        self.parameters_lossy()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
 }
 
 impl<'a> Iterator for Iter<'a> {
     type Item = Result<(&'a str, Value<'a>), InvalidContentType>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.rest.is_empty() {
-            return None;
-        }
-        self.rest = ows(self.rest);
-        {
+        // Parameters can be empty, as in `text/html;;charset=utf-8`.
+        loop {
+            self.rest = ows(self.rest);
             let (&c, b) = self.rest.split_first()?;
-            self.rest = b;
             if c != b';' {
                 self.rest = b"";
                 return Some(Err(InvalidContentType));
             }
-        };
-        self.rest = ows(self.rest);
-        if self.rest.is_empty() {
-            return None;
+            self.rest = ows(b);
+            match self.rest.first() {
+                None => return None,
+                Some(b';') => (),
+                Some(_) => break,
+            }
         }
         if let Some((k, v, rest)) = pull_parameter(self.rest) {
             self.rest = rest;
@@ -241,6 +304,30 @@ impl<'a> Iterator for Iter<'a> {
     }
 }
 
+impl<'a> Iterator for LossyIter<'a> {
+    type Item = (&'a str, Value<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.rest = ows(self.rest);
+            let (&c, b) = self.rest.split_first()?;
+            if c != b';' {
+                self.rest = skip_parameter(self.rest);
+                continue;
+            }
+            self.rest = ows(b);
+            if self.rest.is_empty() {
+                return None;
+            }
+            if let Some((k, v, rest)) = pull_parameter(self.rest) {
+                self.rest = rest;
+                return Some((k, v));
+            }
+            self.rest = skip_parameter(self.rest);
+        }
+    }
+}
+
 impl<'a> Value<'a> {
     pub fn value(&self) -> Cow<'a, [u8]> {
         if self.copy {
@@ -267,3 +354,194 @@ impl<'a> Value<'a> {
         }
     }
 }
+
+/// The file name suggested by a `Content-Disposition` header, `filename*` winning over
+/// `filename`.
+///
+/// Only the UTF-8 and ISO-8859-1 charsets of `filename*` are understood, the name is returned
+/// as is and can contain directories.
+pub fn content_disposition_filename(buf: &[u8]) -> Option<String> {
+    let (_, rest) = pull_token(ows(buf))?;
+    let mut name = None;
+    for (key, value) in (LossyIter { rest }) {
+        if key.eq_ignore_ascii_case("filename*") {
+            if let Some(value) = ext_value(&value.value()) {
+                return Some(value);
+            }
+        } else if key.eq_ignore_ascii_case("filename") && name.is_none() {
+            name = String::from_utf8(value.value().into_owned()).ok();
+        }
+    }
+    name
+}
+
+/// Decodes a `charset'language'value` extended parameter (RFC 8187).
+fn ext_value(buf: &[u8]) -> Option<String> {
+    let mut parts = buf.splitn(3, |&c| c == b'\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let value = percent_decode(parts.next()?)?;
+    if charset.eq_ignore_ascii_case(b"utf-8") {
+        String::from_utf8(value).ok()
+    } else if charset.eq_ignore_ascii_case(b"iso-8859-1") {
+        Some(value.into_iter().map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+pub(crate) fn percent_decode(buf: &[u8]) -> Option<Vec<u8>> {
+    let mut res = Vec::with_capacity(buf.len());
+    let mut it = buf.iter().copied();
+    while let Some(c) = it.next() {
+        if c == b'%' {
+            let hi = char::from(it.next()?).to_digit(16)?;
+            let lo = char::from(it.next()?).to_digit(16)?;
+            res.push((hi * 16 + lo) as u8);
+        } else {
+            res.push(c);
+        }
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(header: &str) -> Result<Vec<(String, String)>, InvalidContentType> {
+        ContentType::parse(header.as_bytes())?
+            .parameters()
+            .map(|param| {
+                param.map(|(key, value)| {
+                    (
+                        key.to_string(),
+                        String::from_utf8(value.value().into_owned()).unwrap(),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn media_type() {
+        let ct = ContentType::parse(b"  Application/Vnd.Api+JSON").unwrap();
+        assert_eq!(ct.media_type().type_, "Application");
+        assert_eq!(ct.media_type().subtype, "Vnd.Api+JSON");
+        assert_eq!(ct.media_type().essence(), "application/vnd.api+json");
+        assert!(ct.media_type().is_json());
+        assert!(ct.parameters().next().is_none());
+    }
+
+    #[test]
+    fn missing_subtype() {
+        for header in [
+            "text",
+            "text/",
+            "text/;charset=utf-8",
+            "/html",
+            "",
+            "text /html",
+        ] {
+            assert!(ContentType::parse(header.as_bytes()).is_err(), "{header:?}");
+        }
+    }
+
+    #[test]
+    fn multiple_parameters() {
+        assert_eq!(
+            params("multipart/form-data;boundary=abc;charset=utf-8;q=0.5").unwrap(),
+            pairs(&[("boundary", "abc"), ("charset", "utf-8"), ("q", "0.5")])
+        );
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(
+            params("text/html \t; charset=utf-8\t ;  level=1 ").unwrap(),
+            pairs(&[("charset", "utf-8"), ("level", "1")])
+        );
+        // No whitespace is allowed around `=`.
+        assert!(params("text/html; charset =utf-8").is_err());
+        assert!(params("text/html; charset= utf-8").is_err());
+    }
+
+    #[test]
+    fn empty_parameters() {
+        assert_eq!(params("text/html;").unwrap(), pairs(&[]));
+        assert_eq!(params("text/html ; ").unwrap(), pairs(&[]));
+        assert_eq!(
+            params("text/html;;charset=utf-8;;").unwrap(),
+            pairs(&[("charset", "utf-8")])
+        );
+    }
+
+    #[test]
+    fn quoted_strings() {
+        assert_eq!(
+            params(r#"text/plain; a="x y;z"; b="say \"hi\""; c="back\\slash"; d="""#).unwrap(),
+            pairs(&[
+                ("a", "x y;z"),
+                ("b", r#"say "hi""#),
+                ("c", r"back\slash"),
+                ("d", ""),
+            ])
+        );
+        let ct = ContentType::parse(br#"text/plain; a="plain""#).unwrap();
+        let (_, value) = ct.parameters().next().unwrap().unwrap();
+        assert!(matches!(value.value(), Cow::Borrowed(b"plain")));
+    }
+
+    #[test]
+    fn malformed_parameters() {
+        for header in [
+            "text/html; charset",
+            "text/html; =utf-8",
+            "text/html; charset=",
+            "text/html; charset=\"utf-8",
+            "text/html; charset=\"utf-8\"x",
+            "text/html; charset=utf@8",
+            "text/html charset=utf-8",
+        ] {
+            assert!(params(header).is_err(), "{header:?}");
+        }
+    }
+
+    #[test]
+    fn lossy_parameters() {
+        let ct = ContentType::parse(br#"text/html; bad; x="a;b" junk; Charset=UTF-8"#).unwrap();
+        let keys = ct
+            .parameters_lossy()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["x", "Charset"]);
+        assert_eq!(
+            ct.get_parameter("charset").unwrap().value().as_ref(),
+            b"UTF-8"
+        );
+        assert!(ct.get_parameter("boundary").is_none());
+    }
+
+    #[test]
+    fn disposition_filename() {
+        assert_eq!(
+            content_disposition_filename(
+                br#"attachment; filename="book.epub"; filename*=UTF-8''b%C3%B6%C3%B6k.epub"#
+            )
+            .as_deref(),
+            Some("böök.epub")
+        );
+        assert_eq!(
+            content_disposition_filename(br#"attachment; filename="a \"b\".epub""#).as_deref(),
+            Some(r#"a "b".epub"#)
+        );
+        assert_eq!(content_disposition_filename(b"inline"), None);
+    }
+}
@@ -0,0 +1,745 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Read, Seek, Write},
+    num::NonZeroUsize,
+};
+
+use aes::cipher::{generic_array::GenericArray, BlockDecryptMut, KeyInit};
+use parking_lot::Mutex;
+use tracing::warn;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+type Aes128EcbDec = ecb::Decryptor<aes::Aes128>;
+
+const BLOCK_SIZE: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DecryptError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid padding")]
+    Padding,
+    #[error("{0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Decryption workers stopped")]
+    WorkersStopped,
+    #[error("Invalid {entry}: {reason}")]
+    InvalidEntry { entry: Box<str>, reason: Box<str> },
+}
+
+fn invalid<R: std::fmt::Display>(entry: &str, reason: R) -> DecryptError {
+    DecryptError::InvalidEntry {
+        entry: entry.into(),
+        reason: reason.to_string().into(),
+    }
+}
+
+/// The keys of the encrypted entries of a book, by entry name.
+pub type ContentKeys = HashMap<Box<str>, aes::cipher::Key<aes::Aes128Dec>>;
+
+/// How the entries of a decrypted book are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Deflate at the given level from 0 to 9, the default one when `None`.
+    Deflate(Option<i64>),
+    Stored,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::Deflate(None)
+    }
+}
+
+/// See [`decrypt_book`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecryptOptions {
+    pub compression: Compression,
+    /// Copies the entries that are not encrypted as they are instead of recompressing them.
+    pub copy_unencrypted: bool,
+    /// Threads decrypting entries, the archive is still read and written by the caller's.
+    pub jobs: NonZeroUsize,
+    /// Runs [`validate_book`] once the entries are written, so that wrong keys are caught before
+    /// the book is finished.
+    pub validate: bool,
+    /// Strips the markup Kobo adds to kepubs from the XHTML entries, for other readers.
+    pub clean_kepub: bool,
+}
+
+impl Default for DecryptOptions {
+    fn default() -> Self {
+        Self {
+            compression: Compression::default(),
+            copy_unencrypted: false,
+            jobs: NonZeroUsize::MIN,
+            validate: false,
+            clean_kepub: false,
+        }
+    }
+}
+
+impl DecryptOptions {
+    fn zip_options(&self) -> SimpleFileOptions {
+        let options = SimpleFileOptions::default();
+        match self.compression {
+            Compression::Stored => options.compression_method(CompressionMethod::Stored),
+            Compression::Deflate(level) => options
+                .compression_method(CompressionMethod::DEFLATE)
+                .compression_level(level),
+        }
+    }
+}
+
+fn fill<R: Read>(reader: &mut R, mut buf: &mut [u8]) -> std::io::Result<usize> {
+    let len = buf.len();
+    while !buf.is_empty() {
+        match reader.read(buf) {
+            Ok(0) => break,
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len - buf.len())
+}
+
+/// Decrypts an AES128-ECB, PKCS7 padded stream from `reader` into `writer`.
+///
+/// The data is processed in fixed-size chunks, so memory usage doesn't depend on the size of
+/// the entry.
+pub fn decrypt_entry<R: Read, W: Write>(
+    key: &aes::cipher::Key<aes::Aes128Dec>,
+    mut reader: R,
+    mut writer: W,
+) -> Result<(), DecryptError> {
+    let mut cipher = Aes128EcbDec::new(key);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut filled = 0;
+
+    loop {
+        let n = fill(&mut reader, &mut buf[filled..])?;
+        filled += n;
+
+        if filled < buf.len() {
+            let data = cipher
+                .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut buf[..filled])
+                .map_err(|_| DecryptError::Padding)?;
+            writer.write_all(data)?;
+            return Ok(());
+        }
+
+        // Hold back the last block: it might be the one carrying the padding.
+        let end = filled - BLOCK_SIZE;
+        for block in buf[..end].chunks_exact_mut(BLOCK_SIZE) {
+            cipher.decrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+        writer.write_all(&buf[..end])?;
+        buf.copy_within(end.., 0);
+        filled = BLOCK_SIZE;
+    }
+}
+
+/// Decrypts a whole AES128-ECB, PKCS7 padded buffer in place, dropping the padding.
+pub fn decrypt_in_place(
+    key: &aes::cipher::Key<aes::Aes128Dec>,
+    data: &mut Vec<u8>,
+) -> Result<(), DecryptError> {
+    let len = Aes128EcbDec::new(key)
+        .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(data)
+        .map_err(|_| DecryptError::Padding)?
+        .len();
+    data.truncate(len);
+    Ok(())
+}
+
+/// The EPUB spec wants `mimetype` stored, entries stored in the source are likely not worth
+/// compressing either.
+fn entry_options(
+    options: SimpleFileOptions,
+    name: &str,
+    method: CompressionMethod,
+) -> SimpleFileOptions {
+    if name == "mimetype" || method == CompressionMethod::Stored {
+        options
+            .compression_method(CompressionMethod::Stored)
+            .compression_level(None)
+    } else {
+        options
+    }
+}
+
+/// Whether the entry `i` can be copied without recompressing it.
+fn copy_raw<R: Read + Seek>(
+    keys: &ContentKeys,
+    inzip: &mut ZipArchive<R>,
+    i: usize,
+    clean_kepub: bool,
+) -> Result<bool, DecryptError> {
+    let entry = inzip.by_index_raw(i)?;
+    Ok(!keys.contains_key(entry.name())
+        && (!clean_kepub || !is_xhtml(entry.name()))
+        && (entry.name() != "mimetype" || entry.compression() == CompressionMethod::Stored))
+}
+
+fn is_xhtml(name: &str) -> bool {
+    name.rsplit_once('.').is_some_and(|(_, ext)| {
+        ["xhtml", "html", "htm"]
+            .iter()
+            .any(|e| ext.eq_ignore_ascii_case(e))
+    })
+}
+
+fn parse_xhtml(text: &str) -> Result<roxmltree::Document<'_>, roxmltree::Error> {
+    let options = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    roxmltree::Document::parse_with_options(text, options)
+}
+
+/// Replaces every named entity reference in `text` with as many spaces.
+fn blank_entities(text: &str) -> String {
+    let mut res = text.as_bytes().to_vec();
+    for (start, _) in text.match_indices('&') {
+        let name = &text.as_bytes()[start + 1..];
+        let len = name
+            .iter()
+            .take_while(|b| b.is_ascii_alphanumeric())
+            .count();
+        if len > 0 && name.get(len) == Some(&b';') {
+            res[start..start + len + 2].fill(b' ');
+        }
+    }
+    // Only ASCII is replaced, with ASCII.
+    String::from_utf8(res).unwrap()
+}
+
+/// Removes the markup Kobo adds to the XHTML of kepubs: the `koboSpan` spans around every
+/// sentence and the `book-columns` and `book-inner` divs are unwrapped, the `kobostylehacks`
+/// style is dropped. The rest of the text is kept byte for byte.
+///
+/// `None` when there is nothing to remove or the entry `name` doesn't parse, which is logged.
+fn clean_xhtml(name: &str, data: &[u8]) -> Option<Vec<u8>> {
+    let Ok(text) = std::str::from_utf8(data) else {
+        warn!("{name} is left as is, it isn't UTF-8");
+        return None;
+    };
+    let bom = if text.starts_with('\u{feff}') { 3 } else { 0 };
+    let text = &text[bom..];
+    let blanked;
+    let doc = match parse_xhtml(text) {
+        // HTML entities like `&nbsp;` are only defined by the XHTML DTD, which isn't read. Only
+        // the markup matters to find the cuts, so they are blanked out in a copy of the same
+        // length.
+        Err(roxmltree::Error::UnknownEntityReference(..)) => {
+            blanked = blank_entities(text);
+            parse_xhtml(&blanked)
+        }
+        res => res,
+    };
+    let doc = match doc {
+        Ok(doc) => doc,
+        Err(err) => {
+            warn!("{name} is left as is, it doesn't parse: {err}");
+            return None;
+        }
+    };
+    let mut cuts = Vec::new();
+    for node in doc.descendants().filter(roxmltree::Node::is_element) {
+        let unwrap = match node.tag_name().name() {
+            "span" => node
+                .attribute("class")
+                .is_some_and(|class| class.split_ascii_whitespace().any(|c| c == "koboSpan")),
+            "div" => matches!(node.attribute("id"), Some("book-columns" | "book-inner")),
+            "style" if node.attribute("id") == Some("kobostylehacks") => {
+                cuts.push(node.range());
+                continue;
+            }
+            _ => false,
+        };
+        if !unwrap {
+            continue;
+        }
+        let range = node.range();
+        match (node.first_child(), node.last_child()) {
+            (Some(first), Some(last)) => {
+                cuts.push(range.start..first.range().start);
+                cuts.push(last.range().end..range.end);
+            }
+            _ => cuts.push(range),
+        }
+    }
+    if cuts.is_empty() {
+        return None;
+    }
+    cuts.sort_unstable_by_key(|cut| cut.start);
+    let mut res = Vec::with_capacity(data.len());
+    res.extend_from_slice(&data[..bom]);
+    let data = &data[bom..];
+    let mut pos = 0;
+    for cut in cuts {
+        // Nested cuts are already gone with the outer one.
+        if cut.start < pos {
+            continue;
+        }
+        res.extend_from_slice(&data[pos..cut.start]);
+        pos = cut.end;
+    }
+    res.extend_from_slice(&data[pos..]);
+    Some(res)
+}
+
+/// Decrypts the book read from `reader`, as downloaded, into a plain EPUB written to `writer`.
+pub fn decrypt_book<R: Read + Seek, W: Write + Seek>(
+    keys: &ContentKeys,
+    reader: R,
+    writer: W,
+    options: &DecryptOptions,
+) -> Result<W, DecryptError> {
+    decrypt_book_with_progress(keys, reader, writer, options, |_, _| ())
+}
+
+/// Like [`decrypt_book`], calling `progress` with the entries written so far and their total,
+/// once before starting too.
+pub fn decrypt_book_with_progress<R, W, F>(
+    keys: &ContentKeys,
+    reader: R,
+    writer: W,
+    options: &DecryptOptions,
+    mut progress: F,
+) -> Result<W, DecryptError>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+    F: FnMut(usize, usize),
+{
+    let zip_options = options.zip_options();
+    let mut outzip = ZipWriter::new(writer);
+    let mut inzip = ZipArchive::new(reader)?;
+    let total = inzip.len();
+    progress(0, total);
+
+    if options.jobs.get() > 1 {
+        decrypt_entries_parallel(keys, &mut inzip, &mut outzip, options, &mut |done| {
+            progress(done, total)
+        })?;
+    } else {
+        for i in 0..total {
+            if options.copy_unencrypted && copy_raw(keys, &mut inzip, i, options.clean_kepub)? {
+                outzip.raw_copy_file(inzip.by_index_raw(i)?)?;
+                progress(i + 1, total);
+                continue;
+            }
+            let mut infile = inzip.by_index(i)?;
+            let entry_options = entry_options(zip_options, infile.name(), infile.compression());
+            outzip.start_file(infile.name(), entry_options)?;
+            if options.clean_kepub && is_xhtml(infile.name()) {
+                let mut data = Vec::with_capacity(infile.size() as usize);
+                infile.read_to_end(&mut data)?;
+                if let Some(key) = keys.get(infile.name()) {
+                    decrypt_in_place(key, &mut data)?;
+                }
+                outzip.write_all(
+                    clean_xhtml(infile.name(), &data)
+                        .as_deref()
+                        .unwrap_or(&data),
+                )?;
+            } else if let Some(key) = keys.get(infile.name()) {
+                decrypt_entry(key, &mut infile, &mut outzip)?;
+            } else {
+                std::io::copy(&mut infile, &mut outzip)?;
+            }
+            progress(i + 1, total);
+        }
+    }
+    if options.validate {
+        validate_book(keys, &mut inzip)?;
+    }
+    Ok(outzip.finish()?)
+}
+
+const CONTAINER: &str = "META-INF/container.xml";
+
+/// The entry `name` of `inzip` decrypted, `None` if there is no such entry.
+fn read_entry<R: Read + Seek>(
+    keys: &ContentKeys,
+    inzip: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<Option<Vec<u8>>, DecryptError> {
+    let mut entry = match inzip.by_name(name) {
+        Ok(entry) => entry,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut data = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut data)?;
+    if let Some(key) = keys.get(name) {
+        decrypt_in_place(key, &mut data).map_err(|err| invalid(name, err))?;
+    }
+    Ok(Some(data))
+}
+
+/// Parses the entry `name`, decoding it as its BOM says or as UTF-8.
+fn parse_entry<T>(
+    name: &str,
+    data: &[u8],
+    f: impl FnOnce(&roxmltree::Document) -> T,
+) -> Result<T, DecryptError> {
+    let (text, _, malformed) = encoding_rs::UTF_8.decode(data);
+    if malformed {
+        return Err(invalid(name, "not UTF-8 text, the key may be wrong"));
+    }
+    roxmltree::Document::parse(&text)
+        .map(|doc| f(&doc))
+        .map_err(|err| invalid(name, err))
+}
+
+/// Checks that `META-INF/container.xml` parses and names a package document that is in the book
+/// and parses too, decrypting them with `keys` like [`decrypt_book`] does.
+///
+/// Garbage from wrong keys seldom parses as XML, so this catches them without opening the whole
+/// book.
+pub fn validate_book<R: Read + Seek>(
+    keys: &ContentKeys,
+    inzip: &mut ZipArchive<R>,
+) -> Result<(), DecryptError> {
+    let container = read_entry(keys, inzip, CONTAINER)?
+        .ok_or_else(|| invalid(CONTAINER, "missing from the book"))?;
+    let package = parse_entry(CONTAINER, &container, |doc| {
+        doc.descendants()
+            .find(|node| node.has_tag_name("rootfile"))
+            .and_then(|node| node.attribute("full-path"))
+            .map(str::to_string)
+    })?
+    .ok_or_else(|| invalid(CONTAINER, "no rootfile with a full-path"))?;
+    let data = read_entry(keys, inzip, &package)?.ok_or_else(|| {
+        invalid(
+            &package,
+            format_args!("missing from the book, named by {CONTAINER}"),
+        )
+    })?;
+    parse_entry(&package, &data, |_| ())
+}
+
+/// Reads the entries sequentially, decrypts them on `jobs` worker threads and writes them
+/// back in the original order.
+fn decrypt_entries_parallel<R: Read + Seek, W: Write + Seek>(
+    keys: &ContentKeys,
+    inzip: &mut ZipArchive<R>,
+    outzip: &mut ZipWriter<W>,
+    options: &DecryptOptions,
+    progress: &mut dyn FnMut(usize),
+) -> Result<(), DecryptError> {
+    /// The key is `None` for entries that only need cleaning, the name is there for entries that
+    /// need it.
+    type Job<'a> = (
+        usize,
+        Option<&'a aes::cipher::Key<aes::Aes128Dec>>,
+        Option<Box<str>>,
+        Vec<u8>,
+    );
+
+    /// `None` stands for an entry copied as is from the input.
+    fn flush<R: Read + Seek, W: Write + Seek>(
+        ready: &mut BTreeMap<usize, Option<Vec<u8>>>,
+        next: &mut usize,
+        entries: &[(Box<str>, SimpleFileOptions)],
+        inzip: &mut ZipArchive<R>,
+        outzip: &mut ZipWriter<W>,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<(), DecryptError> {
+        while let Some(data) = ready.remove(next) {
+            if let Some(data) = data {
+                let (ref name, options) = entries[*next];
+                outzip.start_file(&**name, options)?;
+                outzip.write_all(&data)?;
+            } else {
+                outzip.raw_copy_file(inzip.by_index_raw(*next)?)?;
+            }
+            *next += 1;
+            progress(*next);
+        }
+        Ok(())
+    }
+
+    let jobs = options.jobs;
+    let zip_options = options.zip_options();
+    let (job_tx, job_rx) = std::sync::mpsc::sync_channel::<Job>(jobs.get());
+    let (res_tx, res_rx) = std::sync::mpsc::channel();
+    let job_rx = Mutex::new(job_rx);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.get() {
+            let job_rx = &job_rx;
+            let res_tx = res_tx.clone();
+            scope.spawn(move || loop {
+                let Ok((i, key, clean, mut data)) = job_rx.lock().recv() else {
+                    break;
+                };
+                let res = key
+                    .map_or(Ok(()), |key| decrypt_in_place(key, &mut data))
+                    .map(
+                        |()| match clean.and_then(|name| clean_xhtml(&name, &data)) {
+                            Some(cleaned) => cleaned,
+                            None => data,
+                        },
+                    );
+                if res_tx.send((i, res)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(res_tx);
+
+        let mut entries = Vec::with_capacity(inzip.len());
+        let mut ready = BTreeMap::new();
+        let mut next = 0;
+        for i in 0..inzip.len() {
+            let raw = options.copy_unencrypted && copy_raw(keys, inzip, i, options.clean_kepub)?;
+            {
+                let mut infile = inzip.by_index(i)?;
+                entries.push((
+                    Box::<str>::from(infile.name()),
+                    entry_options(zip_options, infile.name(), infile.compression()),
+                ));
+                if raw {
+                    ready.insert(i, None);
+                } else {
+                    let mut data = Vec::with_capacity(infile.size() as usize);
+                    infile.read_to_end(&mut data)?;
+                    let key = keys.get(infile.name());
+                    let clean = (options.clean_kepub && is_xhtml(infile.name()))
+                        .then(|| Box::<str>::from(infile.name()));
+                    if key.is_some() || clean.is_some() {
+                        job_tx
+                            .send((i, key, clean, data))
+                            .map_err(|_| DecryptError::WorkersStopped)?;
+                    } else {
+                        ready.insert(i, Some(data));
+                    }
+                }
+            }
+            while let Ok((i, res)) = res_rx.try_recv() {
+                ready.insert(i, Some(res?));
+            }
+            flush(&mut ready, &mut next, &entries, inzip, outzip, progress)?;
+        }
+        drop(job_tx);
+        for (i, res) in res_rx {
+            ready.insert(i, Some(res?));
+            flush(&mut ready, &mut next, &entries, inzip, outzip, progress)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut};
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+
+    fn encrypt(data: &[u8]) -> Vec<u8> {
+        ecb::Encryptor::<aes::Aes128>::new(&KEY.into()).encrypt_padded_vec_mut::<Pkcs7>(data)
+    }
+
+    fn plain(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    /// Hands out at most 1000 bytes per read, so chunks get filled over several reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn streaming_matches_buffered() {
+        let key = KEY.into();
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - BLOCK_SIZE,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            4 * 1024 * 1024 + 7,
+        ] {
+            let data = plain(len);
+            let encrypted = encrypt(&data);
+
+            let mut buffered = encrypted.clone();
+            decrypt_in_place(&key, &mut buffered).unwrap();
+            let mut streamed = Vec::new();
+            decrypt_entry(&key, encrypted.as_slice(), &mut streamed).unwrap();
+            let mut trickled = Vec::new();
+            decrypt_entry(&key, Trickle(&encrypted), &mut trickled).unwrap();
+
+            assert_eq!(buffered, data, "buffered, {len} bytes");
+            assert_eq!(streamed, buffered, "streamed, {len} bytes");
+            assert_eq!(trickled, buffered, "trickled, {len} bytes");
+        }
+    }
+
+    #[test]
+    fn streaming_rejects_bad_padding() {
+        let mut encrypted = encrypt(&plain(CHUNK_SIZE * 2));
+        encrypted.truncate(encrypted.len() - 1);
+        assert!(matches!(
+            decrypt_entry(&KEY.into(), encrypted.as_slice(), std::io::sink()),
+            Err(DecryptError::Padding)
+        ));
+    }
+
+    fn clean(xhtml: &str) -> Option<String> {
+        clean_xhtml("test.xhtml", xhtml.as_bytes()).map(|data| String::from_utf8(data).unwrap())
+    }
+
+    #[test]
+    fn clean_unwraps_nested_kobo_spans() {
+        assert_eq!(
+            clean(
+                r#"<p><span class="koboSpan" id="k1"><b><span class="x koboSpan">A</span></b> B</span></p>"#
+            )
+            .as_deref(),
+            Some("<p><b>A</b> B</p>")
+        );
+    }
+
+    #[test]
+    fn clean_unwraps_book_divs() {
+        assert_eq!(
+            clean(
+                r#"<body><div id="book-columns"><div id="book-inner"><p>A</p></div></div><div id="other">B</div></body>"#
+            )
+            .as_deref(),
+            Some(r#"<body><p>A</p><div id="other">B</div></body>"#)
+        );
+    }
+
+    #[test]
+    fn clean_drops_kobo_style_hacks() {
+        assert_eq!(
+            clean(r#"<head><style id="kobostylehacks">div { }</style><style>p { }</style></head>"#)
+                .as_deref(),
+            Some("<head><style>p { }</style></head>")
+        );
+    }
+
+    #[test]
+    fn clean_keeps_the_bom() {
+        assert_eq!(
+            clean("\u{feff}<p><span class=\"koboSpan\">A</span></p>").as_deref(),
+            Some("\u{feff}<p>A</p>")
+        );
+    }
+
+    #[test]
+    fn clean_with_html_entities() {
+        assert_eq!(
+            clean(
+                r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd"><p title="&eacute;"><span class="koboSpan">A&nbsp;&amp;&#160;B&hellip;</span></p>"#
+            )
+            .as_deref(),
+            Some(
+                r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd"><p title="&eacute;">A&nbsp;&amp;&#160;B&hellip;</p>"#
+            )
+        );
+    }
+
+    #[test]
+    fn clean_leaves_nothing_to_remove_or_broken_entries() {
+        assert_eq!(clean("<p>A</p>"), None);
+        assert_eq!(clean(r#"<p><span class="koboSpan">A</p>"#), None);
+        assert_eq!(clean_xhtml("test.xhtml", b"<p>\xff</p>"), None);
+    }
+
+    /// A book with a stored mimetype, a stored and a deflated plain entry, and an encrypted
+    /// XHTML entry, along with its keys.
+    fn sample_book() -> (Vec<u8>, ContentKeys) {
+        let time = zip::DateTime::from_date_and_time(2020, 1, 2, 3, 4, 6).unwrap();
+        let stored = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .last_modified_time(time);
+        let deflated = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::DEFLATE)
+            .compression_level(Some(9))
+            .last_modified_time(time);
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, options, data) in [
+            ("mimetype", stored, b"application/epub+zip".to_vec()),
+            ("OEBPS/image.png", stored, plain(3000)),
+            (
+                "OEBPS/style.css",
+                deflated,
+                b"p { margin: 0 }\n".repeat(100),
+            ),
+            ("OEBPS/text.xhtml", deflated, encrypt(b"<p>text</p>")),
+        ] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(&data).unwrap();
+        }
+        let keys = ContentKeys::from([("OEBPS/text.xhtml".into(), KEY.into())]);
+        (zip.finish().unwrap().into_inner(), keys)
+    }
+
+    /// The compressed bytes of the entry `name` and what its header says about them.
+    #[allow(clippy::type_complexity)]
+    fn raw_entry(
+        zip: &mut ZipArchive<Cursor<&[u8]>>,
+        name: &str,
+    ) -> (Vec<u8>, CompressionMethod, u32, u64, Option<zip::DateTime>) {
+        let mut entry = zip.by_index_raw(zip.index_for_name(name).unwrap()).unwrap();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).unwrap();
+        (
+            data,
+            entry.compression(),
+            entry.crc32(),
+            entry.size(),
+            entry.last_modified(),
+        )
+    }
+
+    #[test]
+    fn copy_unencrypted_keeps_the_raw_entries() {
+        let (book, keys) = sample_book();
+        for jobs in [1, 4] {
+            let options = DecryptOptions {
+                compression: Compression::Deflate(Some(1)),
+                copy_unencrypted: true,
+                jobs: NonZeroUsize::new(jobs).unwrap(),
+                ..Default::default()
+            };
+            let out = decrypt_book(&keys, Cursor::new(&book), Cursor::new(Vec::new()), &options)
+                .unwrap()
+                .into_inner();
+
+            let mut input = ZipArchive::new(Cursor::new(book.as_slice())).unwrap();
+            let mut output = ZipArchive::new(Cursor::new(out.as_slice())).unwrap();
+            for name in ["mimetype", "OEBPS/image.png", "OEBPS/style.css"] {
+                assert_eq!(
+                    raw_entry(&mut output, name),
+                    raw_entry(&mut input, name),
+                    "{name}, {jobs} jobs"
+                );
+            }
+            let mut text = String::new();
+            output
+                .by_name("OEBPS/text.xhtml")
+                .unwrap()
+                .read_to_string(&mut text)
+                .unwrap();
+            assert_eq!(text, "<p>text</p>", "{jobs} jobs");
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use color_eyre::eyre::{Context, Report};
+use tracing::{instrument, warn};
+
+use crate::{Config, NonEmptyStr, Session};
+
+const SERVICE: &str = "kobodown";
+const ACCESS_TOKEN: usize = 0;
+const REFRESH_TOKEN: usize = 1;
+const USER_KEY: usize = 2;
+const SECRETS: [&str; 3] = ["AccessToken", "RefreshToken", "UserKey"];
+
+/// A [`Session`] keeping the tokens in the OS keyring and everything else in [`Config`].
+///
+/// If no keyring service is available it behaves exactly like [`Config`].
+pub struct KeyringSession {
+    config: Config,
+    entries: Option<[::keyring::Entry; 3]>,
+    secrets: [Option<Box<NonEmptyStr>>; 3],
+}
+
+impl core::fmt::Debug for KeyringSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyringSession")
+            .field("config", &self.config)
+            .field("keyring", &self.entries.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+fn entries(profile: Option<&str>) -> Result<[::keyring::Entry; 3], ::keyring::Error> {
+    let user = profile.unwrap_or("default");
+    let [a, b, c] = SECRETS.map(|name| ::keyring::Entry::new(SERVICE, &format!("{user}/{name}")));
+    Ok([a?, b?, c?])
+}
+
+fn get(config: &Config, i: usize) -> Option<&NonEmptyStr> {
+    match i {
+        ACCESS_TOKEN => config.access_token(),
+        REFRESH_TOKEN => config.refresh_token(),
+        _ => config.user_key(),
+    }
+}
+
+fn remove(config: &mut Config, i: usize) {
+    match i {
+        ACCESS_TOKEN => config.remove_access_token(),
+        REFRESH_TOKEN => config.remove_refresh_token(),
+        _ => config.remove_user_key(),
+    }
+}
+
+impl KeyringSession {
+    /// Loads the session of the given profile, or the default one if `profile` is `None`.
+    ///
+    /// Tokens found in the configuration file are moved to the keyring on the next save.
+    #[instrument]
+    pub fn load(profile: Option<&str>) -> Self {
+        let mut config = Config::load(profile);
+        let mut secrets = [None, None, None];
+        let entries = entries(profile).and_then(|entries| {
+            for (secret, entry) in secrets.iter_mut().zip(&entries) {
+                match entry.get_password() {
+                    Ok(s) => *secret = NonEmptyStr::from_string(s),
+                    Err(::keyring::Error::NoEntry) => (),
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(entries)
+        });
+        let entries = match entries {
+            Ok(entries) => {
+                for (i, secret) in secrets.iter_mut().enumerate() {
+                    if let Some(s) = get(&config, i) {
+                        if secret.is_none() {
+                            *secret = NonEmptyStr::r#box(s);
+                        }
+                        remove(&mut config, i);
+                    }
+                }
+                Some(entries)
+            }
+            Err(err) => {
+                warn!("keyring not available, storing tokens in the configuration file: {err}");
+                secrets = [None, None, None];
+                None
+            }
+        };
+
+        Self {
+            config,
+            entries,
+            secrets,
+        }
+    }
+
+    /// Where the non-secret part of the session is saved to.
+    pub fn path(&self) -> &std::path::Path {
+        self.config.path()
+    }
+
+    /// Whether the tokens are kept in the keyring rather than in the configuration file.
+    pub fn uses_keyring(&self) -> bool {
+        self.entries.is_some()
+    }
+
+    fn secret(&self, i: usize) -> Option<&NonEmptyStr> {
+        if self.entries.is_some() {
+            self.secrets[i].as_deref()
+        } else {
+            get(&self.config, i)
+        }
+    }
+
+    fn remove_secret(&mut self, i: usize) {
+        if self.entries.is_some() {
+            self.secrets[i] = None;
+        } else {
+            remove(&mut self.config, i);
+        }
+    }
+
+    fn set_secret<S: Into<String>>(&mut self, i: usize, v: Option<S>) {
+        if self.entries.is_some() {
+            self.secrets[i] = v.map(Into::into).and_then(NonEmptyStr::from_string);
+        } else {
+            match i {
+                ACCESS_TOKEN => self.config.set_access_token(v),
+                REFRESH_TOKEN => self.config.set_refresh_token(v),
+                _ => self.config.set_user_key(v),
+            }
+        }
+    }
+}
+
+impl Session for KeyringSession {
+    type Error = Report;
+
+    fn access_token(&self) -> Option<&NonEmptyStr> {
+        self.secret(ACCESS_TOKEN)
+    }
+
+    fn device_id(&self) -> Option<&NonEmptyStr> {
+        self.config.device_id()
+    }
+
+    fn refresh_token(&self) -> Option<&NonEmptyStr> {
+        self.secret(REFRESH_TOKEN)
+    }
+
+    fn user_id(&self) -> Option<&NonEmptyStr> {
+        self.config.user_id()
+    }
+
+    fn user_key(&self) -> Option<&NonEmptyStr> {
+        self.secret(USER_KEY)
+    }
+
+    fn remove_access_token(&mut self) {
+        self.remove_secret(ACCESS_TOKEN)
+    }
+
+    fn remove_device_id(&mut self) {
+        self.config.remove_device_id()
+    }
+
+    fn remove_refresh_token(&mut self) {
+        self.remove_secret(REFRESH_TOKEN)
+    }
+
+    fn remove_user_id(&mut self) {
+        self.config.remove_user_id()
+    }
+
+    fn remove_user_key(&mut self) {
+        self.remove_secret(USER_KEY)
+    }
+
+    fn set_access_token<S: Into<String>>(&mut self, v: Option<S>) {
+        self.set_secret(ACCESS_TOKEN, v)
+    }
+
+    fn set_device_id<S: Into<String>>(&mut self, v: Option<S>) {
+        self.config.set_device_id(v)
+    }
+
+    fn set_refresh_token<S: Into<String>>(&mut self, v: Option<S>) {
+        self.set_secret(REFRESH_TOKEN, v)
+    }
+
+    fn set_user_id<S: Into<String>>(&mut self, v: Option<S>) {
+        self.config.set_user_id(v)
+    }
+
+    fn set_user_key<S: Into<String>>(&mut self, v: Option<S>) {
+        self.set_secret(USER_KEY, v)
+    }
+
+    fn library_sync_token(&self) -> Option<&NonEmptyStr> {
+        self.config.library_sync_token()
+    }
+
+    fn set_library_sync_token<S: Into<String>>(&mut self, v: Option<S>) {
+        self.config.set_library_sync_token(v)
+    }
+
+    #[instrument]
+    fn save(&self) -> Result<(), Report> {
+        if let Some(ref entries) = self.entries {
+            for ((entry, secret), name) in entries.iter().zip(&self.secrets).zip(SECRETS) {
+                match secret {
+                    Some(secret) => entry.set_password(secret),
+                    None => match entry.delete_credential() {
+                        Err(::keyring::Error::NoEntry) => Ok(()),
+                        res => res,
+                    },
+                }
+                .wrap_err_with(|| format!("cannot store {name} in the keyring"))?;
+            }
+        }
+        self.config.save()
+    }
+}
@@ -1,38 +1,63 @@
+#[cfg(feature = "async")]
+mod asynchronous;
 mod book;
+mod builder;
 #[cfg(feature = "curl")]
 mod curl;
+mod device;
 mod js;
+mod limiter;
 mod request;
+#[cfg(feature = "reqwest")]
+mod reqwest;
+#[cfg(any(test, feature = "testing"))]
+mod testing;
 #[cfg(feature = "ureq")]
 mod ureq;
 mod url;
 
-pub use book::{AccessBook, Book, BookInfo};
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncKobo, AsyncTransport};
+pub use book::{
+    AccessBook, Book, BookInfo, DRMType, FormatInfo, InvalidProductId, ProductId, UrlFormat,
+    WishlistItem,
+};
+pub use builder::KoboBuilder;
 #[cfg(feature = "curl")]
 pub use curl::CurlAgent;
+pub use device::{is_locale, DeviceProfile};
+pub use limiter::RateLimiter;
 pub use request::*;
+#[cfg(any(test, feature = "testing"))]
+pub use testing::FnTransport;
+#[cfg(feature = "ureq")]
+pub use ureq::UreqBody;
 
 use scraper::{Html, Selector};
 
-use std::{borrow::Cow, io::Read, str::FromStr, sync::LazyLock};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    io::{Read, Seek, Write},
+    num::NonZeroUsize,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock,
+    },
+    time::{Duration, Instant},
+};
 
 use ::url::Url;
 use base64::Engine;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use tracing::instrument;
+use tracing::{instrument, warn};
 
-use crate::{Session, SessionAdapter};
+use crate::{LoginStatus, Session, SessionAdapter};
 
-const AFFILIATE: &str = "Kobo";
-const APPLICATION_VERSION: &str = "10.1.2.39807";
-const DEFAULT_PLATFORM_ID: &str = "00000000-0000-0000-0000-000000004000";
-const CARRIER_NAME: &str = "310270";
-const DEVICE_MODEL: &str = "Pixel";
-const DEVICE_OS_VERSION: &str = "33";
-const DISPLAY_PROFILE: &str = "Android";
-// Use the user agent of the Kobo Android app, otherwise the login request hangs forever.
-const USER_AGENT: &str = "Mozilla/5.0 (Linux; Android 13; Pixel Build/TQ2B.230505.005.A1; wv) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/101.0.4951.61 Safari/537.36 KoboApp/10.1.2.39807 KoboPlatform Id/00000000-0000-0000-0000-000000004000 KoboAffiliate/Kobo KoboBuildFlavor/global";
+const DEFAULT_BASE_URL: &str = "https://storeapi.kobo.com/";
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "curl")] {
@@ -44,42 +69,34 @@ cfg_if::cfg_if! {
     }
 }
 
-fn default_headers<T>(req: &mut http::Request<T>) {
+fn default_headers<T>(profile: &DeviceProfile, req: &mut http::Request<T>) {
     let hs = req.headers_mut();
-    hs.insert("User-Agent", http::HeaderValue::from_static(USER_AGENT));
-    hs.insert(
-        "x-kobo-affiliatename",
-        http::HeaderValue::from_static(AFFILIATE),
-    );
-    hs.insert(
-        "x-kobo-appversion",
-        http::HeaderValue::from_static(APPLICATION_VERSION),
-    );
-    hs.insert(
-        "x-kobo-platformid",
-        http::HeaderValue::from_static(DEFAULT_PLATFORM_ID),
-    );
-    hs.insert(
-        "x-kobo-carriername",
-        http::HeaderValue::from_static(CARRIER_NAME),
-    );
-    hs.insert(
-        "x-kobo-devicemodel",
-        http::HeaderValue::from_static(DEVICE_MODEL),
-    );
-    hs.insert("x-kobo-deviceos", http::HeaderValue::from_static("Android"));
-    hs.insert(
-        "x-kobo-deviceosversion",
-        http::HeaderValue::from_static(DEVICE_OS_VERSION),
-    );
-    hs.insert(
-        "X-Requested-With",
-        http::HeaderValue::from_static("com.kobobooks.android"),
-    );
-    hs.insert(
-        "Accept-Encoding",
-        http::HeaderValue::from_static("gzip, deflate"),
-    );
+    let mut insert = |name: &'static str, value: &str| {
+        if let Ok(value) = http::HeaderValue::from_str(value) {
+            hs.insert(name, value);
+        }
+    };
+    insert("User-Agent", &profile.user_agent());
+    insert("x-kobo-affiliatename", &profile.affiliate);
+    insert("x-kobo-appversion", &profile.app_version);
+    insert("x-kobo-platformid", &profile.platform_id);
+    insert("x-kobo-carriername", &profile.carrier_name);
+    insert("x-kobo-devicemodel", &profile.device_model);
+    insert("x-kobo-deviceos", "Android");
+    insert("x-kobo-deviceosversion", &profile.device_os_version);
+    insert("X-Requested-With", "com.kobobooks.android");
+    insert("Accept-Encoding", "gzip, deflate");
+}
+
+/// Replaces the headers of `req` named in `custom` with their custom values.
+fn custom_headers<B>(custom: &::http::HeaderMap, req: &mut ::http::Request<B>) {
+    let headers = req.headers_mut();
+    for name in custom.keys() {
+        headers.remove(name);
+        for value in custom.get_all(name) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -108,10 +125,40 @@ pub enum Error<T: std::error::Error + Send + Sync + 'static, S: Send + Sync + 's
     InvalidContentType,
     #[error("Not logged in")]
     NotLoggedIn,
+    #[error("The session has expired")]
+    SessionExpired,
     #[error("Invalid login flow")]
     LoginFlow,
+    #[error("Cannot find the sign in form, Kobo may have changed the page: {0}")]
+    SignInPage(Box<str>),
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+    #[error("The captcha was rejected or has expired")]
+    CaptchaExpired,
+    #[error("Kobo requires additional verification, complete it by signing in from a browser")]
+    VerificationRequired,
     #[error("Invalid status code {0}")]
     StatusCode(::http::StatusCode),
+    #[error("Invalid Content-Range")]
+    InvalidContentRange,
+    #[error("Too many redirects")]
+    TooManyRedirects,
+    #[error("Request timed out")]
+    Timeout,
+    #[error("Download cancelled")]
+    Cancelled,
+    #[error("Connection failed: {0}")]
+    Connection(T),
+    #[error("TLS error: {0}")]
+    Tls(T),
+    #[error("Proxy {0} is not supported by this transport")]
+    UnsupportedProxy(Box<str>),
+    #[error("Format {0} is not available for this book")]
+    FormatUnavailable(UrlFormat),
+    #[error("Kobo is still preparing this book, try again later")]
+    NotReady,
+    #[error("Downloaded {actual} bytes, expected {expected}")]
+    SizeMismatch { expected: u64, actual: u64 },
     #[error("{0}")]
     Io(#[from] std::io::Error),
     #[error("{0}")]
@@ -124,10 +171,158 @@ pub enum Error<T: std::error::Error + Send + Sync + 'static, S: Send + Sync + 's
     Session(S),
 }
 
+impl<T: std::error::Error + Send + Sync + 'static, S: Send + Sync + 'static> Error<T, S> {
+    /// Converts the session error with `f`, e.g. to report an error from a copy of a session as
+    /// one of the original.
+    pub fn map_session_err<S2: Send + Sync + 'static>(
+        self,
+        f: impl FnOnce(S) -> S2,
+    ) -> Error<T, S2> {
+        match self {
+            Self::InvalidEncoding(v) => Error::InvalidEncoding(v),
+            Self::InvalidContentType => Error::InvalidContentType,
+            Self::NotLoggedIn => Error::NotLoggedIn,
+            Self::SessionExpired => Error::SessionExpired,
+            Self::LoginFlow => Error::LoginFlow,
+            Self::SignInPage(v) => Error::SignInPage(v),
+            Self::InvalidCredentials => Error::InvalidCredentials,
+            Self::CaptchaExpired => Error::CaptchaExpired,
+            Self::VerificationRequired => Error::VerificationRequired,
+            Self::StatusCode(v) => Error::StatusCode(v),
+            Self::InvalidContentRange => Error::InvalidContentRange,
+            Self::TooManyRedirects => Error::TooManyRedirects,
+            Self::Timeout => Error::Timeout,
+            Self::Cancelled => Error::Cancelled,
+            Self::Connection(v) => Error::Connection(v),
+            Self::Tls(v) => Error::Tls(v),
+            Self::UnsupportedProxy(v) => Error::UnsupportedProxy(v),
+            Self::FormatUnavailable(v) => Error::FormatUnavailable(v),
+            Self::NotReady => Error::NotReady,
+            Self::SizeMismatch { expected, actual } => Error::SizeMismatch { expected, actual },
+            Self::Io(v) => Error::Io(v),
+            Self::Json(v) => Error::Json(v),
+            Self::Form(v) => Error::Form(v),
+            Self::Transport(v) => Error::Transport(v),
+            Self::Session(v) => Error::Session(f(v)),
+        }
+    }
+}
+
+/// A sync page that could not be read, identified by its position starting from 1.
+#[derive(thiserror::Error, Debug)]
+#[error("Sync page {page}: {error}")]
+pub struct PageError<T: std::error::Error + Send + Sync + 'static, S: Send + Sync + 'static> {
+    pub page: usize,
+    #[source]
+    pub error: Error<T, S>,
+}
+
+/// The books gathered by a sync, along with the pages that failed on the way.
+///
+/// A page whose books cannot be parsed is skipped, a page that cannot be fetched ends the sync.
+#[derive(Debug)]
+pub struct BookList<T: std::error::Error + Send + Sync + 'static, S: Send + Sync + 'static> {
+    pub books: Vec<Book>,
+    /// Books the sync named but left out, e.g. because they were finished or returned since the
+    /// token it started from. Drop them from a list kept from earlier syncs.
+    pub removed: Vec<ProductId>,
+    pub errors: Vec<PageError<T, S>>,
+}
+
+impl<T: std::error::Error + Send + Sync + 'static, S: Send + Sync + 'static> BookList<T, S> {
+    /// Whether every page was read, so `books` is the whole library.
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// What [`Kobo::download_with_name`] wrote, along with the name the server suggested for it.
+#[derive(Debug)]
+pub struct Downloaded<W> {
+    pub output: W,
+    /// The `Content-Disposition` file name, or the last segment of the url when it has an
+    /// extension. Directories are stripped.
+    pub file_name: Option<Box<str>>,
+}
+
+/// How often download progress is reported, the default reports every write.
+///
+/// A report is due once either of the set thresholds is crossed. Rewinds and the end of the
+/// download are always reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProgressThrottle {
+    /// Time since the last report, zero to ignore it.
+    pub interval: Duration,
+    /// Bytes written since the last report, zero to ignore them.
+    pub bytes: u64,
+}
+
+impl ProgressThrottle {
+    fn is_due(&self, bytes: u64, elapsed: Duration) -> bool {
+        if self.interval.is_zero() && self.bytes == 0 {
+            return true;
+        }
+        (!self.interval.is_zero() && elapsed >= self.interval)
+            || (self.bytes != 0 && bytes >= self.bytes)
+    }
+}
+
+/// Per-request transport configuration, attached by [`Kobo`] to the extensions of every request.
+#[derive(Debug, Clone, Default)]
+pub struct TransportOptions {
+    /// For plain requests this bounds the whole exchange, for downloads it bounds connecting
+    /// and the time the transfer can stall.
+    pub timeout: Option<Duration>,
+    /// Proxy url, credentials included. `None` means a direct connection.
+    pub proxy: Option<Box<str>>,
+    /// PEM bundle of the certificate authorities to trust instead of the default ones.
+    pub ca_file: Option<PathBuf>,
+    /// Skips the verification of server certificates, only meant for debugging.
+    pub insecure: bool,
+    /// Size of the buffer downloads are received in, `None` keeps the default of the
+    /// transport. Ignored by the reqwest transport, which hands out chunks as they arrive.
+    pub buffer_size: Option<NonZeroUsize>,
+}
+
 pub enum Body<'a> {
     None,
     Data(Cow<'a, [u8]>),
-    // TODO: Read interface?
+    /// Streamed while sending, with its length if known.
+    Reader(Box<dyn Read + Send + 'a>, Option<u64>),
+}
+
+impl Body<'_> {
+    /// The length of the body, `None` for readers of unknown length.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Self::None => Some(0),
+            Self::Data(data) => Some(data.len() as u64),
+            Self::Reader(_, len) => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// A copy of the body, `None` for readers since they can only be consumed once.
+    pub fn try_clone(&self) -> Option<Self> {
+        match self {
+            Self::None => Some(Self::None),
+            Self::Data(data) => Some(Self::Data(data.clone())),
+            Self::Reader(..) => None,
+        }
+    }
+}
+
+impl core::fmt::Debug for Body<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => f.write_str("None"),
+            Self::Data(data) => f.debug_tuple("Data").field(&data.len()).finish(),
+            Self::Reader(_, len) => f.debug_tuple("Reader").field(len).finish(),
+        }
+    }
 }
 
 pub trait Transport {
@@ -139,13 +334,39 @@ pub trait Transport {
         req: http::Request<Body<'_>>,
     ) -> Result<http::Response<Self::Out>, Error<Self::Error, S>>;
 
-    fn download<S: Send + Sync + 'static, W: std::io::Write>(
+    /// Streams the response body of `req` into `output`.
+    ///
+    /// When `offset` is set a `Range` header is sent and `output` is expected to be already
+    /// positioned at `offset`. If the server ignores the range and answers with the whole
+    /// content, `output` is rewound to the start before writing.
+    fn download<S: Send + Sync + 'static, W: Write + Seek>(
         &mut self,
         req: http::Request<Body<'_>>,
+        offset: Option<u64>,
         output: W,
     ) -> Result<::http::Response<W>, Error<Self::Error, S>>;
 }
 
+fn set_range<B>(req: &mut ::http::Request<B>, offset: Option<u64>) -> Option<u64> {
+    let offset = offset.filter(|&o| o != 0)?;
+    req.headers_mut().insert(
+        ::http::header::RANGE,
+        ::http::HeaderValue::from_str(&format!("bytes={offset}-")).unwrap(),
+    );
+    Some(offset)
+}
+
+fn content_range_start(headers: &::http::HeaderMap) -> Option<u64> {
+    let range = headers
+        .get(::http::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .trim()
+        .strip_prefix("bytes")?
+        .trim_start();
+    range[..range.find('-')?].trim().parse().ok()
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Settings {
     #[serde(with = "url")]
@@ -156,12 +377,27 @@ pub struct Settings {
     #[serde(with = "url")]
     user_wishlist: Url,
     content_access_book: Box<str>,
+    #[serde(default)]
+    image_url_template: Option<Box<str>>,
 }
 
 #[derive(Debug)]
 pub struct Kobo<T: Transport> {
     settings: Option<Settings>,
     cookies: cookie_store::CookieStore,
+    options: TransportOptions,
+    profile: DeviceProfile,
+    base_url: Url,
+    rate_limit_attempts: usize,
+    /// Set whenever a request is retried after being rate limited.
+    rate_limited: bool,
+    sync_page_delay: Duration,
+    progress_throttle: ProgressThrottle,
+    cancel: Option<Arc<AtomicBool>>,
+    send_user_key: bool,
+    headers: ::http::HeaderMap,
+    rate_limiter: Option<RateLimiter>,
+    max_redirects: usize,
     transport: T,
 }
 
@@ -218,112 +454,1058 @@ fn is_cookie_rfc_compliant(cookie: &cookie_store::Cookie) -> bool {
     true
 }
 
+/// Logs headers with the credentials they carry replaced.
+#[cfg(feature = "trace-http")]
+struct RedactedHeaders<'a>(&'a ::http::HeaderMap);
+
+#[cfg(feature = "trace-http")]
+impl core::fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ::http::header::{AUTHORIZATION, COOKIE, SET_COOKIE};
+
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, value)| {
+                let value = if [AUTHORIZATION, COOKIE, SET_COOKIE].contains(name) {
+                    "<redacted>"
+                } else {
+                    value.to_str().unwrap_or("<binary>")
+                };
+                (name.as_str(), value)
+            }))
+            .finish()
+    }
+}
+
+#[cfg(feature = "trace-http")]
+fn trace_request<B>(req: &::http::Request<B>) {
+    tracing::debug!(
+        method = %req.method(),
+        uri = %req.uri(),
+        headers = ?RedactedHeaders(req.headers()),
+        "HTTP request"
+    );
+}
+
+#[cfg(feature = "trace-http")]
+fn trace_response<B>(res: &::http::Response<B>) {
+    tracing::debug!(
+        status = %res.status(),
+        headers = ?RedactedHeaders(res.headers()),
+        "HTTP response"
+    );
+}
+
+const RATE_LIMIT_ATTEMPTS: usize = 5;
+const MAX_REDIRECTS: usize = 10;
+const RATE_LIMIT_DEFAULT_WAIT: Duration = Duration::from_secs(5);
+const RATE_LIMIT_MAX_WAIT: Duration = Duration::from_secs(120);
+
+/// How long to wait before trying again when rate limited, `None` to give up and return `res`.
+fn retry_after<B>(
+    res: &::http::Response<B>,
+    attempts: usize,
+    max_attempts: usize,
+) -> Option<Duration> {
+    if res.status() != ::http::StatusCode::TOO_MANY_REQUESTS || attempts >= max_attempts {
+        return None;
+    }
+    let wait = match res
+        .headers()
+        .get(::http::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+    {
+        Some(v) => match v.parse() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(_) => httpdate::parse_http_date(v)
+                .ok()?
+                .duration_since(std::time::SystemTime::now())
+                .unwrap_or_default(),
+        },
+        None => RATE_LIMIT_DEFAULT_WAIT,
+    };
+    if wait > RATE_LIMIT_MAX_WAIT {
+        return None;
+    }
+    warn!("Rate limited, retrying in {}s", wait.as_secs_f32().ceil());
+    Some(wait)
+}
+
+fn push_cookies<B>(
+    cookies: &cookie_store::CookieStore,
+    url: &::url::Url,
+    req: &mut ::http::Request<B>,
+) {
+    let mut header = String::new();
+    for cookie in cookies.matches(url) {
+        if !is_cookie_rfc_compliant(cookie) {
+            continue;
+        }
+        if !header.is_empty() {
+            header.push(';');
+        }
+
+        use core::fmt::Write;
+        _ = write!(header, "{}", cookie.stripped());
+    }
+
+    req.headers_mut().insert(
+        ::http::header::COOKIE,
+        ::http::HeaderValue::from_maybe_shared(::bytes::Bytes::from(header.into_bytes())).unwrap(),
+    );
+}
+
+/// The unexpired cookies of the jar as JSON, session ones included.
+fn export_cookies(cookies: &cookie_store::CookieStore) -> String {
+    let cookies = cookies
+        .iter_unexpired()
+        .filter(|cookie| is_cookie_rfc_compliant(cookie))
+        .collect::<Vec<_>>();
+    serde_json::to_string(&cookies).expect("cookies are always serializable")
+}
+
+/// A jar with the cookies exported by [`export_cookies`], leaving out the expired ones and the
+/// ones [`push_cookies`] would never send.
+fn import_cookies(json: &str) -> Result<cookie_store::CookieStore, serde_json::Error> {
+    let cookies = serde_json::from_str::<Vec<cookie_store::Cookie<'static>>>(json)?;
+    let Ok(cookies) = cookie_store::CookieStore::from_cookies(
+        cookies
+            .into_iter()
+            .filter(is_cookie_rfc_compliant)
+            .map(Ok::<_, std::convert::Infallible>),
+        false,
+    );
+    Ok(cookies)
+}
+
+fn pull_cookies<B>(
+    cookies: &mut cookie_store::CookieStore,
+    url: &::url::Url,
+    res: &::http::Response<B>,
+) {
+    cookies.store_response_cookies(
+        res.headers()
+            .get_all(::http::header::SET_COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .filter_map(|v| ::cookie_store::Cookie::parse(v, url).ok())
+            .map(::cookie_store::Cookie::into_owned)
+            .map(Into::into),
+        url,
+    );
+}
+
+/// Resolves the target of a redirect response, `None` if `res` isn't one to follow.
+fn redirect_location<B>(url: &::url::Url, res: &::http::Response<B>) -> Option<::url::Url> {
+    if !res.status().is_redirection() {
+        return None;
+    }
+    res.headers()
+        .get_all(::http::header::LOCATION)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .filter_map(|u| url.join(u).ok())
+        .next()
+}
+
+/// Whether the redirect from `url` to `next` leads back to the same request, answered the same
+/// way forever. A redirect to itself that sets cookies may get a different answer.
+fn is_redirect_loop<B>(url: &::url::Url, next: &::url::Url, res: &::http::Response<B>) -> bool {
+    url == next && !res.headers().contains_key(::http::header::SET_COOKIE)
+}
+
+/// The proxy to reach `url` according to the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and
+/// `NO_PROXY` environment variables.
+fn env_proxy(url: &::url::Url) -> Option<Box<str>> {
+    fn var(name: &str) -> Option<String> {
+        std::env::var(name)
+            .or_else(|_| std::env::var(name.to_lowercase()))
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+    }
+
+    let host = url.host_str()?;
+    if let Some(no_proxy) = var("NO_PROXY") {
+        let excluded = no_proxy
+            .split(',')
+            .map(|p| p.trim().trim_start_matches('.'))
+            .filter(|p| !p.is_empty())
+            .any(|p| {
+                p == "*"
+                    || host.eq_ignore_ascii_case(p)
+                    || host.len().checked_sub(p.len() + 1).is_some_and(|i| {
+                        host.as_bytes()[i] == b'.' && host[i + 1..].eq_ignore_ascii_case(p)
+                    })
+            });
+        if excluded {
+            return None;
+        }
+    }
+    match url.scheme() {
+        "https" => var("HTTPS_PROXY"),
+        _ => var("HTTP_PROXY"),
+    }
+    .or_else(|| var("ALL_PROXY"))
+    .map(String::into_boxed_str)
+}
+
+/// Attaches `options` to `req`, falling back to the environment for the proxy and the
+/// certificate authorities.
+fn apply_options<B>(options: &TransportOptions, url: &::url::Url, req: &mut ::http::Request<B>) {
+    let mut options = options.clone();
+    if options.proxy.is_none() {
+        options.proxy = env_proxy(url);
+    }
+    if options.ca_file.is_none() {
+        options.ca_file = std::env::var_os("SSL_CERT_FILE")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+    }
+    req.extensions_mut().insert(options);
+}
+
+fn bearer(token: &str) -> Option<::http::HeaderValue> {
+    ::http::HeaderValue::from_str(&format!("Bearer {token}")).ok()
+}
+
+fn client_key(profile: &DeviceProfile) -> Box<str> {
+    base64::prelude::BASE64_STANDARD
+        .encode(profile.platform_id.as_bytes())
+        .into_boxed_str()
+}
+
+/// Resolves `path` against the store API `base_url`.
+fn store_uri(base_url: &Url, path: &str) -> ::http::Uri {
+    ::http::Uri::from_str(base_url.join(path).unwrap().as_str()).unwrap()
+}
+
+/// Expands a `{ProductId}` url template from [`Settings`].
+fn product_url(template: &str, product_id: &ProductId) -> String {
+    let mut url = String::new();
+    for (i, p) in template.split("{ProductId}").enumerate() {
+        if i != 0 {
+            url.push_str(product_id);
+        }
+        url.push_str(p);
+    }
+    url
+}
+
+/// Used when the initialization does not advertise an image endpoint.
+const COVER_URL_TEMPLATE: &str =
+    "https://cdn.kobo.com/book-images/{ImageId}/{Width}/{Height}/false/image.jpg";
+const COVER_WIDTH: &str = "1650";
+const COVER_HEIGHT: &str = "2200";
+
+fn cover_uri(template: Option<&str>, image_id: &str) -> Option<::http::Uri> {
+    let url = template
+        .unwrap_or(COVER_URL_TEMPLATE)
+        .replace("{ImageId}", image_id)
+        .replace("{Width}", COVER_WIDTH)
+        .replace("{Height}", COVER_HEIGHT)
+        .replace("{IsGreyscale}", "false")
+        .replace("{Quality}", "100");
+    ::http::Uri::from_str(Url::parse(&url).ok()?.as_str()).ok()
+}
+
+fn access_book_uri(profile: &DeviceProfile, template: &str, product_id: &ProductId) -> ::http::Uri {
+    let mut url = Url::parse(&product_url(template, product_id)).unwrap();
+    url.query_pairs_mut()
+        .append_pair("DisplayProfile", &profile.display_profile);
+    ::http::Uri::from_str(url.as_str()).unwrap()
+}
+
+/// The key protecting the content keys, derived from the device and the user.
+fn content_key<S: Session>(
+    session: &SessionAdapter<S>,
+) -> Option<aes::cipher::Key<aes::Aes128Dec>> {
+    let (device_id, user_id) = (session.device_id()?, session.user_id()?);
+    let mut sha = Sha256::new();
+    write!(&mut sha, "{device_id}{user_id}").unwrap();
+    let a: [u8; 32] = sha.finalize().into();
+    Some(Into::<aes::cipher::Key<aes::Aes128Dec>>::into([
+        a[16], a[17], a[18], a[19], a[20], a[21], a[22], a[23], a[24], a[25], a[26], a[27], a[28],
+        a[29], a[30], a[31],
+    ]))
+}
+
+fn select_format<T: std::error::Error + Send + Sync + 'static, S: Send + Sync + 'static>(
+    access: book::ContentAccess,
+    format: Option<UrlFormat>,
+) -> Result<AccessBook, Error<T, S>> {
+    match (access.select(format), format) {
+        (Some(book), _) => Ok(book),
+        (None, Some(format)) => Err(Error::FormatUnavailable(format)),
+        (None, None) => unreachable!(),
+    }
+}
+
+/// Sends the stored user key as `x-kobo-userkey` like some versions of the app do on content
+/// requests, nothing is added without a key.
+fn user_key_header<S: Session>(session: &SessionAdapter<S>, headers: &mut ::http::HeaderMap) {
+    if let Some(key) = session
+        .user_key()
+        .and_then(|k| ::http::HeaderValue::from_str(k).ok())
+    {
+        headers.insert("x-kobo-userkey", key);
+    }
+}
+
+fn download_request<S: Session>(
+    profile: &DeviceProfile,
+    session: &SessionAdapter<S>,
+    url: &::url::Url,
+    user_key: bool,
+) -> Option<::http::Request<Body<'static>>> {
+    if !session.is_logged_in() {
+        return None;
+    }
+
+    let mut parts = mkreq(
+        ::http::Method::GET,
+        ::http::Uri::from_str(url.as_str()).unwrap(),
+    );
+    parts
+        .headers
+        .insert("Authorization", bearer(session.access_token()?)?);
+    if user_key {
+        user_key_header(session, &mut parts.headers);
+    }
+    let mut req = ::http::Request::from_parts(parts, Body::None);
+    default_headers(profile, &mut req);
+    Some(req)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct RefreshRequest<'a> {
+    app_version: &'a str,
+    client_key: Box<str>,
+    platform_id: &'a str,
+    refresh_token: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RefreshResponse {
+    token_type: String,
+    access_token: String,
+    refresh_token: String,
+}
+
+fn refresh_request<'a>(
+    profile: &'a DeviceProfile,
+    base_url: &Url,
+    access_token: ::http::HeaderValue,
+    refresh_token: &'a str,
+) -> ::http::Request<Json<RefreshRequest<'a>>> {
+    let mut parts = mkreq(::http::Method::POST, store_uri(base_url, "v1/auth/refresh"));
+    parts.headers.insert("Authorization", access_token);
+    ::http::Request::from_parts(
+        parts,
+        Json(RefreshRequest {
+            app_version: &profile.app_version,
+            client_key: client_key(profile),
+            platform_id: &profile.platform_id,
+            refresh_token,
+        }),
+    )
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeviceAuthRequest<'a> {
+    affiliate_name: &'a str,
+    app_version: &'a str,
+    client_key: Box<str>,
+    device_id: &'a str,
+    platform_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_key: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeviceAuthResponse {
+    token_type: Box<str>,
+    access_token: Box<str>,
+    refresh_token: Box<str>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeviceAuthResponseFull {
+    token_type: Box<str>,
+    access_token: Box<str>,
+    refresh_token: Box<str>,
+    user_key: Box<str>,
+}
+
+impl DeviceAuthResponseFull {
+    fn into_parts(self) -> (DeviceAuthResponse, Box<str>) {
+        (
+            DeviceAuthResponse {
+                token_type: self.token_type,
+                access_token: self.access_token,
+                refresh_token: self.refresh_token,
+            },
+            self.user_key,
+        )
+    }
+}
+
+/// Kobo refuses to refresh tokens it no longer knows, the user has to log in again.
+fn refresh_error<E, S>(err: Error<E, S>) -> Error<E, S>
+where
+    E: std::error::Error + Send + Sync + 'static,
+    S: Send + Sync + 'static,
+{
+    match err {
+        Error::StatusCode(
+            ::http::StatusCode::BAD_REQUEST
+            | ::http::StatusCode::UNAUTHORIZED
+            | ::http::StatusCode::FORBIDDEN,
+        ) => Error::SessionExpired,
+        err => err,
+    }
+}
+
+fn device_auth_request<'a>(
+    profile: &'a DeviceProfile,
+    base_url: &Url,
+    device_id: &'a str,
+    user_key: Option<&'a str>,
+) -> ::http::Request<Json<DeviceAuthRequest<'a>>> {
+    ::http::Request::from_parts(
+        mkreq(::http::Method::POST, store_uri(base_url, "v1/auth/device")),
+        Json(DeviceAuthRequest {
+            affiliate_name: &profile.affiliate,
+            app_version: &profile.app_version,
+            client_key: client_key(profile),
+            device_id,
+            platform_id: &profile.platform_id,
+            user_key,
+        }),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Initialization {
+    pub resources: Settings,
+}
+
+fn initialization_request(base_url: &Url) -> ::http::Request<fn()> {
+    ::http::Request::from_parts(
+        mkreq(
+            ::http::Method::GET,
+            store_uri(base_url, "v1/initialization"),
+        ),
+        || (),
+    )
+}
+
+fn sync_request(library_sync: &Url, token: Option<&str>) -> ::http::Request<fn()> {
+    let token = token
+        .and_then(|s| if s.is_empty() { None } else { Some(s) })
+        .and_then(|s| ::http::HeaderValue::from_str(s).ok());
+    let mut parts = mkreq(
+        ::http::Method::GET,
+        ::http::Uri::from_str(library_sync.as_str()).unwrap(),
+    );
+    if let Some(token) = token {
+        parts.headers.insert("x-kobo-synctoken", token);
+    }
+    ::http::Request::from_parts(parts, || ())
+}
+
+fn download_file_name(headers: &::http::HeaderMap, url: &Url) -> Option<Box<str>> {
+    let name = headers
+        .get(::http::header::CONTENT_DISPOSITION)
+        .and_then(|h| crate::content_disposition_filename(h.as_bytes()))
+        .or_else(|| {
+            let segment = url.path_segments()?.next_back()?;
+            String::from_utf8(crate::content_type::percent_decode(segment.as_bytes())?)
+                .ok()
+                .filter(|s| s.contains('.'))
+        })?;
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    (!name.trim_matches('.').is_empty()).then(|| name.into())
+}
+
+/// Syncing stops after this many pages, no library is that big.
+const MAX_SYNC_PAGES: usize = 1000;
+
+/// Moves to the `next` sync token, returns whether there is another page to fetch.
+///
+/// The server has been seen returning the same token over and over, that ends the sync too.
+fn next_sync_token(token: &mut Option<Box<str>>, next: Option<Box<str>>, pages: usize) -> bool {
+    if next.is_some() && next == *token {
+        warn!("The same sync token was returned twice, the book list might be incomplete");
+        return false;
+    }
+    if next.is_some() && pages >= MAX_SYNC_PAGES {
+        warn!("Stopped syncing after {pages} pages, the book list might be incomplete");
+        return false;
+    }
+    *token = next;
+    token.is_some()
+}
+
+/// A sync page slower than this makes the next ones wait longer.
+const SLOW_SYNC_PAGE: Duration = Duration::from_secs(5);
+const SYNC_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const SYNC_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Spaces the pages of a sync out by the configured delay.
+///
+/// After a page that was slow or rate limited the delay doubles, up to [`SYNC_BACKOFF_MAX`],
+/// then halves back with every normal page.
+struct SyncPacer {
+    base: Duration,
+    current: Duration,
+}
+
+impl SyncPacer {
+    fn new(base: Duration) -> Self {
+        Self {
+            base,
+            current: base,
+        }
+    }
+
+    /// How long to wait before the next page, after one that took `elapsed`.
+    fn next(&mut self, elapsed: Duration, rate_limited: bool) -> Duration {
+        self.current = if rate_limited || elapsed >= SLOW_SYNC_PAGE {
+            (self.current * 2).clamp(SYNC_BACKOFF_MIN, SYNC_BACKOFF_MAX)
+        } else if self.current / 2 < SYNC_BACKOFF_MIN {
+            self.base
+        } else {
+            (self.current / 2).max(self.base)
+        };
+        jitter(self.current)
+    }
+}
+
+/// `wait` scaled by a random factor between 0.5 and 1, so that clients started together drift
+/// apart.
+fn jitter(wait: Duration) -> Duration {
+    use std::hash::BuildHasher;
+
+    let random = std::collections::hash_map::RandomState::new().hash_one(Instant::now());
+    wait.mul_f64(0.5 + (random % 1000) as f64 / 2000.0)
+}
+
+const PREPARING_WAIT_MIN: Duration = Duration::from_secs(2);
+const PREPARING_WAIT_MAX: Duration = Duration::from_secs(30);
+const PREPARING_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Polls a book Kobo answers `202 Accepted` for while it generates the file, recently
+/// purchased ones mostly.
+///
+/// The wait doubles with every attempt, up to [`PREPARING_WAIT_MAX`], until
+/// [`PREPARING_TIMEOUT`] is over.
+struct PreparingPoll {
+    started: Instant,
+    wait: Duration,
+}
+
+impl PreparingPoll {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            wait: PREPARING_WAIT_MIN,
+        }
+    }
+
+    /// How long to wait before asking again, `None` once the time is up.
+    fn next(&mut self) -> Option<Duration> {
+        let wait = self.wait;
+        if self.started.elapsed() + wait > PREPARING_TIMEOUT {
+            return None;
+        }
+        self.wait = (wait * 2).min(PREPARING_WAIT_MAX);
+        warn!(
+            "Kobo is preparing the book, retrying in {}s",
+            wait.as_secs()
+        );
+        Some(wait)
+    }
+}
+
+/// Adds the `books` and `removed` ids of a sync page to the ones of the earlier pages, the
+/// latest page wins for the books in both.
+fn merge_sync_page(
+    res: &mut Vec<Book>,
+    removed: &mut Vec<ProductId>,
+    books: Vec<Book>,
+    page_removed: Vec<ProductId>,
+) {
+    res.retain(|book| !page_removed.contains(&book.revision_id));
+    removed.retain(|id| !books.iter().any(|book| book.revision_id == *id));
+    for id in page_removed {
+        if !removed.contains(&id) {
+            removed.push(id);
+        }
+    }
+    res.extend(books);
+}
+
+/// Drops the duplicates left by overlapping sync pages, keeping the latest, and sorts by title.
+fn finish_book_list(mut books: Vec<Book>) -> Vec<Book> {
+    let mut seen = HashSet::new();
+    books.reverse();
+    books.retain(|book| seen.insert(book.revision_id.clone()));
+    books.sort_by(|a, b| a.title.as_ref().cmp(b.title.as_ref()));
+    books
+}
+
+const WISHLIST_PAGE_SIZE: &str = "100";
+
+fn wishlist_request(user_wishlist: &Url, page: u32) -> ::http::Request<fn()> {
+    let mut url = user_wishlist.clone();
+    url.query_pairs_mut()
+        .append_pair("PageIndex", &page.to_string())
+        .append_pair("PageSize", WISHLIST_PAGE_SIZE);
+    let parts = mkreq(
+        ::http::Method::GET,
+        ::http::Uri::from_str(url.as_str()).unwrap(),
+    );
+    ::http::Request::from_parts(parts, || ())
+}
+
+#[derive(Debug, Serialize)]
+struct LoginRequest<'a> {
+    #[serde(rename = "LogInModel.WorkflowId")]
+    workflow_id: String,
+    #[serde(rename = "LogInModel.Provider")]
+    provider: &'a str,
+    #[serde(rename = "ReturnUrl")]
+    return_url: &'static str,
+    #[serde(rename = "__RequestVerificationToken")]
+    token: String,
+    #[serde(rename = "LogInModel.UserName")]
+    username: &'a str,
+    #[serde(rename = "LogInModel.Password")]
+    password: &'a str,
+    #[serde(rename = "g-recaptcha-response")]
+    g_captcha: &'a str,
+    #[serde(rename = "h-captcha-response")]
+    h_captcha: &'a str,
+}
+
+fn sign_in_page_uri(profile: &DeviceProfile, sign_in_page: &Url, device_id: &str) -> ::http::Uri {
+    let mut url = sign_in_page.clone();
+    url.query_pairs_mut()
+        .append_pair("wsa", &profile.affiliate)
+        .append_pair("pwsav", &profile.app_version)
+        .append_pair("pwspid", &profile.platform_id)
+        .append_pair("pwsdid", device_id)
+        .append_pair("wscfv", "1.5")
+        .append_pair("wscf", "kepub")
+        .append_pair("wsmc", &profile.carrier_name)
+        .append_pair("pwspov", &profile.device_os_version)
+        .append_pair("pwspt", "Mobile")
+        .append_pair("pwsdm", &profile.device_model);
+    ::http::Uri::from_str(url.as_str()).unwrap()
+}
+
+/// Selectors of the sign in form, from the most specific to the least, so that the form is
+/// still found after Kobo reworks the page as long as it carries a workflow id.
+static FORM_SELECTORS: LazyLock<[Selector; 3]> = LazyLock::new(|| {
+    [
+        "section#defaultOptions form:has(#signInBlock)",
+        "form:has(input[name=\"LogInModel.WorkflowId\"])",
+        "form:has(input[name$=\"WorkflowId\"])",
+    ]
+    .map(|s| Selector::parse(s).unwrap())
+});
+
+/// Extracts the workflow id and the verification token from the sign in page and returns them
+/// along with the url the credentials have to be posted to, in the storefront of `locale`.
+///
+/// Fails with a summary of what the page holds instead.
+fn login_parameters(
+    sign_in_page: &Url,
+    locale: &str,
+    page: &str,
+) -> Result<(String, String, Url), String> {
+    static WORKFLOW_ID_SELECTOR: LazyLock<Selector> =
+        LazyLock::new(|| Selector::parse("input[name$=\"WorkflowId\"]").unwrap());
+    static TOKEN_SELECTOR: LazyLock<Selector> =
+        LazyLock::new(|| Selector::parse("input[name=\"__RequestVerificationToken\"]").unwrap());
+
+    let doc = Html::parse_document(page);
+    let Some(form) = FORM_SELECTORS
+        .iter()
+        .find_map(|selector| doc.select(selector).next())
+    else {
+        return Err(describe_page(&doc, "no sign in form"));
+    };
+    // Only the form posted counts, other forms of the page carry tokens of their own.
+    let value = |selector: &Selector| {
+        form.select(selector)
+            .filter_map(|input| input.attr("value"))
+            .find(|value| !value.is_empty())
+    };
+    let Some(workflow_id) = value(&WORKFLOW_ID_SELECTOR) else {
+        return Err(describe_page(&doc, "no workflow id"));
+    };
+    let Some(token) = value(&TOKEN_SELECTOR) else {
+        return Err(describe_page(&doc, "no verification token"));
+    };
+    let mut url = sign_in_page.clone();
+    url.set_query(None);
+    url.set_path(&format!("/{locale}/signin/signin"));
+    Ok((workflow_id.to_string(), token.to_string(), url))
+}
+
+/// What went wrong along with the title, forms and input names of `doc`, the values are left
+/// out since they can be tokens.
+fn describe_page(doc: &Html, problem: &str) -> String {
+    const MAX_INPUTS: usize = 20;
+    static TITLE_SELECTOR: LazyLock<Selector> = LazyLock::new(|| Selector::parse("title").unwrap());
+    static FORM_SELECTOR: LazyLock<Selector> = LazyLock::new(|| Selector::parse("form").unwrap());
+    static INPUT_SELECTOR: LazyLock<Selector> =
+        LazyLock::new(|| Selector::parse("input[name]").unwrap());
+
+    let mut res = problem.to_string();
+    if let Some(title) = doc.select(&TITLE_SELECTOR).next() {
+        let title = title
+            .text()
+            .flat_map(str::split_whitespace)
+            .collect::<Vec<_>>();
+        res.push_str(&format!(", title: {:?}", title.join(" ")));
+    }
+    res.push_str(&format!(", forms: {}", doc.select(&FORM_SELECTOR).count()));
+    let mut names = Vec::new();
+    for name in doc.select(&INPUT_SELECTOR).filter_map(|i| i.attr("name")) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    if names.is_empty() {
+        res.push_str(", no inputs");
+    } else {
+        res.push_str(", inputs: ");
+        res.push_str(&names[..names.len().min(MAX_INPUTS)].join(", "));
+        if names.len() > MAX_INPUTS {
+            res.push_str(&format!(" and {} more", names.len() - MAX_INPUTS));
+        }
+    }
+    res
+}
+
+/// Runs the scripts of the page returned after signing in to find where they redirect to,
+/// the target carries the user id and key.
+fn login_result(page: &str) -> Option<(String, String)> {
+    static SCRIPT_SELECTOR: LazyLock<Selector> =
+        LazyLock::new(|| Selector::parse("script").unwrap());
+
+    let doc = Html::parse_document(page);
+    let mut script = "var location={};\n".to_string();
+    for s in doc.select(&SCRIPT_SELECTOR) {
+        script.push_str("try{\n");
+        for txt in s.text() {
+            script.push_str(txt);
+            script.push('\n');
+        }
+        script.push_str("}catch(____e){}\n");
+    }
+    let url = Url::parse(&js::extract_href(script)?).ok()?;
+    let mut user_id = None;
+    let mut user_key = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "userId" => user_id = Some(value.into_owned()),
+            "userKey" => user_key = Some(value.into_owned()),
+            _ => (),
+        }
+    }
+    Some((user_id?, user_key?))
+}
+
+/// Phrases of the pages Kobo serves in place of the login result when it wants the account or
+/// the device to be verified first, matched against the lowercased visible text.
+const VERIFICATION_MARKERS: &[&str] = &[
+    "verify your device",
+    "verify your identity",
+    "verify your account",
+    "verify it's you",
+    "additional verification",
+    "two-step verification",
+    "verification code",
+    "security code",
+    "one-time code",
+];
+
+/// Whether `doc` is a verification interstitial rather than a sign in page.
+fn needs_verification(doc: &Html) -> bool {
+    let text = doc
+        .root_element()
+        .descendants()
+        .filter_map(|node| {
+            let text = node.value().as_text()?;
+            let parent = node.parent()?.value().as_element()?;
+            (!matches!(parent.name(), "script" | "style" | "noscript")).then_some(&**text)
+        })
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+        .replace('\u{2019}', "'");
+    VERIFICATION_MARKERS
+        .iter()
+        .any(|marker| text.contains(marker))
+}
+
+/// Tells why signing in did not lead to the login result.
+///
+/// A rejected captcha and a stale workflow both bring the sign in form back, credential errors
+/// are the only ones reported next to the fields.
+fn login_error<T: std::error::Error + Send + Sync + 'static, S: Send + Sync + 'static>(
+    page: &str,
+) -> Error<T, S> {
+    static SIGN_IN_SELECTOR: LazyLock<Selector> =
+        LazyLock::new(|| Selector::parse("#signInBlock, input[name$=\"WorkflowId\"]").unwrap());
+    static ERROR_SELECTOR: LazyLock<Selector> = LazyLock::new(|| {
+        Selector::parse(".validation-summary-errors, .field-validation-error").unwrap()
+    });
+
+    let doc = Html::parse_document(page);
+    if needs_verification(&doc) {
+        return Error::VerificationRequired;
+    }
+    if doc.select(&SIGN_IN_SELECTOR).next().is_none() {
+        return Error::LoginFlow;
+    }
+    let errors = doc
+        .select(&ERROR_SELECTOR)
+        .map(|e| e.text().collect::<String>().to_lowercase())
+        .filter(|e| !e.trim().is_empty())
+        .collect::<Vec<_>>();
+    if errors.is_empty() || errors.iter().any(|e| e.contains("captcha")) {
+        Error::CaptchaExpired
+    } else {
+        Error::InvalidCredentials
+    }
+}
+
 impl<T: Transport> Kobo<T> {
     pub fn new(transport: T) -> Self {
         Self {
             settings: None,
             cookies: ::cookie_store::CookieStore::new(None),
+            options: TransportOptions::default(),
+            profile: DeviceProfile::from_env(),
+            base_url: Url::parse(DEFAULT_BASE_URL).unwrap(),
+            rate_limit_attempts: RATE_LIMIT_ATTEMPTS,
+            rate_limited: false,
+            sync_page_delay: Duration::ZERO,
+            progress_throttle: ProgressThrottle::default(),
+            cancel: None,
+            send_user_key: false,
+            headers: ::http::HeaderMap::new(),
+            rate_limiter: None,
+            max_redirects: MAX_REDIRECTS,
             transport,
         }
     }
 
-    fn push_cookies<B>(&self, url: &::url::Url, req: &mut ::http::Request<B>) {
-        let mut cookies = String::new();
-        for cookie in self.cookies.matches(url) {
-            if !is_cookie_rfc_compliant(cookie) {
-                continue;
-            }
-            if !cookies.is_empty() {
-                cookies.push(';');
-            }
+    /// Replaces the profile read from the environment by [`Kobo::new`].
+    pub fn with_device_profile(mut self, profile: DeviceProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn device_profile(&self) -> &DeviceProfile {
+        &self.profile
+    }
 
-            use core::fmt::Write;
-            _ = write!(cookies, "{}", cookie.stripped());
+    /// Talks to the store API at `base_url` instead of `https://storeapi.kobo.com/`, e.g. a staging
+    /// or mock server. The other endpoints are the ones it advertises on initialization.
+    pub fn with_base_url(mut self, mut base_url: Url) -> Self {
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
         }
+        self.base_url = base_url;
+        self.settings = None;
+        self
+    }
 
-        req.headers_mut().insert(
-            ::http::header::COOKIE,
-            ::http::HeaderValue::from_maybe_shared(::bytes::Bytes::from(cookies.into_bytes()))
-                .unwrap(),
-        );
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
     }
 
-    fn pull_cookies<B>(&mut self, url: &::url::Url, res: &::http::Response<B>) {
-        self.cookies.store_response_cookies(
-            res.headers()
-                .get_all(::http::header::SET_COOKIE)
-                .iter()
-                .filter_map(|h| h.to_str().ok())
-                .filter_map(|v| ::cookie_store::Cookie::parse(v, url).ok())
-                .map(::cookie_store::Cookie::into_owned)
-                .map(Into::into),
-            url,
-        );
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.options.timeout = Some(timeout);
     }
 
-    fn raw_request<'a, InB: IntoRequest<'a>, S: Send + Sync + 'static>(
+    /// Sends every request through `proxy` instead of the one from the environment.
+    pub fn set_proxy<P: Into<Box<str>>>(&mut self, proxy: P) {
+        self.options.proxy = Some(proxy.into());
+    }
+
+    /// Trusts the certificate authorities in the PEM bundle at `path` instead of the ones
+    /// from `SSL_CERT_FILE` or the defaults of the transport.
+    pub fn set_ca_file<P: Into<PathBuf>>(&mut self, path: P) {
+        self.options.ca_file = Some(path.into());
+    }
+
+    /// Disables the verification of server certificates, anyone on the path can read and
+    /// alter the traffic then.
+    pub fn set_insecure(&mut self, insecure: bool) {
+        self.options.insecure = insecure;
+    }
+
+    /// Receives downloads `size` bytes at a time, bigger buffers help on fast links with high
+    /// latency while smaller ones save memory.
+    pub fn set_download_buffer_size(&mut self, size: NonZeroUsize) {
+        self.options.buffer_size = Some(size);
+    }
+
+    /// Waits about `delay` between the pages of a library sync, longer after pages that were
+    /// slow or rate limited. Defaults to zero.
+    pub fn set_sync_page_delay(&mut self, delay: Duration) {
+        self.sync_page_delay = delay;
+    }
+
+    /// How many redirects a request follows before failing with [`Error::TooManyRedirects`],
+    /// 10 by default. A redirect back to the same url fails right away unless it sets cookies.
+    pub fn set_max_redirects(&mut self, max: usize) {
+        self.max_redirects = max;
+    }
+
+    /// Sends the user key of the session as `x-kobo-userkey` along with the bearer token when
+    /// accessing and downloading books, for accounts where the token alone is rejected.
+    /// Disabled by default.
+    pub fn set_send_user_key(&mut self, send: bool) {
+        self.send_user_key = send;
+    }
+
+    /// Makes every request wait for `limiter`, share it between clients to cap their requests
+    /// together. Unlimited by default.
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// Sends `name` with `value` on every request, replacing the header of the same name set
+    /// by the crate if any. Meant to keep up with changes on Kobo's side or to debug.
+    pub fn set_header<K: ::http::header::IntoHeaderName>(
         &mut self,
+        name: K,
+        value: ::http::HeaderValue,
+    ) {
+        self.headers.insert(name, value);
+    }
+
+    /// Stops sending a header added by [`Kobo::set_header`], the one set by the crate is sent
+    /// again.
+    pub fn remove_header<K: ::http::header::AsHeaderName>(
+        &mut self,
+        name: K,
+    ) -> Option<::http::HeaderValue> {
+        self.headers.remove(name)
+    }
+
+    /// Limits how often the `progress` callback of
+    /// [`download_with_progress`](Self::download_with_progress) is called.
+    pub fn set_progress_throttle(&mut self, throttle: ProgressThrottle) {
+        self.progress_throttle = throttle;
+    }
+
+    /// Makes downloads stop with [`Error::Cancelled`] once `flag` is set, it is checked whenever
+    /// data is written and never reset.
+    pub fn set_cancel_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.cancel = Some(flag);
+    }
+
+    /// The cookie jar as JSON, to carry it over to another [`Kobo`] with
+    /// [`Kobo::import_cookies`] instead of negotiating the cookies again.
+    pub fn export_cookies(&self) -> String {
+        export_cookies(&self.cookies)
+    }
+
+    /// Replaces the cookie jar with one exported by [`Kobo::export_cookies`], expired cookies
+    /// are dropped.
+    pub fn import_cookies(&mut self, json: &str) -> Result<(), serde_json::Error> {
+        self.cookies = import_cookies(json)?;
+        Ok(())
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+    }
+
+    fn apply_options<B>(&self, url: &::url::Url, req: &mut ::http::Request<B>) {
+        apply_options(&self.options, url, req);
+    }
+
+    fn send<'a, InB: IntoRequest<'a>, S: Send + Sync + 'static>(
+        &mut self,
+        url: &::url::Url,
         mut req: http::Request<InB>,
     ) -> Result<::http::Response<T::Out>, Error<T::Error, S>> {
-        default_headers(&mut req);
+        default_headers(&self.profile, &mut req);
         let (parts, body) = req.into_parts();
-        let url = ::url::Url::parse(&parts.uri.to_string()).unwrap();
         let mut r = body.into_request(parts)?;
-        self.push_cookies(&url, &mut r);
-        let res = self.transport.request(r)?;
-        self.pull_cookies(&url, &res);
-        if !res.status().is_redirection() {
-            return Ok(res);
-        }
-        let mut url = if let Some(u) = res
-            .headers()
-            .get_all(::http::header::LOCATION)
-            .iter()
-            .filter_map(|h| h.to_str().ok())
-            .filter_map(|u| url.join(u).ok())
-            .next()
-        {
-            u
-        } else {
-            return Ok(res);
-        };
-        let mut req = ::http::Request::from_parts(
-            mkreq(
-                ::http::Method::GET,
-                ::http::Uri::from_str(url.as_str()).unwrap(),
-            ),
-            (),
-        );
+        push_cookies(&self.cookies, url, &mut r);
+        custom_headers(&self.headers, &mut r);
+        self.apply_options(url, &mut r);
+        let (parts, mut body) = r.into_parts();
+        let mut attempts = 1;
         loop {
-            default_headers(&mut req);
-            let (parts, body) = req.into_parts();
-            let mut r = body.into_request(parts)?;
-            self.push_cookies(&url, &mut r);
+            // Streamed bodies can't be sent twice, the response is returned as is then.
+            let (body, last) = match body.try_clone() {
+                Some(body) => (body, false),
+                None => (core::mem::replace(&mut body, Body::None), true),
+            };
+            let r = ::http::Request::from_parts(parts.clone(), body);
+            #[cfg(feature = "trace-http")]
+            trace_request(&r);
+            if let Some(ref limiter) = self.rate_limiter {
+                limiter.acquire();
+            }
             let res = self.transport.request(r)?;
-            self.pull_cookies(&url, &res);
-            if !res.status().is_redirection() {
-                return Ok(res);
+            #[cfg(feature = "trace-http")]
+            trace_response(&res);
+            pull_cookies(&mut self.cookies, url, &res);
+            match retry_after(&res, attempts, self.rate_limit_attempts) {
+                Some(wait) if !last => {
+                    self.rate_limited = true;
+                    std::thread::sleep(wait);
+                }
+                _ => return Ok(res),
             }
-            url = if let Some(u) = res
-                .headers()
-                .get_all(::http::header::LOCATION)
-                .iter()
-                .filter_map(|h| h.to_str().ok())
-                .filter_map(|u| url.join(u).ok())
-                .next()
-            {
-                u
-            } else {
-                return Ok(res);
-            };
-            req = ::http::Request::from_parts(
+            attempts += 1;
+        }
+    }
+
+    fn raw_request<'a, InB: IntoRequest<'a>, S: Send + Sync + 'static>(
+        &mut self,
+        req: http::Request<InB>,
+    ) -> Result<::http::Response<T::Out>, Error<T::Error, S>> {
+        let mut url = ::url::Url::parse(&req.uri().to_string()).unwrap();
+        let mut res = self.send(&url, req)?;
+        let mut redirects = 0;
+        while let Some(next) = redirect_location(&url, &res) {
+            if redirects == self.max_redirects || is_redirect_loop(&url, &next, &res) {
+                return Err(Error::TooManyRedirects);
+            }
+            redirects += 1;
+            url = next;
+            let req = ::http::Request::from_parts(
                 mkreq(
                     ::http::Method::GET,
                     ::http::Uri::from_str(url.as_str()).unwrap(),
                 ),
                 (),
             );
+            res = self.send(&url, req)?;
         }
+        Ok(res)
     }
 
     fn simple_request<'a, InB: IntoRequest<'a>, OutB: FromResponse, S: Send + Sync + 'static>(
@@ -398,10 +1580,7 @@ impl<T: Transport> Kobo<T> {
         let (mut parts, body) = req.into_parts();
 
         let res = {
-            if let Some(auth) = session
-                .access_token()
-                .and_then(|s| ::http::HeaderValue::from_str(&format!("Bearer {s}")).ok())
-            {
+            if let Some(auth) = session.access_token().and_then(|s| bearer(s)) {
                 let mut parts = parts.clone();
                 parts.headers.insert("Authorization", auth);
                 self.raw_request(::http::Request::from_parts(parts, body()))?
@@ -459,50 +1638,22 @@ impl<T: Transport> Kobo<T> {
         &mut self,
         session: &mut SessionAdapter<S>,
     ) -> Result<::http::HeaderValue, Error<T::Error, S::Error>> {
-        #[derive(Debug, Serialize)]
-        #[serde(rename_all = "PascalCase")]
-        struct RequestBody<'a> {
-            app_version: &'static str,
-            client_key: Box<str>,
-            platform_id: &'static str,
-            refresh_token: &'a str,
-        }
-
-        #[derive(Debug, Deserialize)]
-        #[serde(rename_all = "PascalCase")]
-        struct ResponseBody {
-            token_type: String,
-            access_token: String,
-            refresh_token: String,
-        }
-
         if let (Some(access_token), Some(refresh_token)) = (
-            session
-                .access_token()
-                .and_then(|s| ::http::HeaderValue::from_str(&format!("Bearer {s}")).ok()),
+            session.access_token().and_then(|s| bearer(s)),
             session.refresh_token(),
         ) {
-            let mut parts = mkreq(
-                ::http::Method::POST,
-                ::http::Uri::from_static("https://storeapi.kobo.com/v1/auth/refresh"),
-            );
-            parts.headers.insert("Authorization", access_token);
-            let ResponseBody {
+            let RefreshResponse {
                 token_type,
                 access_token,
                 refresh_token,
             } = self
-                .simple_request::<_, Json<ResponseBody>, _>(::http::Request::from_parts(
-                    parts,
-                    Json(RequestBody {
-                        app_version: APPLICATION_VERSION,
-                        client_key: base64::prelude::BASE64_STANDARD
-                            .encode(DEFAULT_PLATFORM_ID.as_bytes())
-                            .into_boxed_str(),
-                        platform_id: DEFAULT_PLATFORM_ID,
-                        refresh_token,
-                    }),
-                ))?
+                .simple_request::<_, Json<RefreshResponse>, _>(refresh_request(
+                    &self.profile.clone(),
+                    &self.base_url.clone(),
+                    access_token,
+                    refresh_token,
+                ))
+                .map_err(refresh_error)?
                 .into_inner();
             assert!(token_type == "Bearer");
             session.refresh_tokens(access_token, refresh_token);
@@ -517,7 +1668,7 @@ impl<T: Transport> Kobo<T> {
     ) -> Result<::http::HeaderValue, Error<T::Error, S::Error>> {
         loop {
             if let Some(access_token) = session.access_token() {
-                if let Ok(h) = ::http::HeaderValue::from_str(&format!("Bearer {access_token}")) {
+                if let Some(h) = bearer(access_token) {
                     return Ok(h);
                 }
                 session.set_tokens("", "");
@@ -533,44 +1684,6 @@ impl<T: Transport> Kobo<T> {
         session: &mut SessionAdapter<S>,
         user_key: Option<String>,
     ) -> Result<(), Error<T::Error, S::Error>> {
-        #[derive(Serialize)]
-        #[serde(rename_all = "PascalCase")]
-        struct RequestBody<'a> {
-            affiliate_name: &'a str,
-            app_version: &'a str,
-            client_key: Box<str>,
-            device_id: &'a str,
-            platform_id: &'a str,
-        }
-
-        #[derive(Serialize)]
-        #[serde(rename_all = "PascalCase")]
-        struct RequestBodyFull<'a> {
-            affiliate_name: &'a str,
-            app_version: &'a str,
-            client_key: Box<str>,
-            device_id: &'a str,
-            platform_id: &'a str,
-            user_key: &'a str,
-        }
-
-        #[derive(Deserialize)]
-        #[serde(rename_all = "PascalCase")]
-        struct ResponseBody {
-            token_type: Box<str>,
-            access_token: Box<str>,
-            refresh_token: Box<str>,
-        }
-
-        #[derive(Deserialize)]
-        #[serde(rename_all = "PascalCase")]
-        struct ResponseBodyFull {
-            token_type: Box<str>,
-            access_token: Box<str>,
-            refresh_token: Box<str>,
-            user_key: Box<str>,
-        }
-
         let user_key = user_key.and_then(|s| if s.is_empty() { None } else { Some(s) });
 
         if session.is_auth_set() && user_key.is_none() {
@@ -583,53 +1696,19 @@ impl<T: Transport> Kobo<T> {
             }
             session.set_device_id(uuid::Uuid::now_v7().to_string());
         };
-        let parts = mkreq(
-            ::http::Method::POST,
-            ::http::Uri::from_static("https://storeapi.kobo.com/v1/auth/device"),
-        );
+        let (profile, base_url) = (self.profile.clone(), self.base_url.clone());
+        let req = device_auth_request(&profile, &base_url, device_id, user_key.as_deref());
 
-        let (res, user_key) = if let Some(user_key) = user_key.as_deref() {
-            let Json(ResponseBodyFull {
-                token_type,
-                access_token,
-                refresh_token,
-                user_key,
-            }) = self.simple_request(::http::Request::from_parts(
-                parts,
-                Json(RequestBodyFull {
-                    affiliate_name: AFFILIATE,
-                    app_version: APPLICATION_VERSION,
-                    client_key: base64::prelude::BASE64_STANDARD
-                        .encode(DEFAULT_PLATFORM_ID.as_bytes())
-                        .into_boxed_str(),
-                    device_id: device_id.as_str(),
-                    platform_id: DEFAULT_PLATFORM_ID,
-                    user_key,
-                }),
-            ))?;
-            (
-                ResponseBody {
-                    token_type,
-                    access_token,
-                    refresh_token,
-                },
-                Some(user_key),
-            )
+        let (res, user_key) = if user_key.is_some() {
+            let (res, user_key) = self
+                .simple_request::<_, Json<DeviceAuthResponseFull>, _>(req)?
+                .into_inner()
+                .into_parts();
+            (res, Some(user_key))
         } else {
             (
-                self.simple_request::<_, Json<_>, _>(::http::Request::from_parts(
-                    parts,
-                    Json(RequestBody {
-                        affiliate_name: AFFILIATE,
-                        app_version: APPLICATION_VERSION,
-                        client_key: base64::prelude::BASE64_STANDARD
-                            .encode(DEFAULT_PLATFORM_ID.as_bytes())
-                            .into_boxed_str(),
-                        device_id: device_id.as_str(),
-                        platform_id: DEFAULT_PLATFORM_ID,
-                    }),
-                ))?
-                .into_inner(),
+                self.simple_request::<_, Json<DeviceAuthResponse>, _>(req)?
+                    .into_inner(),
                 None,
             )
         };
@@ -647,25 +1726,15 @@ impl<T: Transport> Kobo<T> {
         &mut self,
         session: &mut SessionAdapter<S>,
     ) -> Result<&Settings, Error<T::Error, S::Error>> {
-        #[derive(Debug, Deserialize)]
-        #[serde(rename_all = "PascalCase")]
-        struct Inner {
-            pub resources: Settings,
-        }
-
         loop {
             if let Some(ref res) = self.settings {
                 return Ok(res);
             }
 
-            let parts = mkreq(
-                ::http::Method::GET,
-                ::http::Uri::from_static("https://storeapi.kobo.com/v1/initialization"),
-            );
             self.settings = Some(
-                self.anon_request::<_, Json<Inner>, _, _>(
+                self.anon_request::<_, Json<Initialization>, _, _>(
                     session,
-                    ::http::Request::from_parts(parts, || ()),
+                    initialization_request(&self.base_url.clone()),
                 )?
                 .into_inner()
                 .resources,
@@ -678,50 +1747,25 @@ impl<T: Transport> Kobo<T> {
         &mut self,
         session: &mut SessionAdapter<S>,
     ) -> Result<(String, String, Url), Error<T::Error, S::Error>> {
-        fn extract(doc: &Html) -> Option<(String, String)> {
-            static FORM_SELECTOR: LazyLock<Selector> = LazyLock::new(|| {
-                Selector::parse("section#defaultOptions form:has(#signInBlock)").unwrap()
-            });
-            static WORKFLOW_ID_SELECTOR: LazyLock<Selector> =
-                LazyLock::new(|| Selector::parse("input[name=\"LogInModel.WorkflowId\"]").unwrap());
-            static TOKEN_SELECTOR: LazyLock<Selector> = LazyLock::new(|| {
-                Selector::parse("input[name=\"__RequestVerificationToken\"]").unwrap()
-            });
-
-            let form = doc.select(&FORM_SELECTOR).next()?;
-            let workflow_id = form.select(&WORKFLOW_ID_SELECTOR).next()?.attr("value")?;
-            if workflow_id.is_empty() {
-                return None;
-            }
-            let token = form.select(&TOKEN_SELECTOR).next()?.attr("value")?;
-            if token.is_empty() {
-                return None;
-            }
-            Some((workflow_id.to_string(), token.to_string()))
-        }
-
-        let mut url = self.settings(session)?.sign_in_page.clone();
-        url.query_pairs_mut()
-            .append_pair("wsa", AFFILIATE)
-            .append_pair("pwsav", APPLICATION_VERSION)
-            .append_pair("pwspid", DEFAULT_PLATFORM_ID)
-            .append_pair("pwsdid", session.device_id().unwrap())
-            .append_pair("wscfv", "1.5")
-            .append_pair("wscf", "kepub")
-            .append_pair("wsmc", CARRIER_NAME)
-            .append_pair("pwspov", DEVICE_OS_VERSION)
-            .append_pair("pwspt", "Mobile")
-            .append_pair("pwsdm", DEVICE_MODEL);
-        let parts = mkreq(
-            ::http::Method::GET,
-            ::http::Uri::from_str(url.as_str()).unwrap(),
+        let uri = sign_in_page_uri(
+            &self.profile.clone(),
+            &self.settings(session)?.sign_in_page,
+            session.device_id().unwrap(),
         );
-        let page = self.simple_request::<_, String, _>(::http::Request::from_parts(parts, ()))?;
-        let (workflow_id, token) = extract(&Html::parse_document(&page)).ok_or(Error::LoginFlow)?;
-        let mut url = self.settings(session)?.sign_in_page.clone();
-        url.set_query(None);
-        url.set_path("/ww/en/signin/signin");
-        Ok((workflow_id, token, url))
+        let page = self.simple_request::<_, String, _>(::http::Request::from_parts(
+            mkreq(::http::Method::GET, uri),
+            (),
+        ))?;
+        let locale = self.profile.locale.clone();
+        login_parameters(&self.settings(session)?.sign_in_page, &locale, &page)
+            .map_err(|err| Error::SignInPage(err.into()))
+    }
+
+    /// Whether `session` holds a complete login, checking the stored tokens only. An expired
+    /// access token still counts since it is refreshed on the next request, see
+    /// [`LoginStatus::of`] to tell.
+    pub fn is_logged_in<S: Session>(&self, session: &S) -> bool {
+        LoginStatus::of(session) != LoginStatus::LoggedOut
     }
 
     #[instrument(
@@ -735,29 +1779,6 @@ impl<T: Transport> Kobo<T> {
         password: &str,
         captcha: &str,
     ) -> Result<(), Error<T::Error, S::Error>> {
-        static SCRIPT_SELECTOR: LazyLock<Selector> =
-            LazyLock::new(|| Selector::parse("script").unwrap());
-
-        #[derive(Debug, Serialize)]
-        struct RequestBody<'a> {
-            #[serde(rename = "LogInModel.WorkflowId")]
-            workflow_id: String,
-            #[serde(rename = "LogInModel.Provider")]
-            provider: &'static str,
-            #[serde(rename = "ReturnUrl")]
-            return_url: &'static str,
-            #[serde(rename = "__RequestVerificationToken")]
-            token: String,
-            #[serde(rename = "LogInModel.UserName")]
-            username: &'a str,
-            #[serde(rename = "LogInModel.Password")]
-            password: &'a str,
-            #[serde(rename = "g-recaptcha-response")]
-            g_captcha: &'a str,
-            #[serde(rename = "h-captcha-response")]
-            h_captcha: &'a str,
-        }
-
         let mut session = SessionAdapter::new(session);
         let (workflow_id, token, url) = self.login_parameters(&mut session)?;
 
@@ -765,9 +1786,9 @@ impl<T: Transport> Kobo<T> {
             ::http::Method::POST,
             ::http::Uri::from_str(url.as_str()).unwrap(),
         );
-        let body = RequestBody {
+        let body = LoginRequest {
             workflow_id,
-            provider: AFFILIATE,
+            provider: &self.profile.affiliate.clone(),
             return_url: "",
             token,
             username,
@@ -779,31 +1800,7 @@ impl<T: Transport> Kobo<T> {
             &mut session,
             ::http::Request::from_parts(parts, || Form(&body)),
         )?;
-        let doc = Html::parse_document(&page);
-        let mut script = "var location={};\n".to_string();
-        for s in doc.select(&SCRIPT_SELECTOR) {
-            script.push_str("try{\n");
-            for txt in s.text() {
-                script.push_str(txt);
-                script.push('\n');
-            }
-            script.push_str("}catch(____e){}\n");
-        }
-        let url = Url::parse(&js::extract_href(script).ok_or(Error::LoginFlow)?)
-            .map_err(|_| Error::LoginFlow)?;
-        let mut user_id = None;
-        let mut user_key = None;
-        for (key, value) in url.query_pairs() {
-            match key.as_ref() {
-                "userId" => user_id = Some(value.into_owned()),
-                "userKey" => user_key = Some(value.into_owned()),
-                _ => (),
-            }
-        }
-        let (user_id, user_key) = match (user_id, user_key) {
-            (Some(i), Some(k)) => (i, k),
-            _ => return Err(Error::LoginFlow),
-        };
+        let (user_id, user_key) = login_result(&page).ok_or_else(|| login_error(&page))?;
 
         self.authenticate_device(&mut session, Some(user_key))?;
         session.set_user_id(user_id);
@@ -816,179 +1813,748 @@ impl<T: Transport> Kobo<T> {
         session: &mut SessionAdapter<S>,
         token: Option<&str>,
     ) -> Result<book::BooksPage<E>, Error<T::Error, S::Error>> {
-        let token = token
-            .and_then(|s| if s.is_empty() { None } else { Some(s) })
-            .and_then(|s| ::http::HeaderValue::from_str(s).ok());
-        let mut parts = mkreq(
-            ::http::Method::GET,
-            ::http::Uri::from_str(self.settings(session)?.library_sync.as_str()).unwrap(),
-        );
-        if let Some(token) = token {
-            parts.headers.insert("x-kobo-synctoken", token);
-        }
-        self.request::<_, book::BooksPage<E>, _, _>(
-            session,
-            ::http::Request::from_parts(parts, || ()),
-        )
+        let req = sync_request(&self.settings(session)?.library_sync, token);
+        self.request::<_, book::BooksPage<E>, _, _>(session, req)
     }
 
+    #[allow(clippy::type_complexity)]
     fn _book_list<E: book::Entitlement, S: Session>(
         &mut self,
         session: &mut SessionAdapter<S>,
-    ) -> Result<Vec<Book>, Error<T::Error, S::Error>> {
-        let mut token = None;
+        since: Option<Box<str>>,
+    ) -> Result<BookList<T::Error, S::Error>, Error<T::Error, S::Error>> {
+        let mut token = since;
         let mut res = Vec::new();
-        loop {
-            let book::BooksPage { books, next, .. } =
-                self.sync_page::<E, S>(session, token.as_deref())?;
-            res.extend(books);
-            token = next;
-            if token.is_none() {
-                res.sort_by(|a, b| a.title.as_ref().cmp(b.title.as_ref()));
-                return Ok(res);
+        let mut removed = Vec::new();
+        let mut errors = Vec::new();
+        let mut pages = 0;
+        let mut pacer = SyncPacer::new(self.sync_page_delay);
+        self.rate_limited = false;
+        let end = loop {
+            let start = Instant::now();
+            let book::BooksPage {
+                books,
+                removed: page_removed,
+                error,
+                next,
+                token: end,
+                ..
+            } = match self.sync_page::<E, S>(session, token.as_deref()) {
+                Ok(page) => page,
+                Err(err) if pages == 0 => return Err(err),
+                Err(error) => {
+                    errors.push(PageError {
+                        page: pages + 1,
+                        error,
+                    });
+                    break None;
+                }
+            };
+            merge_sync_page(&mut res, &mut removed, books, page_removed);
+            pages += 1;
+            if let Some(error) = error {
+                errors.push(PageError {
+                    page: pages,
+                    error: Error::Json(error),
+                });
             }
+            let complete = next.is_none();
+            if !next_sync_token(&mut token, next, pages) {
+                break if complete { end } else { None };
+            }
+            let wait = pacer.next(start.elapsed(), core::mem::take(&mut self.rate_limited));
+            if !wait.is_zero() {
+                std::thread::sleep(wait);
+            }
+        };
+        // An interrupted sync cannot be resumed and the books of a skipped page would never come
+        // back, either way the next one starts over.
+        session
+            .inner_mut()
+            .set_library_sync_token(if errors.is_empty() { end } else { None });
+        session.save().map_err(Error::Session)?;
+        Ok(BookList {
+            books: finish_book_list(res),
+            removed,
+            errors,
+        })
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn book_list_since<S: Session>(
+        &mut self,
+        mut session: SessionAdapter<S>,
+        all: bool,
+        since: Option<Box<str>>,
+    ) -> Result<BookList<T::Error, S::Error>, Error<T::Error, S::Error>> {
+        if all {
+            self._book_list::<book::NewEntitlement, _>(&mut session, since)
+        } else {
+            self._book_list::<book::NewEntitlementFull, _>(&mut session, since)
         }
     }
 
+    /// Syncs the whole library, or only the books not finished yet unless `all` is set.
+    /// Previews are always left out.
+    ///
+    /// Only fails when not even the first page can be fetched, later failures end up in
+    /// [`BookList::errors`] next to the books gathered so far.
+    #[allow(clippy::type_complexity)]
     #[instrument(skip(self, session))]
     pub fn book_list<S: Session>(
         &mut self,
         session: S,
         all: bool,
-    ) -> Result<Vec<Book>, Error<T::Error, S::Error>> {
-        if all {
-            self._book_list::<book::NewEntitlement, _>(&mut SessionAdapter::new(session))
-        } else {
-            self._book_list::<book::NewEntitlementFull, _>(&mut SessionAdapter::new(session))
+    ) -> Result<BookList<T::Error, S::Error>, Error<T::Error, S::Error>> {
+        self.book_list_since(SessionAdapter::new(session), all, None)
+    }
+
+    /// Only the books added or changed since the last sync, the whole library if the session
+    /// holds no [`library_sync_token`](Session::library_sync_token).
+    #[allow(clippy::type_complexity)]
+    #[instrument(skip(self, session))]
+    pub fn book_list_changes<S: Session>(
+        &mut self,
+        session: S,
+        all: bool,
+    ) -> Result<BookList<T::Error, S::Error>, Error<T::Error, S::Error>> {
+        let since = session.library_sync_token().map(|t| t.to_boxed_str());
+        self.book_list_since(SessionAdapter::new(session), all, since)
+    }
+
+    #[instrument(skip(self, session))]
+    pub fn wishlist<S: Session>(
+        &mut self,
+        session: S,
+    ) -> Result<Vec<WishlistItem>, Error<T::Error, S::Error>> {
+        let mut session = SessionAdapter::new(session);
+        let mut res = Vec::new();
+        let mut page = 0;
+        loop {
+            let req = wishlist_request(&self.settings(&mut session)?.user_wishlist, page);
+            let book::WishlistPage {
+                items,
+                total_page_count,
+            } = self
+                .request::<_, Json<book::WishlistPage>, _, _>(&mut session, req)?
+                .into_inner();
+            res.extend(items);
+            page += 1;
+            if page >= total_page_count {
+                return Ok(res);
+            }
+        }
+    }
+
+    fn content_access<S: Session>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+        product_id: &ProductId,
+    ) -> Result<book::ContentAccess, Error<T::Error, S::Error>> {
+        let uri = access_book_uri(
+            &self.profile.clone(),
+            &self.settings(&mut *session)?.content_access_book,
+            product_id,
+        );
+        let key = content_key(session).ok_or(Error::NotLoggedIn)?;
+        let mut poll = None;
+        loop {
+            let mut parts = mkreq(::http::Method::GET, uri.clone());
+            if self.send_user_key {
+                user_key_header(session, &mut parts.headers);
+            }
+            match self.request_seed(
+                session,
+                ::http::Request::from_parts(parts, || ()),
+                Json(book::AccessBookDeserializer(&key)),
+            ) {
+                Err(Error::StatusCode(::http::StatusCode::ACCEPTED)) => {
+                    let wait = poll
+                        .get_or_insert_with(PreparingPoll::new)
+                        .next()
+                        .ok_or(Error::NotReady)?;
+                    std::thread::sleep(wait);
+                }
+                res => return res,
+            }
         }
     }
 
     pub fn access_book<S: Session>(
         &mut self,
         session: S,
-        product_id: &str,
+        product_id: &ProductId,
+        format: Option<UrlFormat>,
     ) -> Result<AccessBook, Error<T::Error, S::Error>> {
         let mut session = SessionAdapter::new(session);
-        let url = {
-            let mut url = String::new();
-            for (i, p) in self
-                .settings(&mut session)?
-                .content_access_book
-                .split("{ProductId}")
-                .enumerate()
-            {
-                if i != 0 {
-                    url.push_str(product_id);
-                }
-                url.push_str(p);
-            }
-            let mut url = Url::parse(&url).unwrap();
-            url.query_pairs_mut()
-                .append_pair("DisplayProfile", DISPLAY_PROFILE);
-            ::http::Uri::from_str(url.as_str()).unwrap()
-        };
-        let key = if let (Some(device_id), Some(user_id)) = (session.device_id(), session.user_id())
-        {
-            use std::io::Write;
-
-            let mut sha = Sha256::new();
-            write!(&mut sha, "{device_id}{user_id}").unwrap();
-            let a: [u8; 32] = sha.finalize().into();
-            Into::<aes::cipher::Key<aes::Aes128Dec>>::into([
-                a[16], a[17], a[18], a[19], a[20], a[21], a[22], a[23], a[24], a[25], a[26], a[27],
-                a[28], a[29], a[30], a[31],
-            ])
-        } else {
-            return Err(Error::NotLoggedIn);
-        };
-        let parts = mkreq(::http::Method::GET, url);
-        self.request_seed(
-            &mut session,
-            ::http::Request::from_parts(parts, || ()),
-            Json(book::AccessBookDeserializer(&key)),
-        )
+        let access = self.content_access(&mut session, product_id)?;
+        select_format(access, format)
+    }
+
+    /// Like [`Kobo::access_book`] for every format `product_id` is offered in.
+    #[allow(clippy::type_complexity)]
+    pub fn access_book_all<S: Session>(
+        &mut self,
+        session: S,
+        product_id: &ProductId,
+    ) -> Result<Vec<(UrlFormat, AccessBook)>, Error<T::Error, S::Error>> {
+        let mut session = SessionAdapter::new(session);
+        Ok(self.content_access(&mut session, product_id)?.into_all())
+    }
+
+    /// Lists the formats `product_id` can be downloaded in, [`Kobo::access_book`] picks the first
+    /// one when no format is asked for.
+    pub fn content_formats<S: Session>(
+        &mut self,
+        session: S,
+        product_id: &ProductId,
+    ) -> Result<Vec<FormatInfo>, Error<T::Error, S::Error>> {
+        let mut session = SessionAdapter::new(session);
+        Ok(self.content_access(&mut session, product_id)?.formats())
     }
 
     #[instrument(skip(self, session))]
     pub fn book_info<S: Session>(
         &mut self,
         session: S,
-        product_id: &str,
+        product_id: &ProductId,
     ) -> Result<BookInfo, Error<T::Error, S::Error>> {
         let mut session = SessionAdapter::new(session);
-        let parts = mkreq(::http::Method::GET, {
-            let mut url = String::new();
-            for (i, p) in self
-                .settings(&mut session)?
-                .book
-                .split("{ProductId}")
-                .enumerate()
-            {
-                if i != 0 {
-                    url.push_str(product_id);
-                }
-                url.push_str(p);
-            }
-            ::http::Uri::from_str(url.as_str()).unwrap()
-        });
-        self.request::<_, Json<BookInfo>, _, _>(
+        let parts = mkreq(
+            ::http::Method::GET,
+            ::http::Uri::from_str(&product_url(&self.settings(&mut session)?.book, product_id))
+                .unwrap(),
+        );
+        self.request::<_, Json<book::RawBookInfo>, _, _>(
             &mut session,
             ::http::Request::from_parts(parts, || ()),
         )
-        .map(Json::into_inner)
+        .map(|info| info.into_inner().into())
+    }
+
+    /// Streams the cover of `product_id` into `output`, or returns `None` if the book has none.
+    #[instrument(skip(self, session, output))]
+    pub fn cover<S: Session, W: Write>(
+        &mut self,
+        mut session: S,
+        product_id: &ProductId,
+        mut output: W,
+    ) -> Result<Option<W>, Error<T::Error, S::Error>> {
+        let Some(image_id) = self.book_info(&mut session, product_id)?.cover_image_id else {
+            return Ok(None);
+        };
+        let template = self
+            .settings(&mut SessionAdapter::new(&mut session))?
+            .image_url_template
+            .clone();
+        let Some(uri) = cover_uri(template.as_deref(), &image_id) else {
+            return Ok(None);
+        };
+        let res = self.raw_request(::http::Request::from_parts(
+            mkreq(::http::Method::GET, uri),
+            (),
+        ))?;
+        match res.status() {
+            ::http::StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                std::io::copy(&mut res.into_body(), &mut output)?;
+                output.flush()?;
+                Ok(Some(output))
+            }
+            status => Err(Error::StatusCode(status)),
+        }
     }
 
     #[instrument(skip(self, session, output))]
-    pub fn download<S: Session, W: std::io::Write>(
+    pub fn download<S: Session, W: Write + Seek>(
+        &mut self,
+        session: S,
+        url: &::url::Url,
+        offset: Option<u64>,
+        output: W,
+    ) -> Result<W, Error<T::Error, S::Error>> {
+        self.download_with_progress(session, url, offset, None, output, |_, _| ())
+    }
+
+    /// Like [`download`](Self::download), calling `progress` with the bytes written so far,
+    /// `offset` included, and `total` whenever `output` is written or rewound.
+    #[instrument(skip(self, session, output, progress))]
+    pub fn download_with_progress<S: Session, W: Write + Seek, F: FnMut(u64, Option<u64>)>(
         &mut self,
         session: S,
         url: &::url::Url,
+        offset: Option<u64>,
+        total: Option<u64>,
         output: W,
+        progress: F,
     ) -> Result<W, Error<T::Error, S::Error>> {
+        self.download_with_name(session, url, offset, total, output, progress)
+            .map(|d| d.output)
+    }
+
+    /// Like [`download_with_progress`](Self::download_with_progress), also returning the file
+    /// name the server suggests.
+    #[instrument(skip(self, session, output, progress))]
+    pub fn download_with_name<S: Session, W: Write + Seek, F: FnMut(u64, Option<u64>)>(
+        &mut self,
+        session: S,
+        url: &::url::Url,
+        offset: Option<u64>,
+        total: Option<u64>,
+        output: W,
+        progress: F,
+    ) -> Result<Downloaded<W>, Error<T::Error, S::Error>> {
+        let mut output = ProgressWriter {
+            inner: output,
+            position: offset.unwrap_or(0),
+            total,
+            progress,
+            cancel: self.cancel.as_deref(),
+            throttle: self.progress_throttle,
+            reported: (offset.unwrap_or(0), Instant::now()),
+        };
         let session = SessionAdapter::new(session);
-        if !session.is_logged_in() {
-            return Err(Error::NotLoggedIn);
+        let mut poll = None;
+        let (parts, body) = loop {
+            let mut req = download_request(&self.profile, &session, url, self.send_user_key)
+                .ok_or(Error::NotLoggedIn)?;
+            push_cookies(&self.cookies, url, &mut req);
+            custom_headers(&self.headers, &mut req);
+            self.apply_options(url, &mut req);
+            if let Some(ref limiter) = self.rate_limiter {
+                limiter.acquire();
+            }
+            let res = match self.transport.download(req, offset, output) {
+                Err(_) if self.is_cancelled() => return Err(Error::Cancelled),
+                res => res?,
+            };
+            pull_cookies(&mut self.cookies, url, &res);
+            let (parts, body) = res.into_parts();
+            if parts.status != ::http::StatusCode::ACCEPTED {
+                break (parts, body);
+            }
+            let wait = poll
+                .get_or_insert_with(PreparingPoll::new)
+                .next()
+                .ok_or(Error::NotReady)?;
+            std::thread::sleep(wait);
+            if self.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            output = body;
+        };
+
+        if !parts.status.is_success() {
+            return Err(Error::StatusCode(parts.status));
         }
+        let mut body = body;
+        body.finish();
+        Ok(Downloaded {
+            output: body.inner,
+            file_name: download_file_name(&parts.headers, url),
+        })
+    }
+}
 
-        let mut parts = mkreq(
-            ::http::Method::GET,
-            ::http::Uri::from_str(url.as_str()).unwrap(),
-        );
+struct ProgressWriter<'a, W, F> {
+    inner: W,
+    position: u64,
+    total: Option<u64>,
+    progress: F,
+    cancel: Option<&'a AtomicBool>,
+    throttle: ProgressThrottle,
+    /// The position and time of the last call to `progress`.
+    reported: (u64, Instant),
+}
 
-        if let Some(auth) = session
-            .access_token()
-            .and_then(|s| ::http::HeaderValue::from_str(&format!("Bearer {s}")).ok())
+impl<W, F: FnMut(u64, Option<u64>)> ProgressWriter<'_, W, F> {
+    /// Calls `progress` if the throttle allows it, always when `force` is set or the download
+    /// is complete.
+    fn report(&mut self, force: bool) {
+        let (position, at) = self.reported;
+        if force
+            || Some(self.position) == self.total
+            || self
+                .throttle
+                .is_due(self.position.abs_diff(position), at.elapsed())
         {
-            parts.headers.insert("Authorization", auth);
-        } else {
-            return Err(Error::NotLoggedIn);
+            (self.progress)(self.position, self.total);
+            self.reported = (self.position, Instant::now());
         }
-        let mut req = ::http::Request::from_parts(parts, Body::None);
-        default_headers(&mut req);
-        let (parts, body) = {
-            self.push_cookies(url, &mut req);
-            let res = self.transport.download(req, output)?;
-            self.pull_cookies(url, &res);
-            res.into_parts()
-        };
+    }
 
-        if !parts.status.is_success() {
-            return Err(Error::StatusCode(parts.status));
+    /// Reports the position if the last write was throttled.
+    fn finish(&mut self) {
+        if self.reported.0 != self.position {
+            self.report(true);
+        }
+    }
+}
+
+impl<W: Write, F: FnMut(u64, Option<u64>)> Write for ProgressWriter<'_, W, F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+            return Err(std::io::Error::other("download cancelled"));
+        }
+        let n = self.inner.write(buf)?;
+        self.position += n as u64;
+        self.report(false);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek, F: FnMut(u64, Option<u64>)> Seek for ProgressWriter<'_, W, F> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        self.report(true);
+        Ok(self.position)
+    }
+}
+
+fn default_agent() -> DefaultAgent {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "curl")] {
+            CurlAgent::new()
+        } else if #[cfg(feature = "ureq")] {
+            ::ureq::config::Config::builder().http_status_as_error(false).build().new_agent()
+        } else {
+            compiler_error!("No transport available.");
         }
-        Ok(body)
     }
 }
 
 impl Default for Kobo<DefaultAgent> {
     fn default() -> Self {
-        cfg_if::cfg_if! {
-            if #[cfg(feature = "curl")] {
-                Kobo::new(CurlAgent)
-            } else if #[cfg(feature = "ureq")] {
-                Kobo::new(::ureq::config::Config::builder().http_status_as_error(false).build().new_agent())
+        Kobo::new(default_agent())
+    }
+}
+
+impl Kobo<DefaultAgent> {
+    /// Configures every option at once, see [`KoboBuilder`].
+    pub fn builder() -> KoboBuilder {
+        KoboBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Mutex};
+
+    use super::*;
+    use crate::{InMemorySession, NonEmptyStr};
+
+    const BASE_URL: &str = "http://kobo.test/api/";
+
+    fn response(
+        status: u16,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> ::http::Response<Cursor<Vec<u8>>> {
+        let mut res = ::http::Response::builder().status(status);
+        for (name, value) in headers {
+            res = res.header(*name, *value);
+        }
+        res.body(Cursor::new(body.as_bytes().to_vec())).unwrap()
+    }
+
+    fn token(s: &str) -> Option<Box<NonEmptyStr>> {
+        NonEmptyStr::from_string(s.to_string())
+    }
+
+    fn logged_in() -> InMemorySession {
+        InMemorySession {
+            access_token: token("old-access"),
+            device_id: token("device"),
+            refresh_token: token("old-refresh"),
+            user_id: token("user"),
+            user_key: token("key"),
+            library_sync_token: None,
+        }
+    }
+
+    fn initialization() -> String {
+        let url = |path: &str| format!("{BASE_URL}{path}");
+        serde_json::json!({
+            "Resources": {
+                "sign_in_page": url("signin"),
+                "book": url("v1/products/books/{ProductId}"),
+                "library_sync": url("v1/library/sync"),
+                "user_wishlist": url("v1/user/wishlist"),
+                "content_access_book": url("v1/products/books/{ProductId}/access"),
+            }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn book_list_against_a_mock_server() {
+        let id = "a0e6e4c5-8f3b-4c2e-9d2b-7f1a3c5e9b10";
+        let sync = serde_json::json!([{
+            "NewEntitlement": {
+                "BookEntitlement": { "Id": id },
+                "BookMetadata": {
+                    "RevisionId": id,
+                    "Title": "A Book",
+                    "ContributorRoles": [{ "Role": "Author", "Name": "An Author" }],
+                },
+            }
+        }])
+        .to_string();
+        let seen = Mutex::new(Vec::new());
+        let transport = FnTransport::new(|req: ::http::Request<Body<'_>>| {
+            let auth = req
+                .headers()
+                .get("Authorization")
+                .map(|v| v.to_str().unwrap().to_string());
+            seen.lock()
+                .unwrap()
+                .push((req.method().clone(), req.uri().to_string(), auth.clone()));
+            match (req.uri().path(), auth.as_deref()) {
+                ("/api/v1/initialization", Some("Bearer old-access")) => {
+                    response(200, &[], &initialization())
+                }
+                ("/api/v1/auth/refresh", _) => response(
+                    200,
+                    &[],
+                    r#"{"TokenType":"Bearer","AccessToken":"new-access","RefreshToken":"new-refresh"}"#,
+                ),
+                ("/api/v1/library/sync", Some("Bearer new-access")) => {
+                    response(200, &[("x-kobo-synctoken", "sync-token")], &sync)
+                }
+                ("/api/v1/library/sync", _) => response(401, &[], ""),
+                _ => response(404, &[], ""),
+            }
+        });
+        let mut kobo = Kobo::new(transport).with_base_url(Url::parse(BASE_URL).unwrap());
+        let mut session = logged_in();
+
+        let list = kobo.book_list(&mut session, true).unwrap();
+        assert!(list.is_complete());
+        assert_eq!(list.books.len(), 1);
+        assert_eq!(list.books[0].revision_id.as_str(), id);
+        assert_eq!(&*list.books[0].title, "A Book");
+        assert_eq!(list.books[0].authors.as_deref(), Some("An Author"));
+
+        assert_eq!(
+            session.access_token.as_deref().map(|t| &**t),
+            Some("new-access")
+        );
+        assert_eq!(
+            session.refresh_token.as_deref().map(|t| &**t),
+            Some("new-refresh")
+        );
+        assert_eq!(
+            session.library_sync_token.as_deref().map(|t| &**t),
+            Some("sync-token")
+        );
+
+        let seen = seen.into_inner().unwrap();
+        let paths = seen
+            .iter()
+            .map(|(method, uri, _)| (method.as_str(), uri.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                ("GET", "http://kobo.test/api/v1/initialization"),
+                ("GET", "http://kobo.test/api/v1/library/sync"),
+                ("POST", "http://kobo.test/api/v1/auth/refresh"),
+                ("GET", "http://kobo.test/api/v1/library/sync"),
+            ]
+        );
+    }
+
+    fn redirect_request<T: Transport>(
+        kobo: &mut Kobo<T>,
+        path: &str,
+    ) -> Result<(), Error<T::Error, std::convert::Infallible>> {
+        let req = ::http::Request::get(format!("{BASE_URL}{path}"))
+            .body("")
+            .unwrap();
+        kobo.raw_request(req).map(drop)
+    }
+
+    #[test]
+    fn redirect_to_itself_fails_at_once() {
+        let seen = Mutex::new(0);
+        let mut kobo = Kobo::new(FnTransport::new(|_: ::http::Request<Body<'_>>| {
+            *seen.lock().unwrap() += 1;
+            response(302, &[("Location", "loop")], "")
+        }));
+
+        let res = redirect_request(&mut kobo, "loop");
+        assert!(matches!(res, Err(Error::TooManyRedirects)));
+        assert_eq!(seen.into_inner().unwrap(), 1);
+    }
+
+    #[test]
+    fn redirect_to_itself_setting_cookies_is_followed() {
+        let seen = Mutex::new(0);
+        let mut kobo = Kobo::new(FnTransport::new(|req: ::http::Request<Body<'_>>| {
+            *seen.lock().unwrap() += 1;
+            if req.headers().get("Cookie").is_some_and(|c| c == "a=b") {
+                response(200, &[], "")
             } else {
-                compiler_error!("No transport available.");
+                response(302, &[("Location", "loop"), ("Set-Cookie", "a=b")], "")
             }
-        }
+        }));
+
+        redirect_request(&mut kobo, "loop").unwrap();
+        assert_eq!(seen.into_inner().unwrap(), 2);
+    }
+
+    #[test]
+    fn redirect_cycle_stops_at_max_redirects() {
+        let seen = Mutex::new(Vec::new());
+        let mut kobo = Kobo::new(FnTransport::new(|req: ::http::Request<Body<'_>>| {
+            let path = req.uri().path().to_string();
+            let next = if path.ends_with("/a") { "b" } else { "a" };
+            seen.lock().unwrap().push(path);
+            response(302, &[("Location", next)], "")
+        }));
+        kobo.set_max_redirects(3);
+
+        let res = redirect_request(&mut kobo, "a");
+        assert!(matches!(res, Err(Error::TooManyRedirects)));
+        assert_eq!(
+            seen.into_inner().unwrap(),
+            ["/api/a", "/api/b", "/api/a", "/api/b"]
+        );
+    }
+
+    #[test]
+    fn redirect_chain_up_to_max_redirects() {
+        let transport = || {
+            FnTransport::new(|req: ::http::Request<Body<'_>>| {
+                let n = req.uri().path()["/api/".len()..].parse::<usize>().unwrap();
+                if n == 0 {
+                    response(200, &[], "")
+                } else {
+                    response(302, &[("Location", &(n - 1).to_string())], "")
+                }
+            })
+        };
+
+        let mut kobo = Kobo::new(transport());
+        kobo.set_max_redirects(3);
+        redirect_request(&mut kobo, "3").unwrap();
+        let res = redirect_request(&mut kobo, "4");
+        assert!(matches!(res, Err(Error::TooManyRedirects)));
+    }
+
+    #[test]
+    fn resumed_download_keeps_its_prefix_across_a_202() {
+        let seen = Mutex::new(Vec::new());
+        let mut kobo = Kobo::new(FnTransport::new(|req: ::http::Request<Body<'_>>| {
+            let range = req
+                .headers()
+                .get("Range")
+                .map(|v| v.to_str().unwrap().to_string());
+            let mut seen = seen.lock().unwrap();
+            seen.push(range);
+            if seen.len() == 1 {
+                response(202, &[], "preparing")
+            } else {
+                response(206, &[("Content-Range", "bytes 6-9/10")], "tail")
+            }
+        }));
+        let mut output = Cursor::new(b"prefix".to_vec());
+        output.set_position(6);
+
+        let downloaded = kobo
+            .download_with_name(
+                logged_in(),
+                &Url::parse("http://kobo.test/book").unwrap(),
+                Some(6),
+                Some(10),
+                output,
+                |_, _| (),
+            )
+            .unwrap();
+        assert_eq!(downloaded.output.into_inner(), b"prefixtail");
+        assert_eq!(
+            seen.into_inner().unwrap(),
+            [Some("bytes=6-".to_string()), Some("bytes=6-".to_string())]
+        );
+    }
+
+    const SIGN_IN_PAGE: &str = "https://authorize.kobo.com/ww/en/signin?wsa=kobo";
+
+    fn sign_in(page: &str) -> Result<(String, String, Url), String> {
+        login_parameters(&Url::parse(SIGN_IN_PAGE).unwrap(), "it/it", page)
+    }
+
+    #[test]
+    fn sign_in_page_current() {
+        let page = r#"<html><head><title>Sign in</title></head><body>
+            <form id="search"><input name="__RequestVerificationToken" value="other"></form>
+            <section id="defaultOptions"><form method="post">
+                <div id="signInBlock">
+                    <input name="LogInModel.WorkflowId" value="workflow">
+                    <input name="__RequestVerificationToken" value="token">
+                    <input name="LogInModel.UserName">
+                </div>
+            </form></section>
+        </body></html>"#;
+        let (workflow_id, token, url) = sign_in(page).unwrap();
+        assert_eq!(workflow_id, "workflow");
+        assert_eq!(token, "token");
+        assert_eq!(
+            url.as_str(),
+            "https://authorize.kobo.com/it/it/signin/signin"
+        );
+    }
+
+    #[test]
+    fn sign_in_page_reworked() {
+        let page = r#"<html><body>
+            <form id="newsletter"><input name="__RequestVerificationToken" value="other"></form>
+            <main><form method="post">
+                <input name="SignIn.WorkflowId" value="workflow">
+                <input name="__RequestVerificationToken" value="token">
+            </form></main>
+        </body></html>"#;
+        let (workflow_id, token, _) = sign_in(page).unwrap();
+        assert_eq!(workflow_id, "workflow");
+        assert_eq!(token, "token");
+    }
+
+    #[test]
+    fn sign_in_token_from_the_posted_form_only() {
+        let page = r#"<html><body>
+            <form id="newsletter"><input name="__RequestVerificationToken" value="other"></form>
+            <form method="post"><input name="LogInModel.WorkflowId" value="workflow"></form>
+        </body></html>"#;
+        assert_eq!(
+            sign_in(page).unwrap_err(),
+            "no verification token, forms: 2, inputs: __RequestVerificationToken, LogInModel.WorkflowId"
+        );
+    }
+
+    #[test]
+    fn sign_in_page_without_form() {
+        let page = r#"<html><head><title>
+            Just a moment...
+        </title></head><body><input name="cf-turnstile-response" value="secret"></body></html>"#;
+        let transport = FnTransport::new(|req: ::http::Request<Body<'_>>| match req.uri().path() {
+            "/api/v1/initialization" => response(200, &[], &initialization()),
+            "/api/signin" => response(200, &[], page),
+            _ => response(404, &[], ""),
+        });
+        let mut kobo = Kobo::new(transport).with_base_url(Url::parse(BASE_URL).unwrap());
+
+        let err = kobo
+            .login_parameters(&mut SessionAdapter::new(logged_in()))
+            .unwrap_err();
+        assert!(matches!(err, Error::SignInPage(_)));
+        assert_eq!(
+            err.to_string(),
+            "Cannot find the sign in form, Kobo may have changed the page: no sign in form, \
+             title: \"Just a moment...\", forms: 0, inputs: cf-turnstile-response"
+        );
     }
 }
@@ -0,0 +1,884 @@
+use std::{
+    future::Future,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use ::url::Url;
+use tokio::io::{AsyncSeek, AsyncWrite, AsyncWriteExt};
+use tracing::instrument;
+
+use crate::{LoginStatus, Session, SessionAdapter};
+
+use super::{
+    access_book_uri, apply_options, bearer, book, content_key, cover_uri, custom_headers,
+    default_headers, device_auth_request, download_file_name, download_request, export_cookies,
+    finish_book_list, import_cookies, initialization_request, login_error, login_parameters,
+    login_result, merge_sync_page, mkreq, next_sync_token, product_url, pull_cookies, push_cookies,
+    refresh_error, refresh_request, select_format, sign_in_page_uri, sync_request, user_key_header,
+    wishlist_request, AccessBook, Body, BookInfo, BookList, DeviceAuthResponse,
+    DeviceAuthResponseFull, DeviceProfile, Downloaded, Error, Form, FormatInfo, FromResponse,
+    FromResponseSeed, Initialization, IntoRequest, Json, LoginRequest, PageError, PreparingPoll,
+    ProductId, RateLimiter, RefreshResponse, Settings, SyncPacer, TransportOptions, UrlFormat,
+    WishlistItem, DEFAULT_BASE_URL, MAX_REDIRECTS,
+};
+
+/// Asynchronous counterpart of [`Transport`](super::Transport).
+///
+/// Responses are read whole, redirects must not be followed.
+pub trait AsyncTransport {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn request<S: Send + Sync + 'static>(
+        &mut self,
+        req: http::Request<Body<'_>>,
+    ) -> impl Future<Output = Result<http::Response<::bytes::Bytes>, Error<Self::Error, S>>> + Send;
+
+    /// Streams the response body of `req` into `output`, see
+    /// [`Transport::download`](super::Transport::download).
+    fn download<S: Send + Sync + 'static, W: AsyncWrite + AsyncSeek + Unpin + Send>(
+        &mut self,
+        req: http::Request<Body<'_>>,
+        offset: Option<u64>,
+        output: W,
+    ) -> impl Future<Output = Result<::http::Response<W>, Error<Self::Error, S>>> + Send;
+}
+
+/// Asynchronous counterpart of [`Kobo`](super::Kobo).
+///
+/// Proxies, TLS and buffering are configured on the transport itself, e.g. the
+/// `reqwest::Client` it is built with.
+#[derive(Debug)]
+pub struct AsyncKobo<T: AsyncTransport> {
+    settings: Option<Settings>,
+    cookies: cookie_store::CookieStore,
+    options: TransportOptions,
+    profile: DeviceProfile,
+    base_url: Url,
+    /// Set whenever a request is retried after being rate limited.
+    rate_limited: bool,
+    sync_page_delay: Duration,
+    send_user_key: bool,
+    headers: ::http::HeaderMap,
+    rate_limiter: Option<RateLimiter>,
+    max_redirects: usize,
+    transport: T,
+}
+
+impl<T: AsyncTransport> AsyncKobo<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            settings: None,
+            cookies: ::cookie_store::CookieStore::new(None),
+            options: TransportOptions::default(),
+            profile: DeviceProfile::from_env(),
+            base_url: Url::parse(DEFAULT_BASE_URL).unwrap(),
+            rate_limited: false,
+            sync_page_delay: Duration::ZERO,
+            send_user_key: false,
+            headers: ::http::HeaderMap::new(),
+            rate_limiter: None,
+            max_redirects: MAX_REDIRECTS,
+            transport,
+        }
+    }
+
+    /// Replaces the profile read from the environment by [`AsyncKobo::new`].
+    pub fn with_device_profile(mut self, profile: DeviceProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn device_profile(&self) -> &DeviceProfile {
+        &self.profile
+    }
+
+    /// Talks to the store API at `base_url` instead of `https://storeapi.kobo.com/`, e.g. a staging
+    /// or mock server. The other endpoints are the ones it advertises on initialization.
+    pub fn with_base_url(mut self, mut base_url: Url) -> Self {
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        self.base_url = base_url;
+        self.settings = None;
+        self
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.options.timeout = Some(timeout);
+    }
+
+    /// See [`Kobo::set_sync_page_delay`](super::Kobo::set_sync_page_delay).
+    pub fn set_sync_page_delay(&mut self, delay: Duration) {
+        self.sync_page_delay = delay;
+    }
+
+    /// See [`Kobo::set_max_redirects`](super::Kobo::set_max_redirects).
+    pub fn set_max_redirects(&mut self, max: usize) {
+        self.max_redirects = max;
+    }
+
+    /// See [`Kobo::set_send_user_key`](super::Kobo::set_send_user_key).
+    pub fn set_send_user_key(&mut self, send: bool) {
+        self.send_user_key = send;
+    }
+
+    /// See [`Kobo::set_rate_limiter`](super::Kobo::set_rate_limiter).
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// See [`Kobo::set_header`](super::Kobo::set_header).
+    pub fn set_header<K: ::http::header::IntoHeaderName>(
+        &mut self,
+        name: K,
+        value: ::http::HeaderValue,
+    ) {
+        self.headers.insert(name, value);
+    }
+
+    /// See [`Kobo::remove_header`](super::Kobo::remove_header).
+    pub fn remove_header<K: ::http::header::AsHeaderName>(
+        &mut self,
+        name: K,
+    ) -> Option<::http::HeaderValue> {
+        self.headers.remove(name)
+    }
+
+    /// See [`Kobo::export_cookies`](super::Kobo::export_cookies).
+    pub fn export_cookies(&self) -> String {
+        export_cookies(&self.cookies)
+    }
+
+    /// See [`Kobo::import_cookies`](super::Kobo::import_cookies).
+    pub fn import_cookies(&mut self, json: &str) -> Result<(), serde_json::Error> {
+        self.cookies = import_cookies(json)?;
+        Ok(())
+    }
+
+    async fn rate_limit(&self) {
+        if let Some(ref limiter) = self.rate_limiter {
+            let wait = limiter.reserve();
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+
+    fn apply_options<B>(&self, url: &Url, req: &mut ::http::Request<B>) {
+        apply_options(&self.options, url, req);
+    }
+
+    async fn send<'a, InB: IntoRequest<'a>, S: Send + Sync + 'static>(
+        &mut self,
+        url: &Url,
+        mut req: http::Request<InB>,
+    ) -> Result<::http::Response<::bytes::Bytes>, Error<T::Error, S>> {
+        default_headers(&self.profile, &mut req);
+        let (parts, body) = req.into_parts();
+        let mut r = body.into_request(parts)?;
+        push_cookies(&self.cookies, url, &mut r);
+        custom_headers(&self.headers, &mut r);
+        self.apply_options(url, &mut r);
+        let (parts, mut body) = r.into_parts();
+        let mut attempts = 1;
+        loop {
+            // Streamed bodies can't be sent twice, the response is returned as is then.
+            let (body, last) = match body.try_clone() {
+                Some(body) => (body, false),
+                None => (core::mem::replace(&mut body, Body::None), true),
+            };
+            let r = ::http::Request::from_parts(parts.clone(), body);
+            #[cfg(feature = "trace-http")]
+            super::trace_request(&r);
+            self.rate_limit().await;
+            let res = self.transport.request(r).await?;
+            #[cfg(feature = "trace-http")]
+            super::trace_response(&res);
+            pull_cookies(&mut self.cookies, url, &res);
+            match super::retry_after(&res, attempts, super::RATE_LIMIT_ATTEMPTS) {
+                Some(wait) if !last => {
+                    self.rate_limited = true;
+                    tokio::time::sleep(wait).await;
+                }
+                _ => return Ok(res),
+            }
+            attempts += 1;
+        }
+    }
+
+    async fn raw_request<'a, InB: IntoRequest<'a>, S: Send + Sync + 'static>(
+        &mut self,
+        req: http::Request<InB>,
+    ) -> Result<::http::Response<::bytes::Bytes>, Error<T::Error, S>> {
+        let mut url = Url::parse(&req.uri().to_string()).unwrap();
+        let mut res = self.send(&url, req).await?;
+        let mut redirects = 0;
+        while let Some(next) = super::redirect_location(&url, &res) {
+            if redirects == self.max_redirects || super::is_redirect_loop(&url, &next, &res) {
+                return Err(Error::TooManyRedirects);
+            }
+            redirects += 1;
+            url = next;
+            let req = ::http::Request::from_parts(
+                mkreq(
+                    ::http::Method::GET,
+                    ::http::Uri::from_str(url.as_str()).unwrap(),
+                ),
+                (),
+            );
+            res = self.send(&url, req).await?;
+        }
+        Ok(res)
+    }
+
+    async fn simple_request<
+        'a,
+        InB: IntoRequest<'a>,
+        OutB: FromResponse,
+        S: Send + Sync + 'static,
+    >(
+        &mut self,
+        req: http::Request<InB>,
+    ) -> Result<OutB, Error<T::Error, S>> {
+        let (parts, body) = self.raw_request(req).await?.into_parts();
+        OutB::from_response(parts, body.as_ref())
+    }
+
+    #[allow(clippy::type_complexity)]
+    async fn _anon_raw_request<'a, InB, S, F>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+        req: http::Request<F>,
+    ) -> Result<::http::Response<::bytes::Bytes>, Error<T::Error, S::Error>>
+    where
+        InB: IntoRequest<'a>,
+        S: Session,
+        F: Fn() -> InB,
+    {
+        let (mut parts, body) = req.into_parts();
+
+        let res = {
+            let auth = self.get_authorization(session).await?;
+            let mut parts = parts.clone();
+            parts.headers.insert("Authorization", auth);
+            self.raw_request(::http::Request::from_parts(parts, body()))
+                .await?
+        };
+
+        if res.status() != ::http::StatusCode::UNAUTHORIZED {
+            return Ok(res);
+        }
+        parts
+            .headers
+            .insert("Authorization", self.refresh_auth(session).await?);
+        self.raw_request(::http::Request::from_parts(parts, body()))
+            .await
+    }
+
+    async fn anon_request<'a, InB, OutB, S, F>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+        req: http::Request<F>,
+    ) -> Result<OutB, Error<T::Error, S::Error>>
+    where
+        InB: IntoRequest<'a>,
+        OutB: FromResponse,
+        S: Session,
+        F: Fn() -> InB,
+    {
+        let (parts, body) = self._anon_raw_request(session, req).await?.into_parts();
+        OutB::from_response(parts, body.as_ref())
+    }
+
+    #[allow(clippy::type_complexity)]
+    async fn _raw_request<'a, InB, S, F>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+        req: http::Request<F>,
+    ) -> Result<::http::Response<::bytes::Bytes>, Error<T::Error, S::Error>>
+    where
+        InB: IntoRequest<'a>,
+        S: Session,
+        F: Fn() -> InB,
+    {
+        if !session.is_logged_in() {
+            return Err(Error::NotLoggedIn);
+        }
+
+        let (mut parts, body) = req.into_parts();
+
+        let res = {
+            if let Some(auth) = session.access_token().and_then(|s| bearer(s)) {
+                let mut parts = parts.clone();
+                parts.headers.insert("Authorization", auth);
+                self.raw_request(::http::Request::from_parts(parts, body()))
+                    .await?
+            } else {
+                return Err(Error::NotLoggedIn);
+            }
+        };
+
+        if res.status() != ::http::StatusCode::UNAUTHORIZED {
+            return Ok(res);
+        }
+
+        parts
+            .headers
+            .insert("Authorization", self.refresh_auth(session).await?);
+        if !session.is_logged_in() {
+            return Err(Error::NotLoggedIn);
+        }
+        self.raw_request(::http::Request::from_parts(parts, body()))
+            .await
+    }
+
+    async fn request<'a, InB, OutB, S, F>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+        req: http::Request<F>,
+    ) -> Result<OutB, Error<T::Error, S::Error>>
+    where
+        InB: IntoRequest<'a>,
+        OutB: FromResponse,
+        S: Session,
+        F: Fn() -> InB,
+    {
+        let (parts, body) = self._raw_request(session, req).await?.into_parts();
+        OutB::from_response(parts, body.as_ref())
+    }
+
+    async fn request_seed<'a, InB, Seed, S, F>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+        req: http::Request<F>,
+        seed: Seed,
+    ) -> Result<Seed::Value, Error<T::Error, S::Error>>
+    where
+        InB: IntoRequest<'a>,
+        Seed: FromResponseSeed<'a>,
+        S: Session,
+        F: Fn() -> InB,
+    {
+        let (parts, body) = self._raw_request(session, req).await?.into_parts();
+        seed.from_response(parts, body.as_ref())
+    }
+
+    #[instrument(skip(self, session))]
+    async fn refresh_auth<S: Session>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+    ) -> Result<::http::HeaderValue, Error<T::Error, S::Error>> {
+        if let (Some(access_token), Some(refresh_token)) = (
+            session.access_token().and_then(|s| bearer(s)),
+            session.refresh_token(),
+        ) {
+            let RefreshResponse {
+                token_type,
+                access_token,
+                refresh_token,
+            } = self
+                .simple_request::<_, Json<RefreshResponse>, _>(refresh_request(
+                    &self.profile.clone(),
+                    &self.base_url.clone(),
+                    access_token,
+                    refresh_token,
+                ))
+                .await
+                .map_err(refresh_error)?
+                .into_inner();
+            assert!(token_type == "Bearer");
+            session.refresh_tokens(access_token, refresh_token);
+        }
+
+        self.get_authorization(session).await
+    }
+
+    async fn get_authorization<S: Session>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+    ) -> Result<::http::HeaderValue, Error<T::Error, S::Error>> {
+        loop {
+            if let Some(access_token) = session.access_token() {
+                if let Some(h) = bearer(access_token) {
+                    return Ok(h);
+                }
+                session.set_tokens("", "");
+            }
+            self.authenticate_device(session, None).await?;
+            session.save().map_err(Error::Session)?;
+        }
+    }
+
+    #[instrument(skip(self, session))]
+    async fn authenticate_device<S: Session>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+        user_key: Option<String>,
+    ) -> Result<(), Error<T::Error, S::Error>> {
+        let user_key = user_key.and_then(|s| if s.is_empty() { None } else { Some(s) });
+
+        if session.is_auth_set() && user_key.is_none() {
+            return Ok(());
+        }
+
+        let device_id = loop {
+            if let Some(id) = session.device_id() {
+                break id;
+            }
+            session.set_device_id(uuid::Uuid::now_v7().to_string());
+        };
+        let (profile, base_url) = (self.profile.clone(), self.base_url.clone());
+        let req = device_auth_request(&profile, &base_url, device_id, user_key.as_deref());
+
+        let (res, user_key) = if user_key.is_some() {
+            let (res, user_key) = self
+                .simple_request::<_, Json<DeviceAuthResponseFull>, _>(req)
+                .await?
+                .into_inner()
+                .into_parts();
+            (res, Some(user_key))
+        } else {
+            (
+                self.simple_request::<_, Json<DeviceAuthResponse>, _>(req)
+                    .await?
+                    .into_inner(),
+                None,
+            )
+        };
+
+        assert!(&*res.token_type == "Bearer");
+        session.set_tokens(res.access_token, res.refresh_token);
+        if let Some(user_key) = user_key {
+            session.set_user_key(user_key);
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self, session))]
+    async fn settings<S: Session>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+    ) -> Result<&Settings, Error<T::Error, S::Error>> {
+        if self.settings.is_none() {
+            self.settings = Some(
+                self.anon_request::<_, Json<Initialization>, _, _>(
+                    session,
+                    initialization_request(&self.base_url.clone()),
+                )
+                .await?
+                .into_inner()
+                .resources,
+            );
+        }
+        Ok(self.settings.as_ref().unwrap())
+    }
+
+    /// See [`Kobo::is_logged_in`](super::Kobo::is_logged_in).
+    pub fn is_logged_in<S: Session>(&self, session: &S) -> bool {
+        LoginStatus::of(session) != LoginStatus::LoggedOut
+    }
+
+    #[instrument(
+        skip(self, session, username, password, captcha),
+        fields(username = username, password = "***", captcha = captcha)
+    )]
+    pub async fn login<S: Session>(
+        &mut self,
+        session: S,
+        username: &str,
+        password: &str,
+        captcha: &str,
+    ) -> Result<(), Error<T::Error, S::Error>> {
+        let mut session = SessionAdapter::new(session);
+
+        let uri = sign_in_page_uri(
+            &self.profile.clone(),
+            &self.settings(&mut session).await?.sign_in_page,
+            session.device_id().unwrap(),
+        );
+        let page = self
+            .simple_request::<_, String, _>(::http::Request::from_parts(
+                mkreq(::http::Method::GET, uri),
+                (),
+            ))
+            .await?;
+        let locale = self.profile.locale.clone();
+        let (workflow_id, token, url) = login_parameters(
+            &self.settings(&mut session).await?.sign_in_page,
+            &locale,
+            &page,
+        )
+        .map_err(|err| Error::SignInPage(err.into()))?;
+
+        let parts = mkreq(
+            ::http::Method::POST,
+            ::http::Uri::from_str(url.as_str()).unwrap(),
+        );
+        let body = LoginRequest {
+            workflow_id,
+            provider: &self.profile.affiliate.clone(),
+            return_url: "",
+            token,
+            username,
+            password,
+            g_captcha: captcha,
+            h_captcha: captcha,
+        };
+        let page = self
+            .anon_request::<_, String, _, _>(
+                &mut session,
+                ::http::Request::from_parts(parts, || Form(&body)),
+            )
+            .await?;
+        let (user_id, user_key) = login_result(&page).ok_or_else(|| login_error(&page))?;
+
+        self.authenticate_device(&mut session, Some(user_key))
+            .await?;
+        session.set_user_id(user_id);
+        session.save().map_err(Error::Session)?;
+        Ok(())
+    }
+
+    #[allow(clippy::type_complexity)]
+    async fn _book_list<E: book::Entitlement, S: Session>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+        since: Option<Box<str>>,
+    ) -> Result<BookList<T::Error, S::Error>, Error<T::Error, S::Error>> {
+        let mut token = since;
+        let mut res = Vec::new();
+        let mut removed = Vec::new();
+        let mut errors = Vec::new();
+        let mut pages = 0;
+        let mut pacer = SyncPacer::new(self.sync_page_delay);
+        self.rate_limited = false;
+        let end = loop {
+            let start = Instant::now();
+            let page = match self.settings(session).await {
+                Ok(settings) => {
+                    let req = sync_request(&settings.library_sync, token.as_deref());
+                    self.request::<_, book::BooksPage<E>, _, _>(session, req)
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            let book::BooksPage {
+                books,
+                removed: page_removed,
+                error,
+                next,
+                token: end,
+                ..
+            } = match page {
+                Ok(page) => page,
+                Err(err) if pages == 0 => return Err(err),
+                Err(error) => {
+                    errors.push(PageError {
+                        page: pages + 1,
+                        error,
+                    });
+                    break None;
+                }
+            };
+            merge_sync_page(&mut res, &mut removed, books, page_removed);
+            pages += 1;
+            if let Some(error) = error {
+                errors.push(PageError {
+                    page: pages,
+                    error: Error::Json(error),
+                });
+            }
+            let complete = next.is_none();
+            if !next_sync_token(&mut token, next, pages) {
+                break if complete { end } else { None };
+            }
+            let wait = pacer.next(start.elapsed(), core::mem::take(&mut self.rate_limited));
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        };
+        session
+            .inner_mut()
+            .set_library_sync_token(if errors.is_empty() { end } else { None });
+        session.save().map_err(Error::Session)?;
+        Ok(BookList {
+            books: finish_book_list(res),
+            removed,
+            errors,
+        })
+    }
+
+    #[allow(clippy::type_complexity)]
+    async fn book_list_since<S: Session>(
+        &mut self,
+        mut session: SessionAdapter<S>,
+        all: bool,
+        since: Option<Box<str>>,
+    ) -> Result<BookList<T::Error, S::Error>, Error<T::Error, S::Error>> {
+        if all {
+            self._book_list::<book::NewEntitlement, _>(&mut session, since)
+                .await
+        } else {
+            self._book_list::<book::NewEntitlementFull, _>(&mut session, since)
+                .await
+        }
+    }
+
+    /// See [`Kobo::book_list`](super::Kobo::book_list).
+    #[allow(clippy::type_complexity)]
+    #[instrument(skip(self, session))]
+    pub async fn book_list<S: Session>(
+        &mut self,
+        session: S,
+        all: bool,
+    ) -> Result<BookList<T::Error, S::Error>, Error<T::Error, S::Error>> {
+        self.book_list_since(SessionAdapter::new(session), all, None)
+            .await
+    }
+
+    /// See [`Kobo::book_list_changes`](super::Kobo::book_list_changes).
+    #[allow(clippy::type_complexity)]
+    #[instrument(skip(self, session))]
+    pub async fn book_list_changes<S: Session>(
+        &mut self,
+        session: S,
+        all: bool,
+    ) -> Result<BookList<T::Error, S::Error>, Error<T::Error, S::Error>> {
+        let since = session.library_sync_token().map(|t| t.to_boxed_str());
+        self.book_list_since(SessionAdapter::new(session), all, since)
+            .await
+    }
+
+    #[instrument(skip(self, session))]
+    pub async fn wishlist<S: Session>(
+        &mut self,
+        session: S,
+    ) -> Result<Vec<WishlistItem>, Error<T::Error, S::Error>> {
+        let mut session = SessionAdapter::new(session);
+        let mut res = Vec::new();
+        let mut page = 0;
+        loop {
+            let req = wishlist_request(&self.settings(&mut session).await?.user_wishlist, page);
+            let book::WishlistPage {
+                items,
+                total_page_count,
+            } = self
+                .request::<_, Json<book::WishlistPage>, _, _>(&mut session, req)
+                .await?
+                .into_inner();
+            res.extend(items);
+            page += 1;
+            if page >= total_page_count {
+                return Ok(res);
+            }
+        }
+    }
+
+    async fn content_access<S: Session>(
+        &mut self,
+        session: &mut SessionAdapter<S>,
+        product_id: &ProductId,
+    ) -> Result<book::ContentAccess, Error<T::Error, S::Error>> {
+        let uri = access_book_uri(
+            &self.profile.clone(),
+            &self.settings(&mut *session).await?.content_access_book,
+            product_id,
+        );
+        let key = content_key(session).ok_or(Error::NotLoggedIn)?;
+        let mut poll = None;
+        loop {
+            let mut parts = mkreq(::http::Method::GET, uri.clone());
+            if self.send_user_key {
+                user_key_header(session, &mut parts.headers);
+            }
+            match self
+                .request_seed(
+                    session,
+                    ::http::Request::from_parts(parts, || ()),
+                    Json(book::AccessBookDeserializer(&key)),
+                )
+                .await
+            {
+                Err(Error::StatusCode(::http::StatusCode::ACCEPTED)) => {
+                    let wait = poll
+                        .get_or_insert_with(PreparingPoll::new)
+                        .next()
+                        .ok_or(Error::NotReady)?;
+                    tokio::time::sleep(wait).await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    pub async fn access_book<S: Session>(
+        &mut self,
+        session: S,
+        product_id: &ProductId,
+        format: Option<UrlFormat>,
+    ) -> Result<AccessBook, Error<T::Error, S::Error>> {
+        let mut session = SessionAdapter::new(session);
+        let access = self.content_access(&mut session, product_id).await?;
+        select_format(access, format)
+    }
+
+    /// See [`Kobo::access_book_all`](super::Kobo::access_book_all).
+    pub async fn access_book_all<S: Session>(
+        &mut self,
+        session: S,
+        product_id: &ProductId,
+    ) -> Result<Vec<(UrlFormat, AccessBook)>, Error<T::Error, S::Error>> {
+        let mut session = SessionAdapter::new(session);
+        Ok(self
+            .content_access(&mut session, product_id)
+            .await?
+            .into_all())
+    }
+
+    /// See [`Kobo::content_formats`](super::Kobo::content_formats).
+    pub async fn content_formats<S: Session>(
+        &mut self,
+        session: S,
+        product_id: &ProductId,
+    ) -> Result<Vec<FormatInfo>, Error<T::Error, S::Error>> {
+        let mut session = SessionAdapter::new(session);
+        Ok(self
+            .content_access(&mut session, product_id)
+            .await?
+            .formats())
+    }
+
+    #[instrument(skip(self, session))]
+    pub async fn book_info<S: Session>(
+        &mut self,
+        session: S,
+        product_id: &ProductId,
+    ) -> Result<BookInfo, Error<T::Error, S::Error>> {
+        let mut session = SessionAdapter::new(session);
+        let parts = mkreq(
+            ::http::Method::GET,
+            ::http::Uri::from_str(&product_url(
+                &self.settings(&mut session).await?.book,
+                product_id,
+            ))
+            .unwrap(),
+        );
+        self.request::<_, Json<book::RawBookInfo>, _, _>(
+            &mut session,
+            ::http::Request::from_parts(parts, || ()),
+        )
+        .await
+        .map(|info| info.into_inner().into())
+    }
+
+    /// Writes the cover of `product_id` into `output`, or returns `None` if the book has none.
+    #[instrument(skip(self, session, output))]
+    pub async fn cover<S: Session, W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        mut session: S,
+        product_id: &ProductId,
+        mut output: W,
+    ) -> Result<Option<W>, Error<T::Error, S::Error>> {
+        let Some(image_id) = self
+            .book_info(&mut session, product_id)
+            .await?
+            .cover_image_id
+        else {
+            return Ok(None);
+        };
+        let template = self
+            .settings(&mut SessionAdapter::new(&mut session))
+            .await?
+            .image_url_template
+            .clone();
+        let Some(uri) = cover_uri(template.as_deref(), &image_id) else {
+            return Ok(None);
+        };
+        let res = self
+            .raw_request(::http::Request::from_parts(
+                mkreq(::http::Method::GET, uri),
+                (),
+            ))
+            .await?;
+        match res.status() {
+            ::http::StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                output.write_all(res.body()).await?;
+                output.flush().await?;
+                Ok(Some(output))
+            }
+            status => Err(Error::StatusCode(status)),
+        }
+    }
+
+    #[instrument(skip(self, session, output))]
+    pub async fn download<S: Session, W: AsyncWrite + AsyncSeek + Unpin + Send>(
+        &mut self,
+        session: S,
+        url: &Url,
+        offset: Option<u64>,
+        output: W,
+    ) -> Result<W, Error<T::Error, S::Error>> {
+        self.download_with_name(session, url, offset, output)
+            .await
+            .map(|d| d.output)
+    }
+
+    /// Like [`download`](Self::download), also returning the file name the server suggests.
+    #[instrument(skip(self, session, output))]
+    pub async fn download_with_name<S: Session, W: AsyncWrite + AsyncSeek + Unpin + Send>(
+        &mut self,
+        session: S,
+        url: &Url,
+        offset: Option<u64>,
+        mut output: W,
+    ) -> Result<Downloaded<W>, Error<T::Error, S::Error>> {
+        let session = SessionAdapter::new(session);
+        let mut poll = None;
+        let (parts, body) = loop {
+            let mut req = download_request(&self.profile, &session, url, self.send_user_key)
+                .ok_or(Error::NotLoggedIn)?;
+            push_cookies(&self.cookies, url, &mut req);
+            custom_headers(&self.headers, &mut req);
+            self.apply_options(url, &mut req);
+            self.rate_limit().await;
+            let res = self.transport.download(req, offset, output).await?;
+            pull_cookies(&mut self.cookies, url, &res);
+            let (parts, body) = res.into_parts();
+            if parts.status != ::http::StatusCode::ACCEPTED {
+                break (parts, body);
+            }
+            let wait = poll
+                .get_or_insert_with(PreparingPoll::new)
+                .next()
+                .ok_or(Error::NotReady)?;
+            tokio::time::sleep(wait).await;
+            output = body;
+        };
+
+        if !parts.status.is_success() {
+            return Err(Error::StatusCode(parts.status));
+        }
+        Ok(Downloaded {
+            output: body,
+            file_name: download_file_name(&parts.headers, url),
+        })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl Default for AsyncKobo<::reqwest::Client> {
+    fn default() -> Self {
+        AsyncKobo::new(
+            ::reqwest::Client::builder()
+                .redirect(::reqwest::redirect::Policy::none())
+                .build()
+                .unwrap(),
+        )
+    }
+}
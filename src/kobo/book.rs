@@ -1,10 +1,16 @@
-use std::{collections::HashMap, marker::PhantomData};
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    ops::Deref,
+    str::FromStr,
+};
 
 use base64::Engine;
 use serde::{
     de::{DeserializeSeed, Unexpected, Visitor},
-    Deserialize,
+    Deserialize, Serialize,
 };
+use time::OffsetDateTime;
 use url::Url;
 
 use super::FromResponse;
@@ -117,12 +123,87 @@ impl<'de> Deserialize<'de> for False {
     }
 }
 
-#[derive(Debug)]
+/// The id of a book, a UUID like `a0e6e4c5-8f3b-4c2e-9d2b-7f1a3c5e9b10`.
+///
+/// Parsing trims the input, checks the format and normalizes it to lowercase with hyphens, ids
+/// coming from Kobo are taken as they are.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ProductId(Box<str>);
+
+#[derive(thiserror::Error, Debug)]
+#[error("invalid book id {0:?}, expected a UUID")]
+pub struct InvalidProductId(Box<str>);
+
+impl ProductId {
+    #[inline(always)]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for ProductId {
+    type Err = InvalidProductId;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match uuid::Uuid::try_parse(s.trim()) {
+            Ok(id) => Ok(Self(id.hyphenated().to_string().into_boxed_str())),
+            Err(_) => Err(InvalidProductId(s.into())),
+        }
+    }
+}
+
+impl Deref for ProductId {
+    type Target = str;
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<str> for ProductId {
+    #[inline(always)]
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl core::fmt::Display for ProductId {
+    #[inline(always)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Book {
     pub authors: Option<Box<str>>,
     pub title: Box<str>,
-    pub revision_id: Box<str>,
+    pub revision_id: ProductId,
     pub is_archived: bool,
+    /// When the book entered the library.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub date_added: Option<OffsetDateTime>,
+    /// When the entitlement last changed, e.g. when the book was archived.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub last_modified: Option<OffsetDateTime>,
+    /// Audiobooks come along with ebooks in the library, they can't be downloaded.
+    #[serde(default)]
+    pub is_audiobook: bool,
+    #[serde(default)]
+    pub series: Option<Box<str>>,
+    /// Position in [`Book::series`] as Kobo formats it, it is not always an integer.
+    #[serde(default)]
+    pub series_index: Option<Box<str>>,
+}
+
+impl Book {
+    /// The latest of [`date_added`](Self::date_added) and
+    /// [`last_modified`](Self::last_modified).
+    pub fn last_changed(&self) -> Option<OffsetDateTime> {
+        self.date_added.max(self.last_modified)
+    }
 }
 
 impl core::fmt::Display for Book {
@@ -131,10 +212,17 @@ impl core::fmt::Display for Book {
         if let Some(ref authors) = self.authors {
             write!(f, " by {authors}")?;
         }
+        if self.is_audiobook {
+            f.write_str(" [audiobook]")?;
+        }
+        if self.is_archived {
+            f.write_str(" [archived]")?;
+        }
         Ok(())
     }
 }
 
+/// Fails on finished books, so that [`NewEntitlementFull`] leaves them out.
 #[derive(Debug, Default)]
 pub struct StatusInfoStatus;
 
@@ -148,18 +236,20 @@ impl<'de> Deserialize<'de> for StatusInfoStatus {
     }
 }
 
+// A missing or null status is a book never opened, it must not be mistaken for a finished one.
 #[derive(Debug, Deserialize)]
 pub struct StatusInfo {
     #[serde(default, rename = "Status")]
-    _status: StatusInfoStatus,
+    _status: Option<StatusInfoStatus>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ReadingState {
-    #[serde(rename = "StatusInfo")]
-    _status_info: StatusInfo,
+    #[serde(default, rename = "StatusInfo")]
+    _status_info: Option<StatusInfo>,
 }
 
+/// Fails on previews, which can't be downloaded, so that both entitlements leave them out.
 #[derive(Debug, Default)]
 pub struct Accessibility;
 
@@ -177,12 +267,19 @@ impl<'de> Deserialize<'de> for Accessibility {
 #[serde(rename_all = "PascalCase")]
 pub struct BookEntitlement {
     #[serde(default)]
-    _accessibility: Accessibility,
+    _accessibility: Option<Accessibility>,
     #[serde(default)]
     _is_locked: False,
     pub is_removed: Option<bool>,
+    #[serde(default)]
+    created: NoneOnError<Rfc3339>,
+    #[serde(default)]
+    last_modified: NoneOnError<Rfc3339>,
 }
 
+#[derive(Debug, Deserialize)]
+struct Rfc3339(#[serde(with = "time::serde::rfc3339")] OffsetDateTime);
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct ContributorRole {
@@ -193,9 +290,11 @@ pub struct ContributorRole {
 #[derive(Debug, Default, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct BookMetadata {
-    pub revision_id: Box<str>,
+    pub revision_id: ProductId,
     pub title: Box<str>,
     pub contributor_roles: Option<Vec<ContributorRole>>,
+    #[serde(default)]
+    series: NoneOnError<RawSeries>,
 }
 
 fn authors(mut contributor_roles: Vec<ContributorRole>) -> Option<Box<str>> {
@@ -231,13 +330,20 @@ impl From<BookMetadata> for Book {
             revision_id,
             title,
             contributor_roles,
+            series,
         }: BookMetadata,
     ) -> Self {
+        let (series, series_index) = series.into_parts();
         Book {
             authors: contributor_roles.and_then(authors),
             title,
             revision_id,
             is_archived: false,
+            date_added: None,
+            last_modified: None,
+            is_audiobook: false,
+            series,
+            series_index,
         }
     }
 }
@@ -246,15 +352,24 @@ impl From<BookMetadata> for Book {
 #[serde(rename_all = "PascalCase")]
 pub struct NewEntitlement {
     pub book_entitlement: Option<BookEntitlement>,
+    /// Takes the place of `book_entitlement` for audiobooks.
+    #[serde(default)]
+    pub audiobook_entitlement: Option<BookEntitlement>,
+    #[serde(alias = "AudiobookMetadata")]
     pub book_metadata: BookMetadata,
 }
 
+/// Like [`NewEntitlement`], failing on finished books.
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct NewEntitlementFull {
     pub book_entitlement: Option<BookEntitlement>,
-    #[serde(rename = "ReadingState")]
-    _reading_state: ReadingState,
+    /// Takes the place of `book_entitlement` for audiobooks.
+    #[serde(default)]
+    pub audiobook_entitlement: Option<BookEntitlement>,
+    #[serde(default, rename = "ReadingState")]
+    _reading_state: Option<ReadingState>,
+    #[serde(alias = "AudiobookMetadata")]
     pub book_metadata: BookMetadata,
 }
 
@@ -263,25 +378,46 @@ pub trait Entitlement: for<'de> Deserialize<'de> {
 
     fn book_metadata(&mut self) -> &mut BookMetadata;
 
+    fn is_audiobook(&self) -> bool;
+
+    /// Whether a book in the `ReadingState` of a `ChangedReadingState` sync entry is listed.
+    fn keeps_reading_state(_state: &serde_json::Value) -> bool {
+        true
+    }
+
     fn to_book(mut self) -> Book {
-        let is_archived = self
-            .book_entitlement()
-            .and_then(|e| e.is_removed)
-            .unwrap_or(false);
+        let is_audiobook = self.is_audiobook();
+        let (is_archived, date_added, last_modified) = match self.book_entitlement() {
+            Some(e) => (
+                e.is_removed.unwrap_or(false),
+                e.created.0.take().map(|d| d.0),
+                e.last_modified.0.take().map(|d| d.0),
+            ),
+            None => (false, None, None),
+        };
         let mut res: Book = std::mem::take(self.book_metadata()).into();
         res.is_archived = is_archived;
+        res.date_added = date_added;
+        res.last_modified = last_modified;
+        res.is_audiobook = is_audiobook;
         res
     }
 }
 
 impl Entitlement for NewEntitlement {
     fn book_entitlement(&mut self) -> Option<&mut BookEntitlement> {
-        self.book_entitlement.as_mut()
+        self.book_entitlement
+            .as_mut()
+            .or(self.audiobook_entitlement.as_mut())
     }
 
     fn book_metadata(&mut self) -> &mut BookMetadata {
         &mut self.book_metadata
     }
+
+    fn is_audiobook(&self) -> bool {
+        self.audiobook_entitlement.is_some()
+    }
 }
 
 impl From<NewEntitlement> for Book {
@@ -292,12 +428,22 @@ impl From<NewEntitlement> for Book {
 
 impl Entitlement for NewEntitlementFull {
     fn book_entitlement(&mut self) -> Option<&mut BookEntitlement> {
-        self.book_entitlement.as_mut()
+        self.book_entitlement
+            .as_mut()
+            .or(self.audiobook_entitlement.as_mut())
     }
 
     fn book_metadata(&mut self) -> &mut BookMetadata {
         &mut self.book_metadata
     }
+
+    fn is_audiobook(&self) -> bool {
+        self.audiobook_entitlement.is_some()
+    }
+
+    fn keeps_reading_state(state: &serde_json::Value) -> bool {
+        ReadingState::deserialize(state).is_ok()
+    }
 }
 
 impl From<NewEntitlementFull> for Book {
@@ -309,6 +455,7 @@ impl From<NewEntitlementFull> for Book {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct KoboBook<E> {
+    #[serde(alias = "ChangedEntitlement")]
     pub new_entitlement: E,
 }
 
@@ -337,6 +484,12 @@ impl<T> From<Maybe<T>> for Option<T> {
 #[derive(Debug)]
 pub struct NoneOnError<T>(pub Option<T>);
 
+impl<T> Default for NoneOnError<T> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
 impl<'de, T: Deserialize<'de>> Deserialize<'de> for NoneOnError<T> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -348,8 +501,35 @@ impl<'de, T: Deserialize<'de>> Deserialize<'de> for NoneOnError<T> {
     }
 }
 
+/// The book a sync entry that didn't convert to one is about, when it is left out of the list
+/// now: entitlements `T` filters and reading states it rejects. Other entries, e.g. the reading
+/// progress of a listed book, name nothing.
+fn left_out<T: Entitlement>(entry: &serde_json::Value) -> Option<ProductId> {
+    let id = |value: Option<&serde_json::Value>| value.and_then(|v| ProductId::deserialize(v).ok());
+    if let Some(state) = entry.pointer("/ChangedReadingState/ReadingState") {
+        return if T::keeps_reading_state(state) {
+            None
+        } else {
+            id(state.get("EntitlementId"))
+        };
+    }
+    let entitlement = entry
+        .get("NewEntitlement")
+        .or_else(|| entry.get("ChangedEntitlement"))?;
+    [
+        "/BookMetadata/RevisionId",
+        "/AudiobookMetadata/RevisionId",
+        "/BookEntitlement/Id",
+        "/AudiobookEntitlement/Id",
+    ]
+    .into_iter()
+    .find_map(|path| id(entitlement.pointer(path)))
+}
+
+/// The books of a sync page and the ones it leaves out, see [`left_out`]. A book is in either
+/// list according to the last entry about it.
 #[derive(Debug)]
-pub struct Books<T>(pub Vec<Book>, PhantomData<T>);
+pub struct Books<T>(pub Vec<Book>, pub Vec<ProductId>, PhantomData<T>);
 struct BooksVisitor<T>(PhantomData<T>);
 
 impl<'de, T: Entitlement> Visitor<'de> for BooksVisitor<T> {
@@ -363,14 +543,21 @@ impl<'de, T: Entitlement> Visitor<'de> for BooksVisitor<T> {
     where
         A: serde::de::SeqAccess<'de>,
     {
-        let mut vec = Vec::new();
-        while let Some(book) = seq.next_element()? {
-            let NoneOnError::<KoboBook<T>>(Some(book)) = book else {
-                continue;
-            };
-            vec.push(book.into());
+        let mut books = Vec::<Book>::new();
+        let mut removed = Vec::new();
+        while let Some(entry) = seq.next_element::<serde_json::Value>()? {
+            if let Ok(book) = KoboBook::<T>::deserialize(&entry) {
+                let book = Book::from(book);
+                removed.retain(|id| *id != book.revision_id);
+                books.push(book);
+            } else if let Some(id) = left_out::<T>(&entry) {
+                books.retain(|book| book.revision_id != id);
+                if !removed.contains(&id) {
+                    removed.push(id);
+                }
+            }
         }
-        Ok(Books(vec, PhantomData))
+        Ok(Books(books, removed, PhantomData))
     }
 }
 
@@ -385,7 +572,13 @@ impl<'de, T: Entitlement> Deserialize<'de> for Books<T> {
 
 pub struct BooksPage<T: Entitlement> {
     pub books: Vec<Book>,
+    /// See [`Books`].
+    pub removed: Vec<ProductId>,
+    /// Why the books of the page could not be read, the sync can still go on.
+    pub error: Option<serde_json::Error>,
     pub next: Option<Box<str>>,
+    /// The token to resume from once the sync is over.
+    pub token: Option<Box<str>>,
     _entitlement: PhantomData<T>,
 }
 
@@ -401,79 +594,189 @@ impl<T: Entitlement> FromResponse for BooksPage<T> {
         if parts.status != ::http::StatusCode::OK {
             return Err(super::Error::StatusCode(parts.status));
         }
+        let token = parts
+            .headers
+            .get("x-kobo-synctoken")
+            .and_then(|h| {
+                if h.as_bytes().is_empty() {
+                    None
+                } else {
+                    std::str::from_utf8(h.as_bytes()).ok()
+                }
+            })
+            .map(|s| s.to_string().into_boxed_str());
         let next = if parts
             .headers
             .get("x-kobo-sync")
             .map(|h| h.as_bytes() == b"continue")
             .unwrap_or(false)
         {
-            parts
-                .headers
-                .get("x-kobo-synctoken")
-                .and_then(|h| {
-                    if h.as_bytes().is_empty() {
-                        None
-                    } else {
-                        std::str::from_utf8(h.as_bytes()).ok()
-                    }
-                })
-                .map(|s| s.to_string().into_boxed_str())
+            token.clone()
         } else {
             None
         };
 
+        let (books, removed, error) =
+            match <super::Json<Books<T>> as FromResponse>::from_response::<E, S, B>(parts, body) {
+                Ok(super::Json(Books(books, removed, _))) => (books, removed, None),
+                Err(super::Error::Json(err)) => (Vec::new(), Vec::new(), Some(err)),
+                Err(err) => return Err(err),
+            };
+
         Ok(BooksPage {
-            books: <super::Json<Books<T>> as FromResponse>::from_response(parts, body)?
-                .0
-                 .0,
+            books,
+            removed,
+            error,
             next,
+            token,
             _entitlement: PhantomData,
         })
     }
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// A book in the user's wishlist, usually not owned.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WishlistItem {
+    pub product_id: ProductId,
+    pub title: Box<str>,
+    pub authors: Option<Box<str>>,
+}
+
+impl core::fmt::Display for WishlistItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.title)?;
+        if let Some(ref authors) = self.authors {
+            write!(f, " by {authors}")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<BookMetadata> for WishlistItem {
+    fn from(
+        BookMetadata {
+            revision_id,
+            title,
+            contributor_roles,
+            ..
+        }: BookMetadata,
+    ) -> Self {
+        WishlistItem {
+            product_id: revision_id,
+            title,
+            authors: contributor_roles.and_then(authors),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct WishlistProduct {
+    book: BookMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RawWishlistItem {
+    product_metadata: WishlistProduct,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WishlistPage {
+    #[serde(deserialize_with = "wishlist_items")]
+    pub items: Vec<WishlistItem>,
+    #[serde(default)]
+    pub total_page_count: u32,
+}
+
+fn wishlist_items<'de, D>(deserializer: D) -> Result<Vec<WishlistItem>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(
+        Vec::<NoneOnError<RawWishlistItem>>::deserialize(deserializer)?
+            .into_iter()
+            .filter_map(|item| item.0)
+            .map(|item| item.product_metadata.book.into())
+            .collect(),
+    )
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[allow(clippy::upper_case_acronyms)]
-enum DRMType {
+pub enum DRMType {
     KDRM,
     SignedNoDrm,
 }
 
+impl core::fmt::Display for DRMType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::KDRM => "KDRM",
+            Self::SignedNoDrm => "SignedNoDrm",
+        })
+    }
+}
+
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-enum UrlFormat {
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum UrlFormat {
     EPUB3,
     EPUB3FL,
     KEPUB,
 }
 
+impl core::fmt::Display for UrlFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::EPUB3 => "EPUB3",
+            Self::EPUB3FL => "EPUB3FL",
+            Self::KEPUB => "KEPUB",
+        })
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct RawContentUrl {
     #[serde(rename = "DRMType")]
     drm_type: DRMType,
     #[serde(rename = "UrlFormat")]
-    _url_format: UrlFormat,
+    url_format: UrlFormat,
     #[serde(with = "super::url")]
     download_url: Url,
     byte_size: u64,
 }
 
 #[derive(Debug)]
-struct ContentUrl {
+pub struct ContentUrl {
+    pub format: UrlFormat,
+    pub drm_type: DRMType,
     pub has_drm: bool,
     pub url: Url,
     pub size: u64,
 }
 
-impl<'de> Deserialize<'de> for ContentUrl {
+/// A download offered for a book, see [`Kobo::content_formats`](super::Kobo::content_formats).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatInfo {
+    pub format: UrlFormat,
+    pub drm_type: DRMType,
+    pub byte_size: u64,
+}
+
+#[derive(Debug)]
+struct ContentUrls(Vec<ContentUrl>);
+
+impl<'de> Deserialize<'de> for ContentUrls {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        struct ContentUrlVisitor;
-        impl<'de> Visitor<'de> for ContentUrlVisitor {
-            type Value = ContentUrl;
+        struct ContentUrlsVisitor;
+        impl<'de> Visitor<'de> for ContentUrlsVisitor {
+            type Value = ContentUrls;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
                 formatter.write_str("a download url")
@@ -483,6 +786,7 @@ impl<'de> Deserialize<'de> for ContentUrl {
             where
                 A: serde::de::SeqAccess<'de>,
             {
+                let mut res = Vec::new();
                 while let Some(c) = seq.next_element::<NoneOnError<RawContentUrl>>()? {
                     let Some(mut c) = c.0 else {
                         continue;
@@ -509,20 +813,26 @@ impl<'de> Deserialize<'de> for ContentUrl {
                             c.download_url.set_query(qs.as_deref());
                         }
                     }
-                    return Ok(ContentUrl {
+                    res.push(ContentUrl {
+                        format: c.url_format,
+                        drm_type: c.drm_type,
                         has_drm: c.drm_type == DRMType::KDRM,
                         url: c.download_url,
                         size: c.byte_size,
                     });
                 }
-                Err(serde::de::Error::invalid_value(
-                    Unexpected::Seq,
-                    &"a download url",
-                ))
+                if res.is_empty() {
+                    Err(serde::de::Error::invalid_value(
+                        Unexpected::Seq,
+                        &"a download url",
+                    ))
+                } else {
+                    Ok(ContentUrls(res))
+                }
             }
         }
 
-        deserializer.deserialize_seq(ContentUrlVisitor)
+        deserializer.deserialize_seq(ContentUrlsVisitor)
     }
 }
 
@@ -799,22 +1109,171 @@ impl<'de> DeserializeSeed<'de> for ContentKeysDeserializer<'_> {
                 }
                 Ok(ContentKeys2(res))
             }
+
+            fn visit_none<E>(self) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(ContentKeys2::default())
+            }
+
+            fn visit_unit<E>(self) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(ContentKeys2::default())
+            }
+
+            fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                deserializer.deserialize_seq(self)
+            }
         }
-        deserializer.deserialize_seq(ContentKeysVisitor(self.0))
+        // Kobo sometimes sends null instead of an empty list.
+        deserializer.deserialize_option(ContentKeysVisitor(self.0))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct AccessBook {
+    #[serde(with = "super::url")]
     pub url: Url,
     pub size: u64,
+    /// Serialized as base64 strings.
+    #[serde(with = "content_keys")]
     pub content_keys: Option<HashMap<Box<str>, ::aes::cipher::Key<aes::Aes128Dec>>>,
 }
 
+mod content_keys {
+    use std::collections::HashMap;
+
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    type Key = ::aes::cipher::Key<aes::Aes128Dec>;
+
+    pub fn serialize<S>(
+        keys: &Option<HashMap<Box<str>, Key>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        keys.as_ref()
+            .map(|keys| {
+                keys.iter()
+                    .map(|(name, key)| (name, BASE64_STANDARD.encode(key)))
+                    .collect::<HashMap<_, _>>()
+            })
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<HashMap<Box<str>, Key>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<HashMap<Box<str>, Box<str>>>::deserialize(deserializer)?
+            .map(|keys| {
+                keys.into_iter()
+                    .map(|(name, key)| {
+                        BASE64_STANDARD
+                            .decode(&*key)
+                            .ok()
+                            .and_then(|key| TryInto::<[u8; 16]>::try_into(key).ok())
+                            .map(|key| (name, key.into()))
+                            .ok_or_else(|| {
+                                serde::de::Error::invalid_value(
+                                    serde::de::Unexpected::Str(&key),
+                                    &"base64 AES128 key",
+                                )
+                            })
+                    })
+                    .collect()
+            })
+            .transpose()
+    }
+}
+
+/// Every download offered for a book, along with the keys needed by the DRM protected ones.
+#[derive(Debug)]
+pub struct ContentAccess {
+    pub urls: Vec<ContentUrl>,
+    pub content_keys: Option<HashMap<Box<str>, ::aes::cipher::Key<aes::Aes128Dec>>>,
+}
+
+impl ContentAccess {
+    /// Every download offered, in the order Kobo lists them.
+    pub fn formats(&self) -> Vec<FormatInfo> {
+        self.urls
+            .iter()
+            .map(|c| FormatInfo {
+                format: c.format,
+                drm_type: c.drm_type,
+                byte_size: c.size,
+            })
+            .collect()
+    }
+
+    /// Every download offered, the first one of each format.
+    pub fn into_all(self) -> Vec<(UrlFormat, AccessBook)> {
+        if self.urls.iter().any(|c| c.has_drm)
+            && self.content_keys.as_ref().is_none_or(HashMap::is_empty)
+        {
+            tracing::warn!(
+                "no content keys for a DRM protected book, it may remain partially encrypted"
+            );
+        }
+        let mut seen = HashSet::new();
+        let content_keys = self.content_keys;
+        self.urls
+            .into_iter()
+            .filter(|c| seen.insert(c.format))
+            .map(|c| {
+                (
+                    c.format,
+                    AccessBook {
+                        url: c.url,
+                        size: c.size,
+                        content_keys: if c.has_drm {
+                            content_keys.clone()
+                        } else {
+                            None
+                        },
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Picks the download in the given format, or the first one offered if `format` is `None`.
+    pub fn select(mut self, format: Option<UrlFormat>) -> Option<AccessBook> {
+        let i = if let Some(format) = format {
+            self.urls.iter().position(|c| c.format == format)?
+        } else {
+            0
+        };
+        let ContentUrl {
+            has_drm, url, size, ..
+        } = self.urls.swap_remove(i);
+        if has_drm && self.content_keys.as_ref().is_none_or(HashMap::is_empty) {
+            tracing::warn!(
+                "no content keys for a DRM protected book, it may remain partially encrypted"
+            );
+        }
+        Some(AccessBook {
+            url,
+            size,
+            content_keys: if has_drm { self.content_keys } else { None },
+        })
+    }
+}
+
 pub struct AccessBookDeserializer<'a>(pub &'a ::aes::cipher::Key<aes::Aes128Dec>);
 
 impl<'de> DeserializeSeed<'de> for AccessBookDeserializer<'_> {
-    type Value = AccessBook;
+    type Value = ContentAccess;
 
     fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
     where
@@ -878,7 +1337,7 @@ impl<'de> DeserializeSeed<'de> for AccessBookDeserializer<'_> {
 
         struct VisitorImpl<'a>(&'a ::aes::cipher::Key<aes::Aes128Dec>);
         impl<'de> Visitor<'de> for VisitorImpl<'_> {
-            type Value = AccessBook;
+            type Value = ContentAccess;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
                 formatter.write_str("struct AccessBook")
@@ -888,33 +1347,23 @@ impl<'de> DeserializeSeed<'de> for AccessBookDeserializer<'_> {
             where
                 A: serde::de::SeqAccess<'de>,
             {
-                let Some(ContentUrl { has_drm, url, size }) = seq.next_element::<ContentUrl>()?
-                else {
+                let Some(ContentUrls(urls)) = seq.next_element::<ContentUrls>()? else {
                     return Err(serde::de::Error::invalid_length(
                         0,
                         &"struct AccessBook with 2 elements",
                     ));
                 };
-                let content_keys = if has_drm {
-                    let Some(content_keys) = seq
-                        .next_element_seed(ContentKeysDeserializer(self.0))?
-                        .map(|v| v.0)
-                    else {
-                        return Err(serde::de::Error::invalid_length(
-                            1,
-                            &"struct AccessBook with 2 elements",
-                        ));
-                    };
-                    Some(content_keys)
+                let content_keys = if urls.iter().any(|c| c.has_drm) {
+                    Some(
+                        seq.next_element_seed(ContentKeysDeserializer(self.0))?
+                            .unwrap_or_default()
+                            .0,
+                    )
                 } else {
                     None
                 };
 
-                Ok(AccessBook {
-                    url,
-                    size,
-                    content_keys,
-                })
+                Ok(ContentAccess { urls, content_keys })
             }
 
             fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
@@ -945,23 +1394,16 @@ impl<'de> DeserializeSeed<'de> for AccessBookDeserializer<'_> {
                     }
                 }
 
-                let Some(ContentUrl { has_drm, url, size }) = content_url else {
+                let Some(ContentUrls(urls)) = content_url else {
                     return Err(serde::de::Error::missing_field("ContentUrls"));
                 };
-                let content_keys = if has_drm {
-                    let Some(content_keys) = content_keys else {
-                        return Err(serde::de::Error::missing_field("ContentKeys"));
-                    };
-                    Some(content_keys)
+                let content_keys = if urls.iter().any(|c| c.has_drm) {
+                    Some(content_keys.unwrap_or_default())
                 } else {
                     None
                 };
 
-                Ok(AccessBook {
-                    url,
-                    size,
-                    content_keys,
-                })
+                Ok(ContentAccess { urls, content_keys })
             }
         }
 
@@ -972,16 +1414,77 @@ impl<'de> DeserializeSeed<'de> for AccessBookDeserializer<'_> {
 
 #[derive(Debug, Default, Deserialize)]
 #[serde(rename_all = "PascalCase")]
-struct RawBookInfo {
+pub(super) struct RawSeries {
+    #[serde(default)]
+    pub name: NoneOnError<Box<str>>,
+    #[serde(default)]
+    pub number: NoneOnError<Box<str>>,
+}
+
+impl NoneOnError<RawSeries> {
+    /// The name of the series and the position in it, without a name the position is
+    /// meaningless.
+    fn into_parts(self) -> (Option<Box<str>>, Option<Box<str>>) {
+        match self.0 {
+            Some(RawSeries { name, number }) => match non_empty(name.0) {
+                Some(name) => (Some(name), non_empty(number.0)),
+                None => (None, None),
+            },
+            None => (None, None),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(super) struct RawPublisher {
+    #[serde(default)]
+    pub name: NoneOnError<Box<str>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(super) struct RawBookInfo {
     pub title: Box<str>,
     pub contributor_roles: Option<Vec<ContributorRole>>,
+    pub cover_image_id: Option<Box<str>>,
+    // Only nice to have, so unexpected shapes are ignored rather than failing the request.
+    #[serde(default)]
+    pub series: NoneOnError<RawSeries>,
+    #[serde(default)]
+    pub publisher: NoneOnError<RawPublisher>,
+    #[serde(default)]
+    pub publication_date: NoneOnError<Box<str>>,
+    #[serde(default)]
+    pub language: NoneOnError<Box<str>>,
+    #[serde(default, alias = "ISBN")]
+    pub isbn: NoneOnError<Box<str>>,
 }
 
-#[derive(Debug, Default, Deserialize)]
-#[serde(from = "RawBookInfo")]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct BookInfo {
     pub author: Option<Box<str>>,
     pub title: Box<str>,
+    #[serde(default)]
+    pub cover_image_id: Option<Box<str>>,
+    #[serde(default)]
+    pub series: Option<Box<str>>,
+    /// Position in [`BookInfo::series`] as Kobo formats it, it is not always an integer.
+    #[serde(default)]
+    pub series_index: Option<Box<str>>,
+    #[serde(default)]
+    pub publisher: Option<Box<str>>,
+    /// ISO 8601 timestamp.
+    #[serde(default)]
+    pub publication_date: Option<Box<str>>,
+    #[serde(default)]
+    pub language: Option<Box<str>>,
+    #[serde(default)]
+    pub isbn: Option<Box<str>>,
+}
+
+fn non_empty(s: Option<Box<str>>) -> Option<Box<str>> {
+    s.filter(|s| !s.trim().is_empty())
 }
 
 impl From<RawBookInfo> for BookInfo {
@@ -989,11 +1492,130 @@ impl From<RawBookInfo> for BookInfo {
         RawBookInfo {
             title,
             contributor_roles,
+            cover_image_id,
+            series,
+            publisher,
+            publication_date,
+            language,
+            isbn,
         }: RawBookInfo,
     ) -> Self {
+        let (series, series_index) = series.into_parts();
         BookInfo {
             author: contributor_roles.and_then(authors),
             title,
+            cover_image_id: non_empty(cover_image_id),
+            series,
+            series_index,
+            publisher: publisher.0.and_then(|p| non_empty(p.name.0)),
+            publication_date: non_empty(publication_date.0),
+            language: non_empty(language.0),
+            isbn: non_empty(isbn.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "a0e6e4c5-8f3b-4c2e-9d2b-7f1a3c5e9b10";
+
+    fn entitlement(kind: &str, id: &str, status: &str) -> serde_json::Value {
+        serde_json::json!({
+            kind: {
+                "BookEntitlement": { "Id": id, "IsRemoved": false },
+                "BookMetadata": { "RevisionId": id, "Title": "Title" },
+                "ReadingState": { "StatusInfo": { "Status": status } },
+            }
+        })
+    }
+
+    fn reading_state(id: &str, status: &str) -> serde_json::Value {
+        serde_json::json!({
+            "ChangedReadingState": {
+                "ReadingState": { "EntitlementId": id, "StatusInfo": { "Status": status } }
+            }
+        })
+    }
+
+    fn books<T: Entitlement>(entries: &[serde_json::Value]) -> (Vec<Book>, Vec<ProductId>) {
+        let Books(books, removed, _) =
+            serde_json::from_value::<Books<T>>(serde_json::Value::from(entries.to_vec())).unwrap();
+        (books, removed)
+    }
+
+    fn ids(books: &[Book]) -> Vec<&str> {
+        books.iter().map(|book| book.revision_id.as_str()).collect()
+    }
+
+    fn preview(kind: &str, id: &str, status: &str) -> serde_json::Value {
+        let mut entry = entitlement(kind, id, status);
+        entry[kind]["BookEntitlement"]["Accessibility"] = "Preview".into();
+        entry
+    }
+
+    #[test]
+    fn finished_listed_only_with_all() {
+        let entry = entitlement("NewEntitlement", ID, "Finished");
+
+        let book: Book = serde_json::from_value::<KoboBook<NewEntitlement>>(entry.clone())
+            .unwrap()
+            .into();
+        assert_eq!(book.revision_id.as_str(), ID);
+        assert!(serde_json::from_value::<KoboBook<NewEntitlementFull>>(entry.clone()).is_err());
+
+        let (listed, removed) = books::<NewEntitlement>(std::slice::from_ref(&entry));
+        assert_eq!(ids(&listed), [ID]);
+        assert!(removed.is_empty());
+
+        let (listed, removed) = books::<NewEntitlementFull>(&[entry]);
+        assert!(listed.is_empty());
+        assert_eq!(removed, [ProductId(ID.into())]);
+    }
+
+    #[test]
+    fn previews_never_listed() {
+        for status in ["Reading", "Finished"] {
+            let entry = preview("NewEntitlement", ID, status);
+            assert!(serde_json::from_value::<KoboBook<NewEntitlement>>(entry.clone()).is_err());
+            assert!(serde_json::from_value::<KoboBook<NewEntitlementFull>>(entry.clone()).is_err());
+
+            let (listed, removed) = books::<NewEntitlement>(std::slice::from_ref(&entry));
+            assert!(listed.is_empty());
+            assert_eq!(removed, [ProductId(ID.into())]);
+
+            let (listed, removed) = books::<NewEntitlementFull>(&[entry]);
+            assert!(listed.is_empty());
+            assert_eq!(removed, [ProductId(ID.into())]);
         }
     }
+
+    #[test]
+    fn finished_changes_are_removals() {
+        let other = "b0e6e4c5-8f3b-4c2e-9d2b-7f1a3c5e9b10";
+        let entries = [
+            entitlement("ChangedEntitlement", ID, "Reading"),
+            reading_state(ID, "Finished"),
+            reading_state(other, "Reading"),
+        ];
+
+        let (listed, removed) = books::<NewEntitlementFull>(&entries);
+        assert!(listed.is_empty());
+        assert_eq!(removed, [ProductId(ID.into())]);
+
+        let (listed, removed) = books::<NewEntitlement>(&entries);
+        assert_eq!(ids(&listed), [ID]);
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn last_entry_wins() {
+        let (listed, removed) = books::<NewEntitlementFull>(&[
+            entitlement("NewEntitlement", ID, "Finished"),
+            entitlement("ChangedEntitlement", ID, "Reading"),
+        ]);
+        assert_eq!(ids(&listed), [ID]);
+        assert!(removed.is_empty());
+    }
 }
@@ -0,0 +1,174 @@
+use std::{
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use ::url::Url;
+
+use super::{
+    DeviceProfile, Kobo, ProgressThrottle, RateLimiter, Transport, TransportOptions, MAX_REDIRECTS,
+    RATE_LIMIT_ATTEMPTS,
+};
+
+/// Collects the configuration of a [`Kobo`] and creates it in one go.
+///
+/// Everything left unset keeps the defaults of [`Kobo::new`].
+#[derive(Debug, Clone)]
+pub struct KoboBuilder {
+    options: TransportOptions,
+    profile: Option<DeviceProfile>,
+    base_url: Option<Url>,
+    rate_limit_attempts: usize,
+    sync_page_delay: Duration,
+    progress_throttle: ProgressThrottle,
+    cancel: Option<Arc<AtomicBool>>,
+    send_user_key: bool,
+    headers: ::http::HeaderMap,
+    rate_limiter: Option<RateLimiter>,
+    max_redirects: usize,
+}
+
+impl Default for KoboBuilder {
+    fn default() -> Self {
+        Self {
+            options: TransportOptions::default(),
+            profile: None,
+            base_url: None,
+            rate_limit_attempts: RATE_LIMIT_ATTEMPTS,
+            sync_page_delay: Duration::ZERO,
+            progress_throttle: ProgressThrottle::default(),
+            cancel: None,
+            send_user_key: false,
+            headers: ::http::HeaderMap::new(),
+            rate_limiter: None,
+            max_redirects: MAX_REDIRECTS,
+        }
+    }
+}
+
+impl KoboBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See [`TransportOptions::timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// See [`Kobo::set_proxy`].
+    pub fn proxy<P: Into<Box<str>>>(mut self, proxy: P) -> Self {
+        self.options.proxy = Some(proxy.into());
+        self
+    }
+
+    /// See [`Kobo::set_ca_file`].
+    pub fn ca_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.options.ca_file = Some(path.into());
+        self
+    }
+
+    /// See [`Kobo::set_insecure`].
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        self.options.insecure = insecure;
+        self
+    }
+
+    /// See [`Kobo::set_download_buffer_size`].
+    pub fn download_buffer_size(mut self, size: NonZeroUsize) -> Self {
+        self.options.buffer_size = Some(size);
+        self
+    }
+
+    /// See [`Kobo::with_device_profile`].
+    pub fn device_profile(mut self, profile: DeviceProfile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// See [`Kobo::with_base_url`].
+    pub fn base_url(mut self, base_url: Url) -> Self {
+        self.base_url = Some(base_url);
+        self
+    }
+
+    /// How many times a rate limited request is sent before giving up, 1 disables retrying.
+    pub fn rate_limit_attempts(mut self, attempts: usize) -> Self {
+        self.rate_limit_attempts = attempts.max(1);
+        self
+    }
+
+    /// See [`Kobo::set_sync_page_delay`].
+    pub fn sync_page_delay(mut self, delay: Duration) -> Self {
+        self.sync_page_delay = delay;
+        self
+    }
+
+    /// See [`Kobo::set_max_redirects`].
+    pub fn max_redirects(mut self, max: usize) -> Self {
+        self.max_redirects = max;
+        self
+    }
+
+    /// See [`Kobo::set_progress_throttle`].
+    pub fn progress_throttle(mut self, throttle: ProgressThrottle) -> Self {
+        self.progress_throttle = throttle;
+        self
+    }
+
+    /// See [`Kobo::set_cancel_flag`].
+    pub fn cancel_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    /// See [`Kobo::set_send_user_key`].
+    pub fn send_user_key(mut self, send: bool) -> Self {
+        self.send_user_key = send;
+        self
+    }
+
+    /// See [`Kobo::set_rate_limiter`].
+    pub fn rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// See [`Kobo::set_header`].
+    pub fn header<K: ::http::header::IntoHeaderName>(
+        mut self,
+        name: K,
+        value: ::http::HeaderValue,
+    ) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn build<T: Transport>(self, transport: T) -> Kobo<T> {
+        let mut kobo = Kobo::new(transport);
+        if let Some(profile) = self.profile {
+            kobo = kobo.with_device_profile(profile);
+        }
+        if let Some(base_url) = self.base_url {
+            kobo = kobo.with_base_url(base_url);
+        }
+        kobo.options = self.options;
+        kobo.rate_limit_attempts = self.rate_limit_attempts;
+        kobo.sync_page_delay = self.sync_page_delay;
+        kobo.progress_throttle = self.progress_throttle;
+        kobo.cancel = self.cancel;
+        kobo.send_user_key = self.send_user_key;
+        kobo.headers = self.headers;
+        kobo.rate_limiter = self.rate_limiter;
+        kobo.max_redirects = self.max_redirects;
+        kobo
+    }
+
+    /// Like [`KoboBuilder::build`] with the transport of [`Kobo::default`].
+    pub fn build_default(self) -> Kobo<super::DefaultAgent> {
+        self.build(super::default_agent())
+    }
+}
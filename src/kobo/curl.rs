@@ -1,35 +1,140 @@
 use std::{
     borrow::Cow,
+    cell::RefCell,
     io::{Cursor, Read, Seek, Write},
 };
 
-use curl::easy::{Easy2, Handler};
+use curl::easy::Easy;
 
+/// A [`Transport`](super::Transport) backed by libcurl.
+///
+/// The handle is kept between requests and reset before each one, so its connection cache lets
+/// them reuse the same connection instead of doing a new TLS handshake every time. Clones start
+/// with a handle of their own.
 #[derive(Debug, Default)]
-pub struct CurlAgent;
+pub struct CurlAgent {
+    proxy: Option<Box<str>>,
+    handle: Option<Easy>,
+}
+
+impl Clone for CurlAgent {
+    fn clone(&self) -> Self {
+        Self {
+            proxy: self.proxy.clone(),
+            handle: None,
+        }
+    }
+}
+
+impl CurlAgent {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-struct Collector<'a, W: Write> {
-    inbody: Cursor<Cow<'a, [u8]>>,
+    /// Sends every request through `proxy`, overriding the one in the
+    /// [`TransportOptions`](super::TransportOptions).
+    ///
+    /// The scheme selects the protocol: `socks4://`, `socks4a://`, `socks5://` and `socks5h://`,
+    /// the latter letting the proxy resolve host names, or `http://` and `https://`.
+    pub fn with_proxy<P: Into<Box<str>>>(proxy: P) -> Self {
+        Self {
+            proxy: Some(proxy.into()),
+            handle: None,
+        }
+    }
+
+    pub fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    /// The handle with the options of the previous request cleared, live connections survive.
+    fn handle(&mut self) -> (&mut Easy, Option<&str>) {
+        let handle = self.handle.get_or_insert_with(Easy::new);
+        handle.reset();
+        (handle, self.proxy.as_deref())
+    }
+}
+
+enum InBody<'a> {
+    Data(Cursor<Cow<'a, [u8]>>),
+    Reader(Box<dyn Read + Send + 'a>),
+}
+
+struct Collector<'a, W: Write + Seek> {
+    inbody: InBody<'a>,
     first: bool,
+    status: Option<u16>,
     headers: ::http::HeaderMap,
+    mode: Mode,
+    error: Option<Abort>,
     body: W,
 }
 
-impl<'a, W: Write> Collector<'a, W> {
-    pub fn new(inbody: Cow<'a, [u8]>, body: W) -> Self {
+enum Mode {
+    Request,
+    Download(Option<u64>),
+    Discard,
+    Writing,
+}
+
+enum Abort {
+    Io(std::io::Error),
+    ContentRange,
+}
+
+impl<'a, W: Write + Seek> Collector<'a, W> {
+    pub fn new(inbody: InBody<'a>, body: W) -> Self {
         Self {
-            inbody: Cursor::new(inbody),
+            inbody,
             headers: ::http::HeaderMap::new(),
             first: true,
+            status: None,
+            mode: Mode::Request,
+            error: None,
             body,
         }
     }
+
+    fn begin(&mut self) -> Result<(), Abort> {
+        let offset = match core::mem::replace(&mut self.mode, Mode::Writing) {
+            Mode::Download(offset) => offset,
+            mode => {
+                self.mode = mode;
+                return Ok(());
+            }
+        };
+        match (self.status, offset) {
+            // A 202 only tells the book is being prepared, its body isn't part of the book.
+            (Some(202), _) => self.mode = Mode::Discard,
+            (Some(200..300), None) => (),
+            (Some(206), Some(offset)) => {
+                if super::content_range_start(&self.headers) != Some(offset) {
+                    return Err(Abort::ContentRange);
+                }
+            }
+            (Some(200), Some(_)) => {
+                self.body
+                    .seek(std::io::SeekFrom::Start(0))
+                    .map_err(Abort::Io)?;
+            }
+            _ => self.mode = Mode::Discard,
+        }
+        Ok(())
+    }
+}
+
+fn status_code(line: &[u8]) -> Option<u16> {
+    std::str::from_utf8(line.split(|&c| c == b' ').nth(1)?)
+        .ok()?
+        .parse()
+        .ok()
 }
 
-impl<W: Write> Handler for Collector<'_, W> {
+impl<W: Write + Seek> Collector<'_, W> {
     fn header(&mut self, data: &[u8]) -> bool {
         if self.first {
             self.first = false;
+            self.status = status_code(data);
             self.headers.clear();
             return true;
         }
@@ -67,20 +172,36 @@ impl<W: Write> Handler for Collector<'_, W> {
     }
 
     fn write(&mut self, data: &[u8]) -> Result<usize, curl::easy::WriteError> {
-        self.body.write_all(data).unwrap();
+        if let Err(err) = self.begin() {
+            self.error = Some(err);
+            return Ok(0);
+        }
+        if matches!(self.mode, Mode::Discard) {
+            return Ok(data.len());
+        }
+        if let Err(err) = self.body.write_all(data) {
+            self.error = Some(Abort::Io(err));
+            return Ok(0);
+        }
         Ok(data.len())
     }
 
     fn read(&mut self, data: &mut [u8]) -> Result<usize, curl::easy::ReadError> {
-        self.inbody
-            .read(data)
-            .map_err(|_| curl::easy::ReadError::Abort)
+        match self.inbody {
+            InBody::Data(ref mut data_in) => data_in.read(data),
+            InBody::Reader(ref mut reader) => reader.read(data),
+        }
+        .map_err(|_| curl::easy::ReadError::Abort)
     }
 
     fn seek(&mut self, whence: std::io::SeekFrom) -> curl::easy::SeekResult {
-        match self.inbody.seek(whence) {
-            Ok(_) => curl::easy::SeekResult::Ok,
-            Err(_) => curl::easy::SeekResult::Fail,
+        match self.inbody {
+            InBody::Data(ref mut data) => match data.seek(whence) {
+                Ok(_) => curl::easy::SeekResult::Ok,
+                Err(_) => curl::easy::SeekResult::Fail,
+            },
+            // Lets libcurl work around it, e.g. by starting over on a new connection.
+            InBody::Reader(_) => curl::easy::SeekResult::CantSeek,
         }
     }
 }
@@ -113,24 +234,46 @@ fn header_name(name: &str, buf: &mut Vec<u8>) {
     }
 }
 
-fn from_request<W: Write>(
-    req: ::http::Request<super::Body>,
+/// Sets the proxy and selects its protocol from the scheme.
+fn set_proxy(handle: &mut Easy, proxy: &str) -> Result<(), ::curl::Error> {
+    use ::curl::easy::ProxyType;
+
+    handle.proxy(proxy)?;
+    let Some((scheme, _)) = proxy.split_once("://") else {
+        return Ok(());
+    };
+    let kind = match scheme.to_ascii_lowercase().as_str() {
+        "socks4" => ProxyType::Socks4,
+        "socks4a" => ProxyType::Socks4a,
+        "socks" | "socks5" => ProxyType::Socks5,
+        "socks5h" => ProxyType::Socks5Hostname,
+        _ => return Ok(()),
+    };
+    handle.proxy_type(kind)
+}
+
+fn from_request<'a, W: Write + Seek>(
+    handle: &mut Easy,
+    req: ::http::Request<super::Body<'a>>,
     outbody: W,
-) -> Result<Easy2<Collector<W>>, ::curl::Error> {
+    proxy: Option<&str>,
+    download: bool,
+) -> Result<Collector<'a, W>, ::curl::Error> {
     let (mut parts, body) = req.into_parts();
 
-    let len = match &body {
-        crate::Body::None => None,
-        crate::Body::Data(cow) => Some(cow.len()),
+    let (upload, len) = match &body {
+        crate::Body::None => (false, None),
+        body => (true, body.len()),
     };
 
-    let mut handle = Easy2::new(Collector::new(
+    let collector = Collector::new(
         match body {
-            crate::Body::None => Cow::Borrowed(b"".as_slice()),
-            crate::Body::Data(cow) => cow,
+            crate::Body::None => InBody::Data(Cursor::new(Cow::Borrowed(b"".as_slice()))),
+            crate::Body::Data(cow) => InBody::Data(Cursor::new(cow)),
+            crate::Body::Reader(reader, _) => InBody::Reader(reader),
         },
         outbody,
-    ));
+    );
 
     match parts.version {
         ::http::Version::HTTP_09 => {
@@ -144,9 +287,12 @@ fn from_request<W: Write>(
         _ => unreachable!(),
     }?;
 
-    if let Some(len) = len {
+    if upload {
         handle.upload(true)?;
-        handle.in_filesize(len as u64)?;
+        // Without a size the body is sent chunked.
+        if let Some(len) = len {
+            handle.in_filesize(len)?;
+        }
     }
 
     handle.custom_request(parts.method.as_str())?;
@@ -170,19 +316,98 @@ fn from_request<W: Write>(
     handle.http_headers(headers)?;
     handle.follow_location(false)?;
 
-    Ok(handle)
-}
+    let options = parts
+        .extensions
+        .remove::<super::TransportOptions>()
+        .unwrap_or_default();
+    // An empty string disables the proxy, libcurl would look at the environment otherwise.
+    set_proxy(handle, proxy.or(options.proxy.as_deref()).unwrap_or(""))?;
+    if let Some(ref ca_file) = options.ca_file {
+        handle.cainfo(ca_file)?;
+    }
+    if options.insecure {
+        handle.ssl_verify_peer(false)?;
+        handle.ssl_verify_host(false)?;
+    }
+    if let Some(size) = options.buffer_size.filter(|_| download) {
+        handle.buffer_size(size.get())?;
+    }
+    if let Some(timeout) = options.timeout {
+        handle.connect_timeout(timeout)?;
+        if download {
+            handle.low_speed_limit(1)?;
+            handle.low_speed_time(timeout)?;
+        } else {
+            handle.timeout(timeout)?;
+        }
+    }
 
-struct WriteHolder<T>(Option<T>);
+    Ok(collector)
+}
 
-impl<T: Write> Write for WriteHolder<T> {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.0.as_mut().unwrap().write(buf)
+fn transport_error<S: Send + Sync + 'static>(err: ::curl::Error) -> super::Error<::curl::Error, S> {
+    if err.is_operation_timedout() {
+        super::Error::Timeout
+    } else if err.is_couldnt_resolve_host()
+        || err.is_couldnt_resolve_proxy()
+        || err.is_couldnt_connect()
+        || err.is_send_error()
+        || err.is_recv_error()
+        || err.is_got_nothing()
+    {
+        super::Error::Connection(err)
+    } else if err.is_ssl_connect_error()
+        || err.is_peer_failed_verification()
+        || err.is_ssl_certproblem()
+        || err.is_ssl_cipher()
+        || err.is_ssl_cacert()
+        || err.is_ssl_cacert_badfile()
+        || err.is_ssl_crl_badfile()
+        || err.is_ssl_issuer_error()
+        || err.is_use_ssl_failed()
+    {
+        super::Error::Tls(err)
+    } else {
+        super::Error::Transport(err)
     }
+}
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.0.as_mut().unwrap().flush()
+fn perform<'a, W: Write + Seek, S: Send + Sync + 'static>(
+    handle: &mut Easy,
+    collector: Collector<'a, W>,
+) -> Result<(::http::response::Parts, Collector<'a, W>), super::Error<::curl::Error, S>> {
+    let collector = RefCell::new(collector);
+    let res = {
+        let mut transfer = handle.transfer();
+        transfer
+            .header_function(|data| collector.borrow_mut().header(data))
+            .map_err(super::Error::Transport)?;
+        transfer
+            .write_function(|data| collector.borrow_mut().write(data))
+            .map_err(super::Error::Transport)?;
+        transfer
+            .read_function(|data| collector.borrow_mut().read(data))
+            .map_err(super::Error::Transport)?;
+        transfer
+            .seek_function(|whence| collector.borrow_mut().seek(whence))
+            .map_err(super::Error::Transport)?;
+        transfer.perform()
+    };
+    let mut collector = collector.into_inner();
+    match collector.error.take() {
+        Some(Abort::Io(err)) => return Err(super::Error::Io(err)),
+        Some(Abort::ContentRange) => return Err(super::Error::InvalidContentRange),
+        None => res.map_err(transport_error)?,
     }
+
+    let mut parts = ::http::Response::new(()).into_parts().0;
+    parts.headers = core::mem::take(&mut collector.headers);
+    parts.status = ::http::StatusCode::from_u16(
+        handle.response_code().map_err(super::Error::Transport)? as u16,
+    )
+    .unwrap();
+
+    Ok((parts, collector))
 }
 
 impl super::Transport for CurlAgent {
@@ -193,41 +418,32 @@ impl super::Transport for CurlAgent {
         &mut self,
         req: ::http::Request<super::Body<'_>>,
     ) -> Result<http::Response<Self::Out>, super::Error<Self::Error, S>> {
-        let mut handle = from_request(req, Vec::<u8>::new()).map_err(super::Error::Transport)?;
+        let (handle, proxy) = self.handle();
+        let collector = from_request(handle, req, Cursor::new(Vec::<u8>::new()), proxy, false)
+            .map_err(super::Error::Transport)?;
 
-        handle.perform().map_err(super::Error::Transport)?;
+        let (parts, collector) = perform(handle, collector)?;
 
-        let mut parts = ::http::Response::new(()).into_parts().0;
-        parts.headers = core::mem::take(&mut handle.get_mut().headers);
-        parts.status = ::http::StatusCode::from_u16(
-            handle.response_code().map_err(super::Error::Transport)? as u16,
-        )
-        .unwrap();
+        let mut body = collector.body;
+        body.set_position(0);
 
-        Ok(::http::Response::from_parts(
-            parts,
-            Cursor::new(core::mem::take(&mut handle.get_mut().body)),
-        ))
+        Ok(::http::Response::from_parts(parts, body))
     }
 
-    fn download<S: Send + Sync + 'static, W: Write>(
+    fn download<S: Send + Sync + 'static, W: Write + Seek>(
         &mut self,
-        req: http::Request<super::Body<'_>>,
+        mut req: http::Request<super::Body<'_>>,
+        offset: Option<u64>,
         output: W,
     ) -> Result<http::Response<W>, super::Error<Self::Error, S>> {
-        let mut handle =
-            from_request(req, WriteHolder(Some(output))).map_err(super::Error::Transport)?;
-
-        handle.perform().map_err(super::Error::Transport)?;
+        let offset = super::set_range(&mut req, offset);
+        let (handle, proxy) = self.handle();
+        let mut collector =
+            from_request(handle, req, output, proxy, true).map_err(super::Error::Transport)?;
+        collector.mode = Mode::Download(offset);
 
-        let mut parts = ::http::Response::new(()).into_parts().0;
-        parts.headers = core::mem::take(&mut handle.get_mut().headers);
-        parts.status = ::http::StatusCode::from_u16(
-            handle.response_code().map_err(super::Error::Transport)? as u16,
-        )
-        .unwrap();
-        let body = handle.get_mut().body.0.take().unwrap();
+        let (parts, collector) = perform(handle, collector)?;
 
-        Ok(::http::Response::from_parts(parts, body))
+        Ok(::http::Response::from_parts(parts, collector.body))
     }
 }
@@ -0,0 +1,107 @@
+use tracing::warn;
+
+const AFFILIATE: &str = "Kobo";
+const APPLICATION_VERSION: &str = "10.1.2.39807";
+const DEFAULT_PLATFORM_ID: &str = "00000000-0000-0000-0000-000000004000";
+const CARRIER_NAME: &str = "310270";
+const DEVICE_MODEL: &str = "Pixel";
+const DEVICE_OS_VERSION: &str = "33";
+const DISPLAY_PROFILE: &str = "Android";
+const LOCALE: &str = "ww/en";
+
+/// The app and device Kobo is told it is talking to.
+///
+/// Kobo rejects app versions it considers too old, so every field can be changed without
+/// recompiling, either directly or through [`DeviceProfile::from_env`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceProfile {
+    pub affiliate: Box<str>,
+    pub app_version: Box<str>,
+    pub platform_id: Box<str>,
+    pub carrier_name: Box<str>,
+    pub device_model: Box<str>,
+    pub device_os_version: Box<str>,
+    pub display_profile: Box<str>,
+    /// Storefront of the account as `<region>/<language>`, e.g. `ww/en` or `it/it`.
+    pub locale: Box<str>,
+    /// Derived from the other fields when `None`.
+    pub user_agent: Option<Box<str>>,
+}
+
+impl Default for DeviceProfile {
+    fn default() -> Self {
+        Self {
+            affiliate: AFFILIATE.into(),
+            app_version: APPLICATION_VERSION.into(),
+            platform_id: DEFAULT_PLATFORM_ID.into(),
+            carrier_name: CARRIER_NAME.into(),
+            device_model: DEVICE_MODEL.into(),
+            device_os_version: DEVICE_OS_VERSION.into(),
+            display_profile: DISPLAY_PROFILE.into(),
+            locale: LOCALE.into(),
+            user_agent: None,
+        }
+    }
+}
+
+fn env_override(field: &mut Box<str>, name: &str) {
+    let Some(value) = std::env::var_os(name) else {
+        return;
+    };
+    match value.into_string() {
+        Ok(value) if ::http::HeaderValue::from_str(&value).is_ok() && !value.is_empty() => {
+            *field = value.into_boxed_str();
+        }
+        _ => warn!("ignoring invalid {name}"),
+    }
+}
+
+/// Whether `locale` looks like `<region>/<language>`, it ends up in urls as is.
+pub fn is_locale(locale: &str) -> bool {
+    locale.split_once('/').is_some_and(|(region, language)| {
+        [region, language].iter().all(|part| {
+            !part.is_empty() && part.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-')
+        })
+    })
+}
+
+impl DeviceProfile {
+    /// The default profile with the fields set in the environment replaced.
+    ///
+    /// The recognized variables are `KOBO_AFFILIATE`, `KOBO_APP_VERSION`, `KOBO_PLATFORM_ID`,
+    /// `KOBO_CARRIER_NAME`, `KOBO_DEVICE_MODEL`, `KOBO_DEVICE_OS_VERSION`, `KOBO_DISPLAY_PROFILE`,
+    /// `KOBO_LOCALE` and `KOBO_USER_AGENT`.
+    pub fn from_env() -> Self {
+        let mut profile = Self::default();
+        env_override(&mut profile.affiliate, "KOBO_AFFILIATE");
+        env_override(&mut profile.app_version, "KOBO_APP_VERSION");
+        env_override(&mut profile.platform_id, "KOBO_PLATFORM_ID");
+        env_override(&mut profile.carrier_name, "KOBO_CARRIER_NAME");
+        env_override(&mut profile.device_model, "KOBO_DEVICE_MODEL");
+        env_override(&mut profile.device_os_version, "KOBO_DEVICE_OS_VERSION");
+        env_override(&mut profile.display_profile, "KOBO_DISPLAY_PROFILE");
+        env_override(&mut profile.locale, "KOBO_LOCALE");
+        if !is_locale(&profile.locale) {
+            warn!("ignoring invalid KOBO_LOCALE");
+            profile.locale = LOCALE.into();
+        }
+        let mut user_agent = Box::default();
+        env_override(&mut user_agent, "KOBO_USER_AGENT");
+        if !user_agent.is_empty() {
+            profile.user_agent = Some(user_agent);
+        }
+        profile
+    }
+
+    /// The user agent of the Kobo Android app, otherwise the login request hangs forever.
+    pub fn user_agent(&self) -> std::borrow::Cow<'_, str> {
+        match self.user_agent {
+            Some(ref user_agent) => user_agent.as_ref().into(),
+            None => format!(
+                "Mozilla/5.0 (Linux; Android 13; {} Build/TQ2B.230505.005.A1; wv) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/101.0.4951.61 Safari/537.36 KoboApp/{} KoboPlatform Id/{} KoboAffiliate/{} KoboBuildFlavor/global",
+                self.device_model, self.app_version, self.platform_id, self.affiliate,
+            )
+            .into(),
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use cfg_if::cfg_if;
 
+mod simple;
+
 #[allow(unused_macros)]
 macro_rules! imp {
     ($file:literal) => {
@@ -7,6 +9,9 @@ macro_rules! imp {
         mod imp;
 
         pub fn extract_href(mut code: String) -> Option<String> {
+            if let Some(href) = simple::extract_href(&code) {
+                return Some(href);
+            }
             code.push_str("location.href");
             imp::extract_href(&code)
         }
@@ -21,6 +26,8 @@ cfg_if! {
     } else if #[cfg(any(feature = "quickjs", feature = "quickjs-ng"))] {
         imp!("quickjs.rs");
     } else {
-        compile_error!("No js engine selected.");
+        pub fn extract_href(code: String) -> Option<String> {
+            simple::extract_href(&code)
+        }
     }
 }
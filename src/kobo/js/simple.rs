@@ -0,0 +1,178 @@
+//! Finds redirects in scripts without running them.
+//!
+//! Only string literals assigned to `location`/`location.href` or passed to
+//! `location.replace`/`location.assign` are understood, anything else is left to a real engine.
+
+fn is_ident(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, b'_' | b'$')
+}
+
+fn skip_ws(code: &[u8], mut i: usize) -> usize {
+    while code.get(i).is_some_and(|c| c.is_ascii_whitespace()) {
+        i += 1;
+    }
+    i
+}
+
+fn hex(code: &[u8], i: usize, len: usize) -> Option<u32> {
+    let digits = std::str::from_utf8(code.get(i..i + len)?).ok()?;
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// Parses the string literal starting at `i`, returns its value and the index after it.
+fn string(code: &[u8], i: usize) -> Option<(String, usize)> {
+    let quote = *code.get(i).filter(|c| matches!(c, b'"' | b'\''))?;
+    let mut res = Vec::new();
+    let mut units = Vec::new();
+    let mut i = i + 1;
+    loop {
+        let c = *code.get(i)?;
+        i += 1;
+        if c != b'\\' {
+            if !units.is_empty() {
+                res.extend(String::from_utf16(&units).ok()?.bytes());
+                units.clear();
+            }
+            match c {
+                b'\n' => return None,
+                c if c == quote => break,
+                c => res.push(c),
+            }
+            continue;
+        }
+        let e = *code.get(i)?;
+        i += 1;
+        if e == b'u' {
+            units.push(u16::try_from(hex(code, i, 4)?).ok()?);
+            i += 4;
+            continue;
+        }
+        if !units.is_empty() {
+            res.extend(String::from_utf16(&units).ok()?.bytes());
+            units.clear();
+        }
+        match e {
+            b'x' => {
+                let c = char::from_u32(hex(code, i, 2)?)?;
+                res.extend(c.encode_utf8(&mut [0; 4]).bytes());
+                i += 2;
+            }
+            b'n' => res.push(b'\n'),
+            b'r' => res.push(b'\r'),
+            b't' => res.push(b'\t'),
+            b'0' => res.push(0),
+            b'\n' => (),
+            e => res.push(e),
+        }
+    }
+    Some((String::from_utf8(res).ok()?, i))
+}
+
+/// Parses what follows a `location` identifier ending right before `i`.
+fn redirect(code: &[u8], i: usize) -> Option<String> {
+    let mut i = skip_ws(code, i);
+    if code.get(i) == Some(&b'.') {
+        i = skip_ws(code, i + 1);
+        let start = i;
+        while code.get(i).copied().is_some_and(is_ident) {
+            i += 1;
+        }
+        match &code[start..i] {
+            b"href" => i = skip_ws(code, i),
+            b"replace" | b"assign" => {
+                i = skip_ws(code, i);
+                if code.get(i) != Some(&b'(') {
+                    return None;
+                }
+                let (href, end) = string(code, skip_ws(code, i + 1))?;
+                return (code.get(skip_ws(code, end)) == Some(&b')')).then_some(href);
+            }
+            _ => return None,
+        }
+    }
+    if code.get(i) != Some(&b'=') || code.get(i + 1) == Some(&b'=') {
+        return None;
+    }
+    string(code, skip_ws(code, i + 1)).map(|(href, _)| href)
+}
+
+/// Returns the last redirect found in `code`, like evaluating `location.href` after it would.
+pub fn extract_href(code: &str) -> Option<String> {
+    const LOCATION: &[u8] = b"location";
+
+    let code = code.as_bytes();
+    memchr::memmem::find_iter(code, LOCATION)
+        .filter(|&i| i == 0 || !is_ident(code[i - 1]))
+        .filter_map(|i| redirect(code, i + LOCATION.len()))
+        .last()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_href;
+
+    const AUTHENTICATED: &str = "kobo://UserAuthenticated?userId=0123&userKey=abcd&email=a%40b.c&returnUrl=https%3a%2f%2fwww.kobo.com";
+
+    #[test]
+    fn login_redirect() {
+        let script = format!(
+            "var location={{}};\ntry{{\n\n    window.location.href = '{AUTHENTICATED}';\n\n}}catch(____e){{}}\n"
+        );
+        assert_eq!(extract_href(&script).as_deref(), Some(AUTHENTICATED));
+    }
+
+    #[test]
+    fn replace_and_assign() {
+        assert_eq!(
+            extract_href("location.replace( \"https://a.kobo.com/x\" );").as_deref(),
+            Some("https://a.kobo.com/x")
+        );
+        assert_eq!(
+            extract_href("document.location.assign('https://b.kobo.com/')").as_deref(),
+            Some("https://b.kobo.com/")
+        );
+        assert_eq!(
+            extract_href("location = 'https://c.kobo.com/'").as_deref(),
+            Some("https://c.kobo.com/")
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            extract_href(
+                r#"location.href = "kobo:\/\/UserAuthenticated?userId=1&userKey=2\x26a=é";"#
+            )
+            .as_deref(),
+            Some("kobo://UserAuthenticated?userId=1&userKey=2&a=é")
+        );
+        // A surrogate pair written as two escapes.
+        assert_eq!(
+            extract_href(r"location.href = '\ud83d\udcda'").as_deref(),
+            Some("📚")
+        );
+    }
+
+    #[test]
+    fn last_redirect_wins() {
+        let script =
+            "if (a) { location.href = 'https://first/'; }\nlocation.href = 'https://second/';";
+        assert_eq!(extract_href(script).as_deref(), Some("https://second/"));
+    }
+
+    #[test]
+    fn ignored() {
+        for script in [
+            "",
+            "var location={};",
+            "if (location.href == 'https://a/') {}",
+            "mylocation.href = 'https://a/'",
+            "location.reload()",
+            "location.href = base + '/path'",
+            "location.href = 'unterminated\n'",
+            "location.replace(url)",
+        ] {
+            assert_eq!(extract_href(script), None, "{script:?}");
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    num::NonZeroU32,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// Caps the requests per second of every [`Kobo`](super::Kobo) it is given to.
+///
+/// Kobo throttles accounts across connections, clones share the same budget so that concurrent
+/// clients stay under the limit together instead of each retrying on its own. Bursts up to the
+/// rate are let through right away.
+#[derive(Debug, Clone)]
+pub struct RateLimiter(Arc<Mutex<Bucket>>);
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    /// Negative when requests are already waiting for a token.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: NonZeroU32) -> Self {
+        let rate = f64::from(per_second.get());
+        Self(Arc::new(Mutex::new(Bucket {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        })))
+    }
+
+    /// Takes a token, returning how long to wait before it can be used.
+    pub(crate) fn reserve(&self) -> Duration {
+        let mut bucket = self.0.lock();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.rate) - 1.0;
+        bucket.refilled = now;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+        }
+    }
+
+    /// Blocks until a request can be sent.
+    pub fn acquire(&self) {
+        let wait = self.reserve();
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use std::{borrow::Cow, io::Read};
 
 use encoding_rs::Encoding;
-use encoding_rs_io::DecodeReaderBytesBuilder;
+use encoding_rs_io::{DecodeReaderBytes, DecodeReaderBytesBuilder};
 use serde::{
     de::{DeserializeOwned, DeserializeSeed},
     Serialize,
@@ -135,18 +135,7 @@ fn charset_from_content_type<
         .parameters()
     {
         let (key, value) = v.map_err(|_| Error::InvalidContentType)?;
-        if matches!(
-            key.as_bytes(),
-            [
-                b'c' | b'C',
-                b'h' | b'H',
-                b'a' | b'A',
-                b'r' | b'R',
-                b's' | b'S',
-                b'e' | b'E',
-                b't' | b'T'
-            ]
-        ) {
+        if key.eq_ignore_ascii_case("charset") {
             if res.is_some() {
                 err = true;
             } else {
@@ -161,6 +150,14 @@ fn charset_from_content_type<
     }
 }
 
+/// Reads an UTF-8 body without the BOM Kobo sometimes prepends, invalid bytes are left alone.
+fn utf8_body<B: Read>(body: B) -> DecodeReaderBytes<B, Vec<u8>> {
+    DecodeReaderBytesBuilder::new()
+        .utf8_passthru(true)
+        .strip_bom(true)
+        .build(body)
+}
+
 fn is_utf8<T: AsRef<[u8]>>(s: T) -> bool {
     matches!(
         s.as_ref(),
@@ -261,7 +258,7 @@ impl FromResponse for String {
         B: Read,
     >(
         parts: http::response::Parts,
-        mut body: B,
+        body: B,
     ) -> Result<Self, Error<E, S>> {
         if parts.status != ::http::StatusCode::OK {
             return Err(Error::StatusCode(parts.status));
@@ -290,7 +287,8 @@ impl FromResponse for String {
         let mut buf = content_length(&parts)
             .map(String::with_capacity)
             .unwrap_or_default();
-        body.read_to_string(&mut buf)
+        utf8_body(body)
+            .read_to_string(&mut buf)
             .map_err(|_| Error::InvalidEncoding(b"UTF-8".to_vec().into_boxed_slice()))?;
         Ok(buf)
     }
@@ -401,7 +399,9 @@ impl<T: DeserializeOwned> FromResponse for Json<T> {
                 .map_err(Error::Json);
             }
         }
-        serde_json::from_reader(body).map(Json).map_err(Error::Json)
+        serde_json::from_reader(utf8_body(body))
+            .map(Json)
+            .map_err(Error::Json)
     }
 }
 
@@ -439,8 +439,101 @@ impl<'a, T: DeserializeSeed<'a>> FromResponseSeed<'a> for Json<T> {
                 return body.end().map(|_| res).map_err(Error::Json);
             }
         }
-        let mut body = serde_json::Deserializer::from_reader(body);
+        let mut body = serde_json::Deserializer::from_reader(utf8_body(body));
         let res = self.0.deserialize(&mut body).map_err(Error::Json)?;
         body.end().map(|_| res).map_err(Error::Json)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(content_type: Option<&str>) -> ::http::response::Parts {
+        let mut res = ::http::Response::builder();
+        if let Some(ct) = content_type {
+            res = res.header("Content-Type", ct);
+        }
+        res.body(()).unwrap().into_parts().0
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Value {
+        title: String,
+    }
+
+    const BOM_JSON: &[u8] = b"\xef\xbb\xbf{\"Title\":\"Caf\xc3\xa9\"}";
+
+    #[test]
+    fn json_with_bom() {
+        for ct in [
+            None,
+            Some("application/json"),
+            Some("application/json; charset=utf-8"),
+        ] {
+            let Json(value) = <Json<Value> as FromResponse>::from_response::<
+                std::io::Error,
+                std::convert::Infallible,
+                _,
+            >(parts(ct), BOM_JSON)
+            .unwrap();
+            assert_eq!(value.title, "Café", "{ct:?}");
+        }
+    }
+
+    #[test]
+    fn json_seed_with_bom() {
+        let value = Json(std::marker::PhantomData::<Value>)
+            .from_response::<std::io::Error, std::convert::Infallible, _>(parts(None), BOM_JSON)
+            .unwrap();
+        assert_eq!(value.title, "Café");
+    }
+
+    #[test]
+    fn string_with_bom() {
+        let s =
+            <String as FromResponse>::from_response::<std::io::Error, std::convert::Infallible, _>(
+                parts(Some("text/html")),
+                b"\xef\xbb\xbf<html></html>".as_slice(),
+            )
+            .unwrap();
+        assert_eq!(s, "<html></html>");
+    }
+
+    #[test]
+    fn charset_extraction() {
+        let charset = |header: &str| {
+            charset_from_content_type::<std::io::Error, std::convert::Infallible>(header.as_bytes())
+                .map(|charset| charset.map(|c| String::from_utf8(c.into_owned()).unwrap()))
+        };
+        assert_eq!(charset("application/json").unwrap(), None);
+        assert_eq!(
+            charset("text/html; Charset=ISO-8859-1").unwrap().as_deref(),
+            Some("ISO-8859-1")
+        );
+        assert_eq!(
+            charset("text/html;; charset=\"utf-8\";")
+                .unwrap()
+                .as_deref(),
+            Some("utf-8")
+        );
+        assert!(charset("text/html; charset=utf-8; charset=latin1").is_err());
+        assert!(charset("text/html; charset").is_err());
+        assert!(is_utf8("UTF-8") && is_utf8("utf8") && !is_utf8("utf-16"));
+    }
+
+    #[test]
+    fn declared_charset() {
+        let Json(value) = <Json<Value> as FromResponse>::from_response::<
+            std::io::Error,
+            std::convert::Infallible,
+            _,
+        >(
+            parts(Some("application/json; charset=iso-8859-1")),
+            b"{\"Title\":\"Caf\xe9\"}".as_slice(),
+        )
+        .unwrap();
+        assert_eq!(value.title, "Café");
+    }
+}
@@ -0,0 +1,120 @@
+use std::{future::Future, io::Read, time::Duration};
+
+use tokio::io::{AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+fn transport_error<S: Send + Sync + 'static>(
+    err: ::reqwest::Error,
+) -> super::Error<::reqwest::Error, S> {
+    if err.is_timeout() {
+        super::Error::Timeout
+    } else if err.is_connect() {
+        super::Error::Connection(err)
+    } else {
+        super::Error::Transport(err)
+    }
+}
+
+async fn with_timeout<T, S: Send + Sync + 'static>(
+    timeout: Option<Duration>,
+    fut: impl Future<Output = Result<T, ::reqwest::Error>>,
+) -> Result<T, super::Error<::reqwest::Error, S>> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| super::Error::Timeout)?,
+        None => fut.await,
+    }
+    .map_err(transport_error)
+}
+
+fn build(
+    client: &::reqwest::Client,
+    req: http::Request<super::Body<'_>>,
+) -> std::io::Result<(::reqwest::RequestBuilder, Option<Duration>)> {
+    let (mut parts, body) = req.into_parts();
+    let timeout = parts
+        .extensions
+        .remove::<super::TransportOptions>()
+        .unwrap_or_default()
+        .timeout;
+    let builder = client
+        .request(parts.method, parts.uri.to_string())
+        .headers(parts.headers);
+    let builder = match body {
+        super::Body::None => builder,
+        super::Body::Data(cow) => builder.body(cow.into_owned()),
+        // reqwest only streams async bodies, readers are read whole beforehand.
+        super::Body::Reader(mut reader, len) => {
+            let mut buf = Vec::with_capacity(len.unwrap_or(0) as usize);
+            reader.read_to_end(&mut buf)?;
+            builder.body(buf)
+        }
+    };
+    Ok((builder, timeout))
+}
+
+fn response_parts(res: &::reqwest::Response) -> http::response::Parts {
+    let mut parts = http::Response::new(()).into_parts().0;
+    parts.status = res.status();
+    parts.version = res.version();
+    parts.headers = res.headers().clone();
+    parts
+}
+
+/// Proxies and TLS are configured on the [`Client`](::reqwest::Client), which by default picks the
+/// proxies up from the environment. Only [`TransportOptions::timeout`](super::TransportOptions::timeout)
+/// is honored.
+impl super::AsyncTransport for ::reqwest::Client {
+    type Error = ::reqwest::Error;
+
+    async fn request<S: Send + Sync + 'static>(
+        &mut self,
+        req: http::Request<super::Body<'_>>,
+    ) -> Result<http::Response<::bytes::Bytes>, super::Error<Self::Error, S>> {
+        let (builder, timeout) = build(self, req)?;
+        let builder = match timeout {
+            Some(timeout) => builder.timeout(timeout),
+            None => builder,
+        };
+        let res = builder.send().await.map_err(transport_error)?;
+        let parts = response_parts(&res);
+        let body = res.bytes().await.map_err(transport_error)?;
+        Ok(http::Response::from_parts(parts, body))
+    }
+
+    async fn download<S: Send + Sync + 'static, W: AsyncWrite + AsyncSeek + Unpin + Send>(
+        &mut self,
+        mut req: http::Request<super::Body<'_>>,
+        offset: Option<u64>,
+        mut output: W,
+    ) -> Result<http::Response<W>, super::Error<Self::Error, S>> {
+        let offset = super::set_range(&mut req, offset);
+        // The timeout bounds every wait on the server rather than the whole transfer.
+        let (builder, timeout) = build(self, req)?;
+        let mut res = with_timeout(timeout, builder.send()).await?;
+        let parts = response_parts(&res);
+        // A 202 only tells the book is being prepared, its body isn't part of the book.
+        let write = match (parts.status.as_u16(), offset) {
+            (202, _) => false,
+            (200..300, None) => true,
+            (206, Some(offset)) => {
+                if super::content_range_start(&parts.headers) != Some(offset) {
+                    return Err(super::Error::InvalidContentRange);
+                }
+                true
+            }
+            (200, Some(_)) => {
+                output.seek(std::io::SeekFrom::Start(0)).await?;
+                true
+            }
+            _ => false,
+        };
+        if write {
+            while let Some(chunk) = with_timeout(timeout, res.chunk()).await? {
+                output.write_all(&chunk).await?;
+            }
+            output.flush().await?;
+        }
+        Ok(::http::Response::from_parts(parts, output))
+    }
+}
@@ -0,0 +1,70 @@
+use std::io::{Cursor, Seek, Write};
+
+use super::Body;
+
+/// A [`Transport`](super::Transport) that hands every request to a closure and sends back the
+/// response it returns.
+///
+/// Meant for tests: the closure can assert on what [`Kobo`](super::Kobo) sends and answer with
+/// canned responses, no network involved.
+pub struct FnTransport<F>(F);
+
+impl<F> FnTransport<F>
+where
+    F: for<'a> Fn(::http::Request<Body<'a>>) -> ::http::Response<Cursor<Vec<u8>>>,
+{
+    pub fn new(f: F) -> Self {
+        Self(f)
+    }
+}
+
+impl<F> std::fmt::Debug for FnTransport<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("FnTransport").finish_non_exhaustive()
+    }
+}
+
+impl<F> super::Transport for FnTransport<F>
+where
+    F: for<'a> Fn(::http::Request<Body<'a>>) -> ::http::Response<Cursor<Vec<u8>>>,
+{
+    type Error = std::convert::Infallible;
+    type Out = Cursor<Vec<u8>>;
+
+    fn request<S: Send + Sync + 'static>(
+        &mut self,
+        req: ::http::Request<Body<'_>>,
+    ) -> Result<::http::Response<Self::Out>, super::Error<Self::Error, S>> {
+        Ok((self.0)(req))
+    }
+
+    fn download<S: Send + Sync + 'static, W: Write + Seek>(
+        &mut self,
+        mut req: ::http::Request<Body<'_>>,
+        offset: Option<u64>,
+        mut output: W,
+    ) -> Result<::http::Response<W>, super::Error<Self::Error, S>> {
+        let offset = super::set_range(&mut req, offset);
+        let (parts, body) = (self.0)(req).into_parts();
+        // A 202 only tells the book is being prepared, its body isn't part of the book.
+        let write = match (parts.status.as_u16(), offset) {
+            (202, _) => false,
+            (200..300, None) => true,
+            (206, Some(offset)) => {
+                if super::content_range_start(&parts.headers) != Some(offset) {
+                    return Err(super::Error::InvalidContentRange);
+                }
+                true
+            }
+            (200, Some(_)) => {
+                output.seek(std::io::SeekFrom::Start(0))?;
+                true
+            }
+            _ => false,
+        };
+        if write {
+            output.write_all(body.get_ref())?;
+        }
+        Ok(::http::Response::from_parts(parts, output))
+    }
+}
@@ -1,31 +1,349 @@
+fn transport_error<S: Send + Sync + 'static>(err: ::ureq::Error) -> super::Error<::ureq::Error, S> {
+    use std::io::ErrorKind;
+
+    match err {
+        ::ureq::Error::Timeout(_) => super::Error::Timeout,
+        ::ureq::Error::Io(ref io) if io.kind() == ErrorKind::TimedOut => super::Error::Timeout,
+        ::ureq::Error::HostNotFound
+        | ::ureq::Error::ConnectionFailed
+        | ::ureq::Error::ConnectProxyFailed(_) => super::Error::Connection(err),
+        ::ureq::Error::Io(ref io)
+            if matches!(
+                io.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::NotConnected
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+            ) =>
+        {
+            super::Error::Connection(err)
+        }
+        ::ureq::Error::TlsRequired => super::Error::Tls(err),
+        #[cfg(any(feature = "rustls", feature = "native-tls"))]
+        ::ureq::Error::Tls(_) => super::Error::Tls(err),
+        #[cfg(feature = "rustls")]
+        ::ureq::Error::Rustls(_) => super::Error::Tls(err),
+        #[cfg(feature = "native-tls")]
+        ::ureq::Error::NativeTls(_) => super::Error::Tls(err),
+        err => super::Error::Transport(err),
+    }
+}
+
+/// Response body, ureq undoes the `gzip` encoding by itself but not `deflate`.
+pub enum UreqBody<R> {
+    Plain(R),
+    Deflate(flate2::read::ZlibDecoder<R>),
+}
+
+impl<R: std::io::Read> UreqBody<R> {
+    fn new(headers: &::http::HeaderMap, body: R) -> Self {
+        let deflate = headers
+            .get(::http::header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case("deflate"));
+        if deflate {
+            Self::Deflate(flate2::read::ZlibDecoder::new(body))
+        } else {
+            Self::Plain(body)
+        }
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for UreqBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(r) => r.read(buf),
+            Self::Deflate(r) => r.read(buf),
+        }
+    }
+}
+
+/// The TLS configuration of the agent with the overrides of `options` applied, if any.
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+fn tls_config(
+    agent: &::ureq::Agent,
+    options: &super::TransportOptions,
+) -> Result<Option<::ureq::tls::TlsConfig>, ::ureq::Error> {
+    use ::ureq::tls::{PemItem, RootCerts, TlsConfig};
+
+    if options.ca_file.is_none() && !options.insecure {
+        return Ok(None);
+    }
+    let base = agent.config().tls_config();
+    let mut builder = TlsConfig::builder()
+        .provider(base.provider())
+        .client_cert(base.client_cert().cloned())
+        .use_sni(base.use_sni())
+        .root_certs(base.root_certs().clone())
+        .disable_verification(options.insecure || base.disable_verification());
+    if let Some(ref path) = options.ca_file {
+        let pem = std::fs::read(path)?;
+        let certs = ::ureq::tls::parse_pem(&pem)
+            .filter_map(|item| match item {
+                Ok(PemItem::Certificate(cert)) => Some(Ok(cert)),
+                Ok(_) => None,
+                Err(err) => Some(Err(err)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        builder = builder.root_certs(RootCerts::Specific(std::sync::Arc::new(certs)));
+    }
+    Ok(Some(builder.build()))
+}
+
+fn configure<B: ::ureq::AsSendBody>(
+    agent: &::ureq::Agent,
+    req: http::Request<B>,
+    options: super::TransportOptions,
+    download: bool,
+) -> Result<http::Request<B>, ::ureq::Error> {
+    let proxy = options
+        .proxy
+        .as_deref()
+        .map(::ureq::Proxy::new)
+        .transpose()?;
+    let config = agent
+        .configure_request(req)
+        .proxy(proxy)
+        .timeout_connect(options.timeout);
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    let config = match tls_config(agent, &options)? {
+        Some(tls) => config.tls_config(tls),
+        None => config,
+    };
+    Ok(if download {
+        config.timeout_recv_response(options.timeout)
+    } else {
+        config.timeout_global(options.timeout)
+    }
+    .build())
+}
+
+fn run<S: Send + Sync + 'static>(
+    agent: &::ureq::Agent,
+    req: http::Request<super::Body<'_>>,
+    download: bool,
+) -> Result<http::Response<UreqBody<::ureq::BodyReader<'static>>>, super::Error<::ureq::Error, S>> {
+    let (mut parts, body) = req.into_parts();
+    parts.extensions.insert(ureq_proto::CapitalizeHeaders);
+    let options = parts
+        .extensions
+        .remove::<super::TransportOptions>()
+        .unwrap_or_default();
+    // ureq is built without SOCKS support.
+    if let Some(proxy) = options.proxy.as_deref().filter(|proxy| {
+        proxy
+            .split_once("://")
+            .is_some_and(|(scheme, _)| scheme.to_ascii_lowercase().starts_with("socks"))
+    }) {
+        return Err(super::Error::UnsupportedProxy(proxy.into()));
+    }
+    match body {
+        super::Body::None => configure(
+            agent,
+            ::http::Request::from_parts(parts, ()),
+            options,
+            download,
+        )
+        .and_then(|req| agent.run(req)),
+        super::Body::Data(cow) => configure(
+            agent,
+            ::http::Request::from_parts(parts, cow.as_ref()),
+            options,
+            download,
+        )
+        .and_then(|req| agent.run(req)),
+        super::Body::Reader(mut reader, len) => {
+            // Sent chunked otherwise.
+            if let Some(len) = len {
+                parts
+                    .headers
+                    .entry(::http::header::CONTENT_LENGTH)
+                    .or_insert_with(|| len.into());
+            }
+            configure(
+                agent,
+                ::http::Request::from_parts(parts, ::ureq::SendBody::from_reader(&mut reader)),
+                options,
+                download,
+            )
+            .and_then(|req| agent.run(req))
+        }
+    }
+    .map(|res| {
+        let (parts, body) = res.into_parts();
+        let body = UreqBody::new(&parts.headers, body.into_reader());
+        ::http::Response::from_parts(parts, body)
+    })
+    .map_err(transport_error)
+}
+
 impl super::Transport for ::ureq::Agent {
     type Error = ::ureq::Error;
-    type Out = ::ureq::BodyReader<'static>;
+    type Out = UreqBody<::ureq::BodyReader<'static>>;
 
     fn request<S: Send + Sync + 'static>(
         &mut self,
         req: http::Request<super::Body<'_>>,
     ) -> Result<http::Response<Self::Out>, super::Error<Self::Error, S>> {
-        let (mut parts, body) = req.into_parts();
-        parts.extensions.insert(ureq_proto::CapitalizeHeaders);
-        match body {
-            super::Body::None => self.run(::http::Request::from_parts(parts, ())),
-            super::Body::Data(cow) => self.run(::http::Request::from_parts(parts, cow.as_ref())),
-        }
-        .map(|res| {
-            let (parts, body) = res.into_parts();
-            ::http::Response::from_parts(parts, body.into_reader())
-        })
-        .map_err(super::Error::Transport)
+        run(self, req, false)
     }
 
-    fn download<S: Send + Sync + 'static, W: std::io::Write>(
+    fn download<S: Send + Sync + 'static, W: std::io::Write + std::io::Seek>(
         &mut self,
-        req: http::Request<super::Body<'_>>,
+        mut req: http::Request<super::Body<'_>>,
+        offset: Option<u64>,
         mut output: W,
     ) -> Result<http::Response<W>, super::Error<Self::Error, S>> {
-        let (parts, mut body) = self.request(req)?.into_parts();
-        std::io::copy(&mut body, &mut output)?;
+        let offset = super::set_range(&mut req, offset);
+        let buffer_size = req
+            .extensions()
+            .get::<super::TransportOptions>()
+            .and_then(|options| options.buffer_size);
+        let (parts, mut body) = run(self, req, true)?.into_parts();
+        // A 202 only tells the book is being prepared, its body isn't part of the book.
+        let write = match (parts.status.as_u16(), offset) {
+            (202, _) => false,
+            (200..300, None) => true,
+            (206, Some(offset)) => {
+                if super::content_range_start(&parts.headers) != Some(offset) {
+                    return Err(super::Error::InvalidContentRange);
+                }
+                true
+            }
+            (200, Some(_)) => {
+                output.seek(std::io::SeekFrom::Start(0))?;
+                true
+            }
+            _ => false,
+        };
+        if write {
+            match buffer_size {
+                Some(size) => copy_buffered(&mut body, &mut output, size),
+                None => std::io::copy(&mut body, &mut output),
+            }
+            .map_err(|err| {
+                if err.kind() == std::io::ErrorKind::TimedOut {
+                    super::Error::Timeout
+                } else {
+                    super::Error::Io(err)
+                }
+            })?;
+        }
         Ok(::http::Response::from_parts(parts, output))
     }
 }
+
+/// Like [`std::io::copy`] with a buffer of `size` bytes.
+fn copy_buffered<R: std::io::Read, W: std::io::Write>(
+    reader: &mut R,
+    writer: &mut W,
+    size: std::num::NonZeroUsize,
+) -> std::io::Result<u64> {
+    let mut buf = vec![0; size.get()];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Cursor, Write};
+
+    use super::super::{FromResponse, Json, Transport};
+
+    const JSON: &str = r#"{"Key":"value","List":[1,2,3]}"#;
+
+    fn compress<W: Write>(mut encoder: W) -> W {
+        encoder.write_all(JSON.as_bytes()).unwrap();
+        encoder
+    }
+
+    fn decode<R: std::io::Read>(parts: ::http::response::Parts, body: R) -> serde_json::Value {
+        <Json<serde_json::Value> as FromResponse>::from_response::<
+            ::ureq::Error,
+            std::convert::Infallible,
+            _,
+        >(parts, body)
+        .unwrap()
+        .0
+    }
+
+    #[test]
+    fn deflate_body() {
+        let body = compress(flate2::write::ZlibEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ))
+        .finish()
+        .unwrap();
+        let (parts, ()) = ::http::Response::builder()
+            .header(::http::header::CONTENT_ENCODING, "Deflate")
+            .header(
+                ::http::header::CONTENT_TYPE,
+                "application/json; charset=utf-8",
+            )
+            .body(())
+            .unwrap()
+            .into_parts();
+        let body = super::UreqBody::new(&parts.headers, Cursor::new(body));
+        assert!(matches!(body, super::UreqBody::Deflate(_)));
+        assert_eq!(
+            decode(parts, body),
+            serde_json::from_str::<serde_json::Value>(JSON).unwrap()
+        );
+    }
+
+    #[test]
+    fn gzip_body() {
+        let body = compress(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ))
+        .finish()
+        .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Encoding: gzip\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+        });
+
+        let mut agent = ::ureq::Agent::new_with_defaults();
+        let req = ::http::Request::get(format!("http://{addr}/"))
+            .body(super::super::Body::None)
+            .unwrap();
+        let (parts, body) = agent
+            .request::<std::convert::Infallible>(req)
+            .unwrap()
+            .into_parts();
+        assert!(matches!(body, super::UreqBody::Plain(_)));
+        assert_eq!(
+            decode(parts, body),
+            serde_json::from_str::<serde_json::Value>(JSON).unwrap()
+        );
+        server.join().unwrap();
+    }
+}
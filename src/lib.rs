@@ -1,16 +1,22 @@
 mod config;
 mod content_type;
+mod decrypt;
+#[cfg(feature = "keyring")]
+mod keyring;
 mod kobo;
 mod session;
 
-use std::ops::Deref;
+use std::{borrow::Borrow, ops::Deref};
 
 pub use config::*;
 pub use content_type::*;
+pub use decrypt::*;
+#[cfg(feature = "keyring")]
+pub use keyring::KeyringSession;
 pub use kobo::*;
 pub use session::*;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct NonEmptyStr(str);
 
 impl Deref for NonEmptyStr {
@@ -22,6 +28,56 @@ impl Deref for NonEmptyStr {
     }
 }
 
+impl AsRef<str> for NonEmptyStr {
+    #[inline(always)]
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for NonEmptyStr {
+    #[inline(always)]
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+// Lets `Box<NonEmptyStr>` keys be looked up by `&str`.
+impl Borrow<str> for Box<NonEmptyStr> {
+    #[inline(always)]
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for NonEmptyStr {
+    #[inline(always)]
+    fn eq(&self, other: &str) -> bool {
+        &self.0 == other
+    }
+}
+
+impl PartialEq<&str> for NonEmptyStr {
+    #[inline(always)]
+    fn eq(&self, other: &&str) -> bool {
+        &self.0 == *other
+    }
+}
+
+impl PartialEq<NonEmptyStr> for str {
+    #[inline(always)]
+    fn eq(&self, other: &NonEmptyStr) -> bool {
+        self == &other.0
+    }
+}
+
+impl PartialEq<NonEmptyStr> for &str {
+    #[inline(always)]
+    fn eq(&self, other: &NonEmptyStr) -> bool {
+        *self == &other.0
+    }
+}
+
 impl core::fmt::Display for NonEmptyStr {
     #[inline(always)]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -99,6 +155,8 @@ impl NonEmptyStr {
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+#[error("string is empty")]
 pub struct EmptyStr(());
 
 impl<'a> TryFrom<&'a str> for &'a NonEmptyStr {
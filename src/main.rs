@@ -1,36 +1,164 @@
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
     convert::Infallible,
     fs::File,
-    io::{Read, Seek, Write},
+    io::{IsTerminal, Read, Seek, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     time::Duration,
 };
 
 use clap::Parser;
 use color_eyre::eyre::Report;
-use dialoguer::MultiSelect;
-use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
-use kobodown::{Config, Kobo};
+use dialoguer::{Confirm, Input, MultiSelect};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use kobodown::{DefaultAgent, Kobo};
+use parking_lot::Mutex;
 use zeroize::Zeroizing;
-use zip::{write::SimpleFileOptions, ZipArchive, ZipWriter};
+use zip::ZipArchive;
 
 #[derive(clap::Parser, Debug)]
-enum Cli {
+#[command(after_help = EXIT_CODES)]
+struct Cli {
+    #[command(flatten)]
+    global: Global,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Args, Debug)]
+struct Global {
+    #[arg(long, global = true, value_parser = profile_parser)]
+    pub profile: Option<Box<str>>,
+    #[arg(long, global = true, value_name = "SECS", default_value_t = 60)]
+    pub timeout: u64,
+    #[arg(short, long, global = true, default_value_t = false)]
+    pub quiet: bool,
+    #[arg(long, global = true, value_name = "URL")]
+    pub proxy: Option<Box<str>>,
+    #[arg(long, global = true, value_name = "PATH")]
+    pub cacert: Option<PathBuf>,
+    #[arg(long, global = true, default_value_t = false)]
+    pub insecure: bool,
+    /// Storefront of the account, e.g. `it/it`, defaults to `ww/en`
+    #[arg(long, global = true, value_name = "REGION/LANG", value_parser = locale_parser)]
+    pub locale: Option<Box<str>>,
+    /// Wait this long between the pages of a library sync, to stay clear of rate limits
+    #[arg(long, global = true, value_name = "MS", default_value_t = 0)]
+    pub sync_delay: u64,
+    /// Send the user key as `x-kobo-userkey` when accessing and downloading books, try it if
+    /// downloads are refused while the login works
+    #[arg(long, global = true, default_value_t = false)]
+    pub send_user_key: bool,
+    /// Send an extra header on every request, replacing the built-in one of the same name
+    #[arg(short = 'H', long = "header", global = true, value_name = "NAME: VALUE", value_parser = header_parser)]
+    pub headers: Vec<(::http::HeaderName, ::http::HeaderValue)>,
+    /// Send at most this many requests per second, all the concurrent downloads together
+    #[arg(long, global = true, value_name = "REQ/S")]
+    pub rate_limit: Option<std::num::NonZeroU32>,
+    /// Receive downloads this many KiB at a time, defaults to what the transport picks
+    #[arg(long, global = true, value_name = "KIB")]
+    pub buffer_size: Option<NonZeroUsize>,
+    #[arg(skip)]
+    pub limiter: std::sync::OnceLock<kobodown::RateLimiter>,
+}
+
+#[cfg(feature = "keyring")]
+type Config = kobodown::KeyringSession;
+#[cfg(not(feature = "keyring"))]
+type Config = kobodown::Config;
+
+type KoboError = kobodown::Error<
+    <DefaultAgent as kobodown::Transport>::Error,
+    <Config as kobodown::Session>::Error,
+>;
+
+impl Global {
+    fn config(&self) -> Config {
+        Config::load(self.profile.as_deref())
+    }
+
+    fn kobo(&self) -> Kobo<DefaultAgent> {
+        let mut builder = Kobo::builder()
+            .timeout(Duration::from_secs(self.timeout))
+            .insecure(self.insecure)
+            .sync_page_delay(Duration::from_millis(self.sync_delay))
+            .send_user_key(self.send_user_key)
+            .progress_throttle(kobodown::ProgressThrottle {
+                interval: PROGRESS_INTERVAL,
+                bytes: 0,
+            })
+            .cancel_flag(tmp::CANCEL.clone());
+        if let Some(ref proxy) = self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        for (name, value) in &self.headers {
+            builder = builder.header(name.clone(), value.clone());
+        }
+        if let Some(rate) = self.rate_limit {
+            let limiter = self
+                .limiter
+                .get_or_init(|| kobodown::RateLimiter::new(rate));
+            builder = builder.rate_limiter(limiter.clone());
+        }
+        if let Some(ref cacert) = self.cacert {
+            builder = builder.ca_file(cacert.clone());
+        }
+        if let Some(size) = self
+            .buffer_size
+            .and_then(|size| size.checked_mul(NonZeroUsize::new(1024).unwrap()))
+        {
+            builder = builder.download_buffer_size(size);
+        }
+        if let Some(ref locale) = self.locale {
+            let mut profile = kobodown::DeviceProfile::from_env();
+            profile.locale = locale.clone();
+            builder = builder.device_profile(profile);
+        }
+        builder.build_default()
+    }
+
+    /// Whether to print plain lines instead of progress bars.
+    fn quiet(&self) -> bool {
+        self.quiet || !std::io::stderr().is_terminal()
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
     Login(Login),
     Get(Get),
     List(List),
     Pick(Pick),
+    Wishlist(Wishlist),
+    Config(ShowConfig),
+    Verify(Verify),
+    /// Tell whether the profile is logged in, without contacting Kobo
+    Status,
+    /// Decrypt a book saved with --raw using the keys saved with --dump-keys, offline
+    Decrypt(Decrypt),
+    Devices(Devices),
 }
 
+/// Missing credentials are read from `KOBO_USERNAME`, `KOBO_PASSWORD` and the file named by
+/// `KOBO_CAPTCHA_FILE`, then asked for
 #[derive(clap::Parser, Debug)]
 struct Login {
     #[arg(short, long)]
     pub username: Option<Box<str>>,
     #[arg(value_parser = password_parser, short, long)]
     pub password: Option<Zeroizing<Box<str>>>,
+    /// The captcha token, `-` reads it from stdin
     #[arg(short, long)]
     pub captcha: Option<Box<str>>,
+    /// Read the captcha token from a file
+    #[arg(long, value_name = "PATH", conflicts_with = "captcha")]
+    pub captcha_file: Option<PathBuf>,
+    /// Log in as the device with this UUID, e.g. the one of an e-reader, instead of a random one
+    #[arg(long, value_name = "UUID", value_parser = device_id_parser)]
+    pub device_id: Option<Box<str>>,
 }
 
 #[derive(clap::Parser, Debug)]
@@ -39,30 +167,431 @@ struct Get {
     pub output_dir: Option<PathBuf>,
     #[arg(short = 'o', long)]
     pub output_file: Option<PathBuf>,
-    pub id: Box<str>,
+    #[command(flatten)]
+    pub download: DownloadOptions,
+    /// Print the formats the books are offered in instead of downloading them
+    #[arg(long, default_value_t = false, conflicts_with = "output_file")]
+    pub list_formats: bool,
+    #[arg(long, conflicts_with = "ids")]
+    pub title: Option<String>,
+    /// Download every book of the series with this name, in order and numbered
+    #[arg(long, conflicts_with_all = ["ids", "title", "output_file", "list_formats"])]
+    pub series: Option<String>,
+    /// Decrypt a download kept by --keep-temp instead of downloading the book again
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["raw", "all_formats", "list_formats"])]
+    pub from_temp: Option<PathBuf>,
+    #[arg(required_unless_present_any = ["title", "series"], value_parser = book_id_parser)]
+    pub ids: Vec<kobodown::ProductId>,
 }
 
 #[derive(clap::Parser, Debug)]
 struct List {
+    /// Include the books already finished, left out otherwise
     #[arg(short, long, default_value_t = false)]
     pub all: bool,
+    #[arg(long, default_value_t = false)]
+    pub full: bool,
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+    /// Print the books in columns fitted to the terminal, with shortened ids
+    #[arg(long, default_value_t = false, conflicts_with = "json")]
+    pub table: bool,
+    #[command(flatten)]
+    pub filter: BookFilter,
+}
+
+#[derive(clap::Parser, Debug)]
+struct ShowConfig {
+    #[arg(long, default_value_t = false)]
+    pub show: bool,
+}
+
+#[derive(clap::Parser, Debug)]
+struct Wishlist {
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+}
+
+#[derive(clap::Parser, Debug)]
+struct Verify {
+    pub dir: PathBuf,
+}
+
+/// Show the device the profile is registered as, or forget it locally
+///
+/// This can't list the other devices of the account nor deregister one. The store API only has
+/// `v1/auth/device` to register a device, and none of the resources `v1/initialization` hands
+/// out to the Kobo apps removes one (see `NATIVE_KOBO_RESOURCES` in calibre-web's
+/// `cps/kobo.py` for a copy of the list). Devices are removed from the account settings on the
+/// Kobo site, --forget only clears the tokens and device id saved in the profile.
+#[derive(clap::Parser, Debug)]
+struct Devices {
+    /// Log out and forget the device, the next login registers a new one unless --device-id is
+    /// given
+    #[arg(long, default_value_t = false)]
+    pub forget: bool,
+}
+
+#[derive(clap::Parser, Debug)]
+struct Decrypt {
+    /// The encrypted book, e.g. `name.drm.zip`
+    pub input: PathBuf,
+    /// Defaults to the input with `.keys.json` in place of `.drm.zip`
+    #[arg(short, long, value_name = "PATH")]
+    pub keys: Option<PathBuf>,
+    /// Defaults to the input with `.epub` in place of `.drm.zip`
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+    #[arg(short, long)]
+    pub jobs: Option<NonZeroUsize>,
+    #[arg(long, value_name = "0-9|store", value_parser = compression_parser)]
+    pub compression: Option<kobodown::Compression>,
+    #[arg(long, default_value_t = false)]
+    pub copy_unencrypted: bool,
+    /// Skip checking that the container and the package document of the book parse
+    #[arg(long, default_value_t = false)]
+    pub no_validate: bool,
+    /// Strip the spans and the other markup Kobo adds to kepubs, for other readers
+    #[arg(long, default_value_t = false)]
+    pub clean_kepub: bool,
 }
 
 #[derive(clap::Parser, Debug)]
 struct Pick {
     #[arg(short = 'd', long)]
     pub output_dir: Option<PathBuf>,
+    /// Offer the books already finished too
     #[arg(short, long, default_value_t = false)]
     pub all: bool,
+    #[arg(long, default_value_t = false)]
+    pub full: bool,
+    #[arg(short = 'c', long, default_value = "1")]
+    pub concurrency: NonZeroUsize,
+    #[command(flatten)]
+    pub filter: BookFilter,
+    #[command(flatten)]
+    pub download: DownloadOptions,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct BookFilter {
+    #[arg(long)]
+    pub filter: Option<String>,
+    #[arg(long)]
+    pub author: Option<String>,
+    #[arg(long, default_value_t = false, conflicts_with = "skip_archived")]
+    pub only_archived: bool,
+    #[arg(long, default_value_t = false)]
+    pub skip_archived: bool,
+    /// Only books added or changed after DATE, as `YYYY-MM-DD` (UTC) or an RFC 3339 timestamp
+    #[arg(long, value_name = "DATE", value_parser = date_parser)]
+    pub modified_since: Option<time::OffsetDateTime>,
+    #[arg(long, value_enum, default_value_t = Sort::Title)]
+    pub sort: Sort,
+    /// Reverse the order, books without authors or a date still come last
+    #[arg(long, default_value_t = false)]
+    pub reverse: bool,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Sort {
+    Title,
+    /// By authors, then by title. Books without authors come last
+    Author,
+    /// Most recently added first. Books without a date come last
+    Added,
+    Id,
+}
+
+impl BookFilter {
+    fn apply(&self, books: &mut Vec<kobodown::Book>) {
+        let filter = self.filter.as_deref().map(str::to_lowercase);
+        let author = self.author.as_deref().map(str::to_lowercase);
+        let authors = |book: &kobodown::Book| book.authors.as_deref().unwrap_or("").to_lowercase();
+        books.retain(|book| {
+            filter
+                .as_deref()
+                .is_none_or(|f| book.title.to_lowercase().contains(f) || authors(book).contains(f))
+                && author.as_deref().is_none_or(|a| authors(book).contains(a))
+                && (!self.only_archived || book.is_archived)
+                && (!self.skip_archived || !book.is_archived)
+                && self
+                    .modified_since
+                    .is_none_or(|since| book.last_changed().is_some_and(|d| d > since))
+        });
+        let order = |ord: std::cmp::Ordering| if self.reverse { ord.reverse() } else { ord };
+        match self.sort {
+            Sort::Title => books.sort_by(|a, b| order(a.title.cmp(&b.title))),
+            Sort::Author => books.sort_by(|a, b| match (&a.authors, &b.authors) {
+                (Some(x), Some(y)) => order(
+                    x.to_lowercase()
+                        .cmp(&y.to_lowercase())
+                        .then_with(|| a.title.cmp(&b.title)),
+                ),
+                (None, None) => order(a.title.cmp(&b.title)),
+                (x, y) => y.is_some().cmp(&x.is_some()),
+            }),
+            Sort::Added => books.sort_by(|a, b| match (a.date_added, b.date_added) {
+                (Some(a), Some(b)) => order(b.cmp(&a)),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            }),
+            Sort::Id => books.sort_by(|a, b| order(a.revision_id.cmp(&b.revision_id))),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct DownloadOptions {
+    #[arg(short, long)]
+    pub jobs: Option<NonZeroUsize>,
+    #[arg(short, long, value_enum)]
+    pub format: Option<Format>,
+    /// Download every format the book is offered in, e.g. `name.epub` and `name.kepub.epub`
+    #[arg(long, default_value_t = false, conflicts_with = "format")]
+    pub all_formats: bool,
+    #[arg(long, default_value_t = false)]
+    pub raw: bool,
+    #[arg(long, default_value_t = false)]
+    pub dump_keys: bool,
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+    #[arg(long, default_value_t = false)]
+    pub no_space_check: bool,
+    #[arg(long, default_value_t = false)]
+    pub cover: bool,
+    /// Write the metadata of the book to an OPF file next to it, for Calibre
+    #[arg(long, default_value_t = false)]
+    pub write_opf: bool,
+    #[arg(long, value_name = "0-9|store", value_parser = compression_parser)]
+    pub compression: Option<kobodown::Compression>,
+    #[arg(long, default_value_t = false)]
+    pub copy_unencrypted: bool,
+    /// Skip checking that the container and the package document of decrypted books parse
+    #[arg(long, default_value_t = false)]
+    pub no_validate: bool,
+    /// Strip the spans and the other markup Kobo adds to kepubs, for other readers
+    #[arg(long, default_value_t = false, conflicts_with = "raw")]
+    pub clean_kepub: bool,
+    /// What to do when the book is already there
+    #[arg(long, value_enum, default_value_t = IfExists::Overwrite)]
+    pub if_exists: IfExists,
+    #[arg(short, long, value_parser = template_parser)]
+    pub template: Option<Box<str>>,
+    /// Keep the file name Kobo suggests, if any, instead of naming files after the template.
+    /// Existing files are only noticed once the download is over
+    #[arg(long, default_value_t = false, conflicts_with = "template")]
+    pub keep_name: bool,
+    /// Keep the encrypted download when decrypting it fails, to retry with `get --from-temp`
+    #[arg(long, default_value_t = false)]
+    pub keep_temp: bool,
+    /// Where to keep the encrypted download, defaults to $TMPDIR if set or the output directory
+    #[arg(long, value_name = "DIR")]
+    pub temp_dir: Option<PathBuf>,
+    #[arg(skip)]
+    pub from_temp: Option<PathBuf>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum Format {
+    Epub3,
+    Epub3fl,
+    Kepub,
+}
+
+impl From<Format> for kobodown::UrlFormat {
+    fn from(value: Format) -> Self {
+        match value {
+            Format::Epub3 => Self::EPUB3,
+            Format::Epub3fl => Self::EPUB3FL,
+            Format::Kepub => Self::KEPUB,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum IfExists {
+    /// Replace explicit output files, number generated names
+    Overwrite,
+    /// Leave existing non-empty files alone
+    Skip,
+    /// Stop with an error
+    Error,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{} already exists", .0.display())]
+struct AlreadyExists(PathBuf);
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct NoMatch(&'static str);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Not enough space in {}: {} needed, {} available (use --no-space-check to skip this check)", .dir.display(), HumanBytes(*.needed), HumanBytes(*.available))]
+struct NoSpace {
+    dir: PathBuf,
+    needed: u64,
+    available: u64,
+}
+
+const EXIT_CODES: &str = "\
+Exit codes:
+  1    Unexpected error
+  2    Invalid arguments
+  3    Not logged in, the session has expired or the login was refused
+  4    Network error or Kobo is unavailable, try again later
+  5    Book not found
+  6    Not enough disk space
+  130  Cancelled";
+
+/// What a failure means to a script, see [`EXIT_CODES`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    Failure = 1,
+    Login = 3,
+    Network = 4,
+    NotFound = 5,
+    NoSpace = 6,
+    Cancelled = 130,
+}
+
+impl Exit {
+    fn of(err: &Report) -> Self {
+        if err.is::<NoMatch>() {
+            return Self::NotFound;
+        }
+        if err.is::<NoSpace>() {
+            return Self::NoSpace;
+        }
+        let io = match err.downcast_ref::<KoboError>() {
+            Some(err) => match Self::of_kobo(err) {
+                Some(exit) => return exit,
+                None => match err {
+                    kobodown::Error::Io(err) => Some(err),
+                    _ => None,
+                },
+            },
+            None => err.downcast_ref::<std::io::Error>(),
+        };
+        match io.map(std::io::Error::kind) {
+            Some(std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded) => {
+                Self::NoSpace
+            }
+            _ => Self::Failure,
+        }
+    }
+
+    fn of_kobo(err: &KoboError) -> Option<Self> {
+        use ::http::StatusCode;
+        use kobodown::Error;
+
+        Some(match err {
+            Error::NotLoggedIn
+            | Error::SessionExpired
+            | Error::InvalidCredentials
+            | Error::CaptchaExpired
+            | Error::VerificationRequired => Self::Login,
+            Error::Connection(_)
+            | Error::Tls(_)
+            | Error::Timeout
+            | Error::Transport(_)
+            | Error::NotReady => Self::Network,
+            Error::StatusCode(StatusCode::NOT_FOUND) => Self::NotFound,
+            Error::StatusCode(status)
+                if status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS =>
+            {
+                Self::Network
+            }
+            Error::Cancelled => Self::Cancelled,
+            _ => return None,
+        })
+    }
+}
+
+fn compression_parser(raw: &str) -> Result<kobodown::Compression, String> {
+    if raw.eq_ignore_ascii_case("store") {
+        return Ok(kobodown::Compression::Stored);
+    }
+    match raw.parse() {
+        Ok(level @ 0..=9) => Ok(kobodown::Compression::Deflate(Some(level))),
+        _ => Err(format!(
+            "invalid compression {raw:?}, expected a level from 0 to 9 or `store`"
+        )),
+    }
 }
 
+impl DownloadOptions {
+    fn format(&self) -> Option<kobodown::UrlFormat> {
+        self.format.map(Into::into)
+    }
+
+    fn temp_dir(&self) -> Option<PathBuf> {
+        self.temp_dir.clone().or_else(|| {
+            std::env::var_os("TMPDIR")
+                .filter(|p| !p.is_empty())
+                .map(Into::into)
+        })
+    }
+
+    /// Picks the file name of a book, `taken` are the names already given to this batch.
+    fn output_name(&self, dir: Option<&Path>, name: String, taken: &HashSet<PathBuf>) -> PathBuf {
+        match self.if_exists {
+            IfExists::Overwrite => unique_name_among(dir, name, taken),
+            // Existing files are up to the policy, the batch still needs distinct names.
+            IfExists::Skip | IfExists::Error => {
+                number_name(name, |name| taken.contains(Path::new(name)))
+            }
+        }
+    }
+
+    /// Whether `target` can be written, following `--if-exists` for existing files.
+    fn may_write(&self, target: &Path, pb: &ProgressBar) -> Result<bool, Report> {
+        if self.if_exists == IfExists::Overwrite || target.metadata().is_ok_and(|m| m.len() == 0) {
+            return Ok(true);
+        }
+        if self.if_exists == IfExists::Error {
+            return Err(AlreadyExists(target.to_path_buf()).into());
+        }
+        pb.suspend(|| eprintln!("Skipping {}, it already exists", target.display()));
+        Ok(false)
+    }
+
+    fn decrypt_options(&self) -> kobodown::DecryptOptions {
+        kobodown::DecryptOptions {
+            compression: self.compression.unwrap_or_default(),
+            copy_unencrypted: self.copy_unencrypted,
+            jobs: self.jobs(),
+            validate: !self.no_validate,
+            clean_kepub: self.clean_kepub,
+        }
+    }
+
+    fn jobs(&self) -> NonZeroUsize {
+        self.jobs
+            .or_else(|| std::thread::available_parallelism().ok())
+            .unwrap_or(NonZeroUsize::MIN)
+    }
+
+    /// Progress is pointless when nothing is downloaded.
+    fn quiet(&self, global: &Global) -> bool {
+        self.dry_run || global.quiet()
+    }
+}
+
+const CAPTCHA_ATTEMPTS: usize = 3;
+
 fn login(
+    global: &Global,
     Login {
         username,
         password,
         captcha,
+        captcha_file,
+        device_id,
     }: Login,
 ) -> Result<(), Report> {
+    use kobodown::Session;
+
     fn read_line(prompt: &str) -> Result<Box<str>, Report> {
         let mut res = String::new();
         loop {
@@ -94,22 +623,69 @@ fn login(
         }
     }
 
-    let username =
-        if let Some(username) = username.and_then(|s| if s.is_empty() { None } else { Some(s) }) {
-            username
-        } else {
-            read_line("Username: ")?
-        };
-    let password =
-        if let Some(password) = password.and_then(|s| if s.is_empty() { None } else { Some(s) }) {
-            password
-        } else {
-            read_password()?
-        };
-    let captcha = if let Some(captcha) =
-        captcha.and_then(|s| if s.is_empty() { None } else { Some(s) })
+    fn env_var(name: &str) -> Option<String> {
+        std::env::var(name).ok().filter(|s| !s.is_empty())
+    }
+
+    fn read_captcha(path: &Path, what: &str) -> Result<Option<Box<str>>, Report> {
+        let captcha = std::fs::read_to_string(path).map_err(|err| {
+            color_eyre::eyre::eyre!("Cannot read {what} {}: {err}", path.display())
+        })?;
+        let captcha = captcha.trim();
+        Ok((!captcha.is_empty()).then(|| captcha.into()))
+    }
+
+    let captcha_stdin = captcha.as_deref() == Some("-");
+    let username = if let Some(username) = username
+        .and_then(|s| if s.is_empty() { None } else { Some(s) })
+        .or_else(|| env_var("KOBO_USERNAME").map(String::into_boxed_str))
+    {
+        username
+    } else if captcha_stdin {
+        color_eyre::eyre::bail!("--captcha - needs the username in --username or KOBO_USERNAME");
+    } else {
+        read_line("Username: ")?
+    };
+    let password = if let Some(password) = password
+        .and_then(|s| if s.is_empty() { None } else { Some(s) })
+        .or_else(|| env_var("KOBO_PASSWORD").map(|s| Zeroizing::new(s.into_boxed_str())))
     {
+        password
+    } else {
+        read_password()?
+    };
+    let captcha = if captcha_stdin {
+        let mut captcha = String::new();
+        std::io::stdin().read_to_string(&mut captcha)?;
+        let captcha = captcha.trim();
+        if captcha.is_empty() {
+            color_eyre::eyre::bail!("No captcha on stdin");
+        }
+        Some(captcha.into())
+    } else if let Some(path) = captcha_file {
+        match read_captcha(&path, "--captcha-file")? {
+            Some(captcha) => Some(captcha),
+            None => color_eyre::eyre::bail!("{} is empty", path.display()),
+        }
+    } else {
+        match captcha
+            .map(|s| s.trim().into())
+            .and_then(|s: Box<str>| if s.is_empty() { None } else { Some(s) })
+        {
+            Some(captcha) => Some(captcha),
+            None => match std::env::var_os("KOBO_CAPTCHA_FILE").filter(|p| !p.is_empty()) {
+                Some(path) => read_captcha(Path::new(&path), "KOBO_CAPTCHA_FILE")?,
+                None => None,
+            },
+        }
+    };
+    let interactive = captcha.is_none();
+    let mut captcha = if let Some(captcha) = captcha {
         captcha
+    } else if !std::io::stdin().is_terminal() {
+        color_eyre::eyre::bail!(
+            "A captcha is required, pass it with --captcha, --captcha-file or in the file named by KOBO_CAPTCHA_FILE"
+        );
     } else {
         println!(
             r#"
@@ -133,23 +709,309 @@ and paste it here.
         read_line("Captcha: ")?
     };
 
-    let mut config = Config::load();
-    let mut kobo = Kobo::default();
+    let mut config = global.config();
+    let mut kobo = global.kobo();
+
+    if let Some(device_id) = device_id {
+        if config.device_id().is_none_or(|id| *id != *device_id) {
+            // The tokens were issued to the previous device.
+            config.remove_access_token();
+            config.remove_refresh_token();
+            config.remove_user_id();
+            config.remove_user_key();
+            config.set_device_id(Some(device_id));
+        }
+    }
+
+    // Kobo doesn't tell how many devices the account has, only that a new one is going to count.
+    let new_device = config.device_id().is_none();
+    if new_device {
+        eprintln!(
+            "A new device will be registered, Kobo limits the devices of an account: pass --device-id to reuse one"
+        );
+    }
+
+    let mut attempts = 1;
+    loop {
+        match kobo.login(&mut config, &username, &password, &captcha) {
+            Err(kobodown::Error::CaptchaExpired) if interactive && attempts < CAPTCHA_ATTEMPTS => {
+                attempts += 1;
+                eprintln!("The captcha was rejected or has expired, solve it again");
+                captcha = read_line("Captcha: ")?;
+            }
+            res => break res?,
+        }
+    }
 
-    kobo.login(&mut config, &username, &password, &captcha)?;
+    let device_id = config.device_id().map_or("", |id| &**id);
+    if new_device {
+        eprintln!(
+            "Registered device {device_id}, it can be removed from the Kobo account settings"
+        );
+    } else {
+        eprintln!("Logged in as device {device_id}");
+    }
     Ok(())
 }
 
 fn get(
+    global: &Global,
     Get {
         output_dir,
         output_file,
-        id,
+        mut download,
+        list_formats,
+        title,
+        series,
+        from_temp,
+        ids,
     }: Get,
 ) -> Result<(), Report> {
-    let mut settings = Config::load();
-    let mut kobo = Kobo::default();
+    let mut settings = global.config();
+    let mut kobo = global.kobo();
+
+    if list_formats {
+        let ids = match title {
+            Some(title) => vec![find_by_title(&mut kobo, &mut settings, &title)?.revision_id],
+            None => ids,
+        };
+        return print_formats(&mut kobo, &mut settings, &ids);
+    }
 
+    if download.keep_name && output_file.is_some() {
+        color_eyre::eyre::bail!("--keep-name can't be used with --output-file");
+    }
+    let mut names = Vec::new();
+    let (ids, output_file) = if let Some(series) = series {
+        let books = find_series(&mut kobo, &mut settings, &series)?;
+        // With --keep-name the files are named after Kobo, the series is left out then.
+        if !download.keep_name {
+            let mut taken = HashSet::new();
+            for book in &books {
+                let name = download.output_name(
+                    output_dir.as_deref(),
+                    series_name(
+                        book.series_index.as_deref(),
+                        mkname(
+                            download.template.as_deref(),
+                            book.authors.as_deref(),
+                            &book.title,
+                            &book.revision_id,
+                        ),
+                    ),
+                    &taken,
+                );
+                taken.insert(name.clone());
+                names.push(name);
+            }
+        }
+        (
+            books.into_iter().map(|book| book.revision_id).collect(),
+            None,
+        )
+    } else if let Some(title) = title {
+        let book = find_by_title(&mut kobo, &mut settings, &title)?;
+        let output_file = output_file.unwrap_or_else(|| {
+            download.output_name(
+                output_dir.as_deref(),
+                mkname(
+                    download.template.as_deref(),
+                    book.authors.as_deref(),
+                    &book.title,
+                    &book.revision_id,
+                ),
+                &HashSet::new(),
+            )
+        });
+        (vec![book.revision_id], Some(output_file))
+    } else {
+        (ids, output_file)
+    };
+    if output_file.is_some() && ids.len() != 1 {
+        color_eyre::eyre::bail!("--output-file can only be used with a single id");
+    }
+    if from_temp.is_some() && ids.len() != 1 {
+        color_eyre::eyre::bail!("--from-temp can only be used with a single id");
+    }
+    download.from_temp = from_temp;
+
+    if let [id] = &ids[..] {
+        let pb = default_bar(download.quiet(global));
+        return get_book(
+            &mut kobo,
+            &mut settings,
+            id,
+            output_dir,
+            output_file.or_else(|| names.pop()),
+            &download,
+            &pb,
+            DownloadProgress(None),
+        );
+    }
+
+    let (pb, file_pb, global_pb) = multi_progress(ids.len(), download.quiet(global))?;
+    let mut failed = 0;
+    for (i, id) in ids.iter().enumerate() {
+        if let Err(err) = get_book(
+            &mut kobo,
+            &mut settings,
+            id,
+            output_dir.clone(),
+            names.get(i).cloned(),
+            &download,
+            &file_pb,
+            DownloadProgress(Some(&global_pb)),
+        ) {
+            if err.is::<AlreadyExists>()
+                || matches!(
+                    err.downcast_ref::<KoboError>(),
+                    Some(kobodown::Error::Cancelled)
+                )
+            {
+                return Err(err);
+            }
+            failed += 1;
+            pb.suspend(|| eprintln!("Cannot download {id}: {err}"));
+        }
+        if global_pb.is_hidden() && !download.dry_run {
+            eprintln!("Downloaded {} of {}", i + 1, ids.len());
+        }
+    }
+    if failed != 0 {
+        color_eyre::eyre::bail!("{failed} of {} books could not be downloaded", ids.len());
+    }
+    Ok(())
+}
+
+fn print_formats<T: kobodown::Transport, S: kobodown::Session>(
+    kobo: &mut Kobo<T>,
+    settings: &mut S,
+    ids: &[kobodown::ProductId],
+) -> Result<(), Report> {
+    for id in ids {
+        if ids.len() > 1 {
+            println!("{id}:");
+        }
+        for info in kobo.content_formats(&mut *settings, id)? {
+            println!(
+                "{:<8} {:<12} {}",
+                info.format.to_string(),
+                info.drm_type.to_string(),
+                HumanBytes(info.byte_size)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Finds the books of the series named `name`, ignoring case, ordered by their position in it.
+/// Books without a position come last.
+fn find_series<T: kobodown::Transport, S: kobodown::Session>(
+    kobo: &mut Kobo<T>,
+    settings: &mut S,
+    name: &str,
+) -> Result<Vec<kobodown::Book>, Report> {
+    let name = name.to_lowercase();
+    let (mut books, others): (Vec<_>, Vec<_>) = partial_books(kobo.book_list(settings, true)?)
+        .into_iter()
+        .partition(|book| {
+            book.series
+                .as_deref()
+                .is_some_and(|s| s.to_lowercase() == name)
+        });
+    if books.is_empty() {
+        let mut similar = others
+            .iter()
+            .filter_map(|book| book.series.as_deref())
+            .filter(|series| series.to_lowercase().contains(&name))
+            .collect::<Vec<_>>();
+        similar.sort_unstable();
+        similar.dedup();
+        for series in similar {
+            eprintln!("{series}");
+        }
+        return Err(NoMatch("No book belongs to the series").into());
+    }
+    books.retain(|book| {
+        if book.is_audiobook {
+            eprintln!("Skipping {book}, audiobooks can't be downloaded");
+        }
+        !book.is_audiobook
+    });
+    if books.is_empty() {
+        return Err(NoMatch("The series only has audiobooks").into());
+    }
+    let index = |book: &kobodown::Book| {
+        book.series_index
+            .as_deref()
+            .and_then(|i| i.trim().parse::<f64>().ok())
+            .filter(|i| i.is_finite())
+    };
+    books.sort_by(|a, b| match (index(a), index(b)) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+    Ok(books)
+}
+
+/// Numbers the file `name` of a series so that the files sort in order, e.g. `01 - name` or
+/// `02.5 - name`. The directories a template adds are left alone.
+fn series_name(index: Option<&str>, name: String) -> String {
+    let Some(index) = index.map(str::trim).filter(|i| !i.is_empty()) else {
+        return name;
+    };
+    let index = sanitize_filename::sanitize(index);
+    let int = index.split('.').next().unwrap_or_default();
+    let pad = if int.len() == 1 && int.bytes().all(|c| c.is_ascii_digit()) {
+        "0"
+    } else {
+        ""
+    };
+    match name.rsplit_once('/') {
+        Some((dir, file)) => format!("{dir}/{pad}{index} - {file}"),
+        None => format!("{pad}{index} - {name}"),
+    }
+}
+
+/// Finds the only book whose title contains `title`, ignoring case.
+fn find_by_title<T: kobodown::Transport, S: kobodown::Session>(
+    kobo: &mut Kobo<T>,
+    settings: &mut S,
+    title: &str,
+) -> Result<kobodown::Book, Report> {
+    let title = title.to_lowercase();
+    let mut books = partial_books(kobo.book_list(settings, false)?);
+    books.retain(|book| book.title.to_lowercase().contains(&title));
+    match books.len() {
+        0 => Err(NoMatch("No book matches the title").into()),
+        1 if books[0].is_audiobook => {
+            color_eyre::eyre::bail!(
+                "{} is an audiobook, audiobooks can't be downloaded",
+                books[0]
+            )
+        }
+        1 => Ok(books.remove(0)),
+        n => {
+            for book in books {
+                eprintln!("{} - {book}", book.revision_id);
+            }
+            color_eyre::eyre::bail!("{n} books match the title, narrow it down or pass an id")
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn get_book<T: kobodown::Transport, S: kobodown::Session>(
+    kobo: &mut Kobo<T>,
+    settings: &mut S,
+    id: &kobodown::ProductId,
+    output_dir: Option<PathBuf>,
+    output_file: Option<PathBuf>,
+    download: &DownloadOptions,
+    pb: &ProgressBar,
+    progress: DownloadProgress<'_>,
+) -> Result<(), Report> {
+    let mut info = None;
     let (output_dir, output_file) = if let Some(output_file) = output_file {
         if let Some(parent) = output_file.parent() {
             let name = output_file
@@ -171,93 +1033,1003 @@ fn get(
             (output_dir, output_file)
         }
     } else {
-        let book = kobo.book_info(&mut settings, &id)?;
-        (
-            output_dir,
-            PathBuf::from(mkname(book.author.as_deref(), &book.title)),
-        )
+        let book = kobo.book_info(&mut *settings, id)?;
+        let name = download.output_name(
+            output_dir.as_deref(),
+            mkname(
+                download.template.as_deref(),
+                book.author.as_deref(),
+                &book.title,
+                id,
+            ),
+            &HashSet::new(),
+        );
+        info = Some(book);
+        (output_dir, name)
+    };
+
+    let session = Mutex::new(settings);
+    let saved = download_book(
+        kobo,
+        &session,
+        id,
+        output_dir.as_deref(),
+        &output_file,
+        download,
+        pb,
+        progress,
+    )?;
+    if let Some(path) = saved {
+        save_extras(kobo, &session, id, &path, info, download, pb)?;
+    }
+    Ok(())
+}
+
+/// Downloads `id` as `name`, or every format of it named after [`format_name`] with
+/// `--all-formats`. Returns the path of the first file saved, if any.
+///
+/// `session` is locked for the requests that can refresh the tokens, so that workers sharing it
+/// refresh one at a time on the latest tokens. The downloads run on a copy of the tokens.
+#[allow(clippy::too_many_arguments)]
+fn download_book<T: kobodown::Transport, S: kobodown::Session>(
+    kobo: &mut Kobo<T>,
+    session: &Mutex<S>,
+    id: &kobodown::ProductId,
+    dir: Option<&Path>,
+    name: &Path,
+    download: &DownloadOptions,
+    pb: &ProgressBar,
+    progress: DownloadProgress<'_>,
+) -> Result<Option<PathBuf>, Report> {
+    if !download.all_formats {
+        let desc = kobo.access_book(&mut *session.lock(), id, download.format())?;
+        let mut tokens = tokens(&*session.lock());
+        return download_zip(kobo, &mut tokens, desc, dir, name, download, pb, progress)
+            .map_err(session_error::<T, S>);
+    }
+    let books = kobo.access_book_all(&mut *session.lock(), id)?;
+    if books.is_empty() {
+        color_eyre::eyre::bail!("{id} has no downloadable format");
+    }
+    let mut tokens = tokens(&*session.lock());
+    let last = books.len() - 1;
+    let mut progress = Some(progress);
+    let mut saved = None;
+    for (i, (format, desc)) in books.into_iter().enumerate() {
+        // The book counts as done once its last format is.
+        let progress = if i == last {
+            progress.take().unwrap()
+        } else {
+            DownloadProgress(None)
+        };
+        let name = format_name(name, format);
+        let path = download_zip(kobo, &mut tokens, desc, dir, name, download, pb, progress)
+            .map_err(session_error::<T, S>)?;
+        saved = saved.or(path);
+    }
+    Ok(saved)
+}
+
+/// A copy of the tokens of `session`, for the downloads which only read them.
+fn tokens<S: kobodown::Session>(session: &S) -> kobodown::InMemorySession {
+    let copy = |token: Option<&kobodown::NonEmptyStr>| token.map(|t| t.to_boxed_non_empty_str());
+    kobodown::InMemorySession {
+        access_token: copy(session.access_token()),
+        device_id: copy(session.device_id()),
+        refresh_token: copy(session.refresh_token()),
+        user_id: copy(session.user_id()),
+        user_key: copy(session.user_key()),
+        library_sync_token: None,
+    }
+}
+
+/// Reports an error of a download on the copy of the tokens as one of `S`, which is what the
+/// callers downcast to.
+fn session_error<T: kobodown::Transport, S: kobodown::Session>(err: Report) -> Report {
+    match err.downcast::<kobodown::Error<T::Error, std::convert::Infallible>>() {
+        Ok(err) => err
+            .map_session_err::<S::Error>(|never| match never {})
+            .into(),
+        Err(err) => err,
+    }
+}
+
+/// `name` with the extension of `format`: `.epub`, `.kepub.epub` or `.fl.epub`.
+fn format_name(name: &Path, format: kobodown::UrlFormat) -> PathBuf {
+    let suffix = match format {
+        kobodown::UrlFormat::EPUB3 => return name.to_path_buf(),
+        kobodown::UrlFormat::KEPUB => "kepub.epub",
+        kobodown::UrlFormat::EPUB3FL => "fl.epub",
+    };
+    name.with_extension(suffix)
+}
+
+/// Writes the files asked for along the book saved at `path`, `info` is fetched when needed.
+fn save_extras<T: kobodown::Transport, S: kobodown::Session>(
+    kobo: &mut Kobo<T>,
+    session: &Mutex<S>,
+    id: &kobodown::ProductId,
+    path: &Path,
+    info: Option<kobodown::BookInfo>,
+    download: &DownloadOptions,
+    pb: &ProgressBar,
+) -> Result<(), Report> {
+    if download.dry_run {
+        return Ok(());
+    }
+    if download.cover {
+        save_cover(kobo, &mut *session.lock(), id, path, pb)?;
+    }
+    if download.write_opf {
+        let info = match info {
+            Some(info) => info,
+            None => kobo.book_info(&mut *session.lock(), id)?,
+        };
+        let path = path.with_extension("opf");
+        let mut f = TempFile::from_parts(File::create(&path)?, path);
+        f.write_all(opf(id, &info).as_bytes())?;
+        f.keep();
+    }
+    Ok(())
+}
+
+/// Escapes `s` for XML text and attribute values, dropping the characters XML can't hold.
+fn xml_escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&apos;"),
+            '\t' | '\n' | '\r' => res.push(c),
+            c if c.is_control() || matches!(c, '\u{FFFE}' | '\u{FFFF}') => (),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+/// A minimal OPF 2 package with the metadata Calibre imports.
+fn opf(id: &str, info: &kobodown::BookInfo) -> String {
+    use std::fmt::Write;
+
+    let mut res = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<package xmlns=\"http://www.idpf.org/2007/opf\" unique-identifier=\"kobo-id\" version=\"2.0\">\n",
+        "  <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:opf=\"http://www.idpf.org/2007/opf\">\n",
+    ));
+    let _ = writeln!(res, "    <dc:title>{}</dc:title>", xml_escape(&info.title));
+    for author in info
+        .author
+        .as_deref()
+        .into_iter()
+        .flat_map(|a| a.split(" & "))
+    {
+        let _ = writeln!(
+            res,
+            "    <dc:creator opf:role=\"aut\">{}</dc:creator>",
+            xml_escape(author)
+        );
+    }
+    let _ = writeln!(
+        res,
+        "    <dc:identifier id=\"kobo-id\" opf:scheme=\"KOBO\">{}</dc:identifier>",
+        xml_escape(id)
+    );
+    if let Some(ref isbn) = info.isbn {
+        let _ = writeln!(
+            res,
+            "    <dc:identifier opf:scheme=\"ISBN\">{}</dc:identifier>",
+            xml_escape(isbn)
+        );
+    }
+    if let Some(ref publisher) = info.publisher {
+        let _ = writeln!(
+            res,
+            "    <dc:publisher>{}</dc:publisher>",
+            xml_escape(publisher)
+        );
+    }
+    if let Some(ref date) = info.publication_date {
+        let _ = writeln!(res, "    <dc:date>{}</dc:date>", xml_escape(date));
+    }
+    if let Some(ref language) = info.language {
+        let _ = writeln!(
+            res,
+            "    <dc:language>{}</dc:language>",
+            xml_escape(language)
+        );
+    }
+    if let Some(ref series) = info.series {
+        let _ = writeln!(
+            res,
+            "    <meta name=\"calibre:series\" content=\"{}\"/>",
+            xml_escape(series)
+        );
+        if let Some(ref index) = info.series_index {
+            let _ = writeln!(
+                res,
+                "    <meta name=\"calibre:series_index\" content=\"{}\"/>",
+                xml_escape(index)
+            );
+        }
+    }
+    res.push_str("  </metadata>\n</package>\n");
+    res
+}
+
+/// Saves the cover of `id` as a JPEG next to the book at `path`.
+fn save_cover<T: kobodown::Transport, S: kobodown::Session>(
+    kobo: &mut Kobo<T>,
+    session: &mut S,
+    id: &kobodown::ProductId,
+    path: &Path,
+    pb: &ProgressBar,
+) -> Result<(), Report> {
+    let path = path.with_extension("jpg");
+    let mut f = TempFile::from_parts(File::create(&path)?, path);
+    if kobo.cover(session, id, &mut f)?.is_none() {
+        pb.suspend(|| eprintln!("{id} has no cover"));
+        return Ok(());
+    }
+    f.keep();
+    Ok(())
+}
+
+fn multi_progress(
+    len: usize,
+    quiet: bool,
+) -> Result<(MultiProgress, ProgressBar, ProgressBar), Report> {
+    let pb = if quiet {
+        MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
+    let file_pb = pb.add(ProgressBar::no_length().with_finish(indicatif::ProgressFinish::AndClear));
+    let global_pb = pb.add(
+        ProgressBar::new(len as u64 * 2)
+            .with_finish(indicatif::ProgressFinish::AndClear)
+            .with_style(global_style()),
+    );
+    pb.clear()?;
+    enable_tick(&global_pb);
+    Ok((pb, file_pb, global_pb))
+}
+
+/// Lists longer than this are narrowed down with a search before being shown.
+const FILTER_THRESHOLD: usize = 20;
+/// How many books the selection shows at once.
+const PAGE_SIZE: usize = 15;
+
+/// Lets the user toggle books on and off, `None` if the selection was cancelled.
+///
+/// Long lists are searched first: each round asks for a case-insensitive search term, shows the
+/// matching books with the ones already selected checked, and asks whether to keep searching.
+fn select_books(books: &[kobodown::Book]) -> Result<Option<Vec<usize>>, Report> {
+    let theme = dialoguer::theme::ColorfulTheme::default();
+    if books.len() <= FILTER_THRESHOLD {
+        return Ok(MultiSelect::with_theme(&theme)
+            .items(books)
+            .max_length(PAGE_SIZE)
+            .interact_opt()?);
+    }
+
+    let names = books
+        .iter()
+        .map(|book| book.to_string().to_lowercase())
+        .collect::<Vec<_>>();
+    let mut selected = vec![false; books.len()];
+    loop {
+        let search: String = Input::with_theme(&theme)
+            .with_prompt("Search (empty for all)")
+            .allow_empty(true)
+            .interact_text()?;
+        let search = search.trim().to_lowercase();
+        let matches = (0..books.len())
+            .filter(|&i| names[i].contains(&search))
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            eprintln!("No matching books");
+            continue;
+        }
+
+        let defaults = matches.iter().map(|&i| selected[i]).collect::<Vec<_>>();
+        let Some(chosen) = MultiSelect::with_theme(&theme)
+            .items(&matches.iter().map(|&i| &books[i]).collect::<Vec<_>>())
+            .defaults(&defaults)
+            .max_length(PAGE_SIZE)
+            .interact_opt()?
+        else {
+            return Ok(None);
+        };
+        for &i in &matches {
+            selected[i] = false;
+        }
+        for pos in chosen {
+            selected[matches[pos]] = true;
+        }
+
+        let count = selected.iter().filter(|&&s| s).count();
+        if !Confirm::with_theme(&theme)
+            .with_prompt(format!("{count} selected, search again?"))
+            .default(false)
+            .interact()?
+        {
+            break;
+        }
+    }
+    Ok(Some(
+        (0..books.len())
+            .filter(|&i| selected[i])
+            .collect::<Vec<_>>(),
+    ))
+}
+
+fn pick(
+    global: &Global,
+    Pick {
+        output_dir,
+        all,
+        full,
+        concurrency,
+        filter,
+        download,
+    }: Pick,
+) -> Result<(), Report> {
+    let mut config = global.config();
+    let mut kobo = global.kobo();
+    let mut books = book_list(global, &mut kobo, &mut config, all, full)?;
+    filter.apply(&mut books);
+    if books.is_empty() {
+        eprintln!("No matching books");
+        return Ok(());
+    }
+
+    let Some(mut selections) = select_books(&books)? else {
+        return Ok(());
+    };
+    selections.retain(|&i| {
+        let book = &books[i];
+        if book.is_audiobook {
+            eprintln!("Skipping {book}, audiobooks can't be downloaded");
+        }
+        !book.is_audiobook
+    });
+    match selections.len() {
+        0 => return Ok(()),
+        1 => {
+            let book = books.remove(selections[0]);
+            let output_file = download.output_name(
+                output_dir.as_deref(),
+                mkname(
+                    download.template.as_deref(),
+                    book.authors.as_deref(),
+                    &book.title,
+                    &book.revision_id,
+                ),
+                &HashSet::new(),
+            );
+            return get(
+                global,
+                Get {
+                    output_dir,
+                    output_file: Some(output_file),
+                    download,
+                    list_formats: false,
+                    title: None,
+                    series: None,
+                    from_temp: None,
+                    ids: vec![book.revision_id],
+                },
+            );
+        }
+        _ => (),
+    }
+    selections.sort_unstable();
+
+    // Names are picked upfront, concurrent downloads would not see each other's files.
+    let mut taken = HashSet::new();
+    let jobs = selections
+        .into_iter()
+        .flat_map(|i| books.get(i))
+        .map(|book| {
+            let file = download.output_name(
+                output_dir.as_deref(),
+                mkname(
+                    download.template.as_deref(),
+                    book.authors.as_deref(),
+                    &book.title,
+                    &book.revision_id,
+                ),
+                &taken,
+            );
+            taken.insert(file.clone());
+            (book, file)
+        })
+        .collect::<Vec<_>>();
+
+    let total = jobs.len();
+    let (pb, file_pb, global_pb) = multi_progress(total, download.quiet(global))?;
+    let queue = Mutex::new(jobs.into_iter());
+    let done = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    // Every worker has its own client, the session is shared so that token refreshes don't
+    // race, see `download_book`.
+    let session = Mutex::new(config);
+    let work = |kobo: &mut Kobo<DefaultAgent>, file_pb: &ProgressBar| {
+        while !failed.load(Ordering::Relaxed) {
+            let Some((book, file)) = queue.lock().next() else {
+                break;
+            };
+            let res = download_book(
+                kobo,
+                &session,
+                &book.revision_id,
+                output_dir.as_deref(),
+                &file,
+                &download,
+                file_pb,
+                DownloadProgress(Some(&global_pb)),
+            )
+            .and_then(|saved| {
+                let Some(path) = saved else {
+                    return Ok(());
+                };
+                save_extras(
+                    kobo,
+                    &session,
+                    &book.revision_id,
+                    &path,
+                    None,
+                    &download,
+                    file_pb,
+                )
+            });
+            if let Err(err) = res {
+                failed.store(true, Ordering::Relaxed);
+                return Err(err);
+            }
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            if global_pb.is_hidden() && !download.dry_run {
+                eprintln!("Downloaded {done} of {total}");
+            }
+        }
+        Ok(())
+    };
+
+    std::thread::scope(|scope| {
+        let work = &work;
+        let workers = (1..concurrency.get().min(total))
+            .map(|_| {
+                let file_pb = pb.insert_before(
+                    &global_pb,
+                    ProgressBar::no_length().with_finish(indicatif::ProgressFinish::AndClear),
+                );
+                scope.spawn(move || work(&mut global.kobo(), &file_pb))
+            })
+            .collect::<Vec<_>>();
+        let res = work(&mut kobo, &file_pb);
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .fold(res, Result::and)
+    })
+}
+
+/// Bumped whenever [`kobodown::Book`] gains fields, older caches would lack them, or when older
+/// caches may hold books that should be gone.
+const LIBRARY_CACHE_VERSION: u32 = 4;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct LibraryCache {
+    #[serde(default)]
+    version: u32,
+    token: Box<str>,
+    books: Vec<kobodown::Book>,
+}
+
+fn library_cache_path(profile: Option<&str>, all: bool) -> Option<PathBuf> {
+    let dirs = directories::ProjectDirs::from("dev", "shurizzle", "kobodown")?;
+    let mut name = "library".to_string();
+    if let Some(profile) = profile {
+        name.push('.');
+        name.push_str(profile);
+    }
+    if all {
+        name.push_str(".all");
+    }
+    name.push_str(".json");
+    Some(dirs.cache_dir().join(name))
+}
+
+fn save_library_cache(path: &Path, cache: &LibraryCache) -> Result<(), Report> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut w = std::io::BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut w, cache)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Warns about the sync pages that failed, keeping the books that could be read.
+fn partial_books<E: std::error::Error + Send + Sync, S: std::fmt::Display + Send + Sync>(
+    list: kobodown::BookList<E, S>,
+) -> Vec<kobodown::Book> {
+    for err in &list.errors {
+        tracing::warn!("{err}, the book list is incomplete");
+    }
+    list.books
+}
+
+/// Lists the books, only asking for what changed since the cached list unless `full`.
+fn book_list(
+    global: &Global,
+    kobo: &mut Kobo<DefaultAgent>,
+    config: &mut Config,
+    all: bool,
+    full: bool,
+) -> Result<Vec<kobodown::Book>, Report> {
+    use kobodown::Session;
+
+    let path = library_cache_path(global.profile.as_deref(), all);
+    let cache = path
+        .as_deref()
+        .filter(|_| !full)
+        .and_then(|path| File::open(path).ok())
+        .and_then(|f| serde_json::from_reader::<_, LibraryCache>(std::io::BufReader::new(f)).ok())
+        .filter(|cache| {
+            cache.version == LIBRARY_CACHE_VERSION
+                && config.library_sync_token().map(|t| &**t) == Some(&*cache.token)
+        });
+    let books = if let Some(LibraryCache { mut books, .. }) = cache {
+        let changes = kobo.book_list_changes(&mut *config, all)?;
+        books.retain(|book| !changes.removed.contains(&book.revision_id));
+        let changes = partial_books(changes);
+        books.retain(|book| !changes.iter().any(|c| c.revision_id == book.revision_id));
+        books.extend(changes);
+        books.sort_by(|a, b| a.title.cmp(&b.title));
+        books
+    } else {
+        partial_books(kobo.book_list(&mut *config, all)?)
+    };
+
+    let (Some(path), Some(token)) = (path, config.library_sync_token()) else {
+        return Ok(books);
+    };
+    let cache = LibraryCache {
+        version: LIBRARY_CACHE_VERSION,
+        token: token.to_boxed_str(),
+        books,
+    };
+    if let Err(err) = save_library_cache(&path, &cache) {
+        tracing::warn!("cannot save the library cache: {err}");
+    }
+    Ok(cache.books)
+}
+
+fn list(
+    global: &Global,
+    List {
+        all,
+        full,
+        json,
+        table,
+        filter,
+    }: List,
+) -> Result<(), Report> {
+    let mut config = global.config();
+    let mut kobo = global.kobo();
+
+    let mut books = book_list(global, &mut kobo, &mut config, all, full)?;
+    filter.apply(&mut books);
+    if all {
+        if books.is_empty() {
+            eprintln!("No matching books");
+        }
+    } else if books.is_empty() {
+        eprintln!("No matching unfinished books, use --all for the whole library");
+    } else {
+        eprintln!(
+            "Showing {} unfinished books, use --all for the whole library",
+            books.len()
+        );
+    }
+    if json {
+        let mut w = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut w, &books)?;
+        writeln!(w)?;
+    } else if table {
+        print_table(&books);
+    } else {
+        for book in books {
+            println!("{} - {book}", book.revision_id);
+        }
+    }
+    Ok(())
+}
+
+/// Prints `books` in aligned columns, truncating titles and authors to fit the terminal.
+fn print_table(books: &[kobodown::Book]) {
+    use console::{measure_text_width, pad_str, truncate_str, Alignment};
+
+    const ID_WIDTH: usize = 8;
+    const MIN_WIDTH: usize = 10;
+    const HEADERS: [&str; 4] = ["ID", "TITLE", "AUTHORS", "STATUS"];
+
+    let status = |book: &kobodown::Book| match (book.is_archived, book.is_audiobook) {
+        (true, true) => "archived audiobook",
+        (true, false) => "archived",
+        (false, true) => "audiobook",
+        (false, false) => "",
+    };
+    let widest = |header: &str, f: &dyn Fn(&kobodown::Book) -> usize| {
+        books
+            .iter()
+            .map(f)
+            .fold(measure_text_width(header), usize::max)
+    };
+    let status_width = widest(HEADERS[3], &|book| status(book).len());
+    let mut title_width = widest(HEADERS[1], &|book| measure_text_width(&book.title));
+    let mut authors_width = widest(HEADERS[2], &|book| {
+        book.authors.as_deref().map_or(0, measure_text_width)
+    });
+
+    // Titles give way first, authors keep at least a third of the room left.
+    if let Some((_, columns)) = console::Term::stdout().size_checked() {
+        let room = (columns as usize)
+            .saturating_sub(ID_WIDTH + status_width + 3 * 2)
+            .max(2 * MIN_WIDTH);
+        if title_width + authors_width > room {
+            authors_width = authors_width.min((room / 3).max(room.saturating_sub(title_width)));
+            title_width = room - authors_width;
+        }
+    }
+
+    let row = |id: &str, title: &str, authors: &str, status: &str| {
+        let cells = [
+            pad_str(id, ID_WIDTH, Alignment::Left, None),
+            pad_str(title, title_width, Alignment::Left, Some("…")),
+            pad_str(authors, authors_width, Alignment::Left, Some("…")),
+            status.into(),
+        ];
+        println!("{}", cells.join("  ").trim_end());
+    };
+    row(HEADERS[0], HEADERS[1], HEADERS[2], HEADERS[3]);
+    for book in books {
+        row(
+            &truncate_str(&book.revision_id, ID_WIDTH, ""),
+            &book.title,
+            book.authors.as_deref().unwrap_or(""),
+            status(book),
+        );
+    }
+}
+
+fn show_config(global: &Global, ShowConfig { show }: ShowConfig) -> Result<(), Report> {
+    use kobodown::Session;
+
+    let config = global.config();
+    let path = std::path::absolute(config.path())?;
+    if path.exists() {
+        println!("{}", path.display());
+    } else {
+        println!("{} (not created yet)", path.display());
+    }
+    if !show {
+        return Ok(());
+    }
+
+    let value = |v: Option<&kobodown::NonEmptyStr>| v.map_or("not set", |v| &**v).to_string();
+    let secret = |v: Option<&kobodown::NonEmptyStr>| v.map_or("not set", |_| "set (hidden)");
+    println!("DeviceId:     {}", value(config.device_id()));
+    println!("UserId:       {}", value(config.user_id()));
+    println!("UserKey:      {}", secret(config.user_key()));
+    println!("AccessToken:  {}", secret(config.access_token()));
+    println!("RefreshToken: {}", secret(config.refresh_token()));
+    #[cfg(feature = "keyring")]
+    println!(
+        "Tokens in:    {}",
+        if config.uses_keyring() {
+            "keyring"
+        } else {
+            "configuration file"
+        }
+    );
+    Ok(())
+}
+
+/// `name.drm.zip` without the suffix added by --raw, other names without their extension.
+fn raw_stem(input: &Path) -> std::ffi::OsString {
+    match input.to_str().and_then(|s| s.strip_suffix(".drm.zip")) {
+        Some(stem) => stem.into(),
+        None => input.with_extension("").into_os_string(),
+    }
+}
+
+fn decrypt(
+    global: &Global,
+    Decrypt {
+        input,
+        keys,
+        output,
+        jobs,
+        compression,
+        copy_unencrypted,
+        no_validate,
+        clean_kepub,
+    }: Decrypt,
+) -> Result<(), Report> {
+    let with_suffix = |suffix: &str| {
+        let mut path = raw_stem(&input);
+        path.push(suffix);
+        PathBuf::from(path)
     };
+    let keys = load_keys(&keys.unwrap_or_else(|| with_suffix(".keys.json")))?;
+    let output = output.unwrap_or_else(|| with_suffix(".epub"));
+    if output == input {
+        color_eyre::eyre::bail!("The output would replace the input, pass --output");
+    }
 
-    let desc = kobo.access_book(&mut settings, &id)?;
-    let pb = default_bar(None);
-    download_zip(
-        &mut kobo,
-        &mut settings,
-        desc,
-        output_dir.as_deref(),
-        output_file,
-        &pb,
-        DownloadProgress(None),
-    )?;
+    let mut reader = std::io::BufReader::new(File::open(&input)?);
+    let names = ZipArchive::new(&mut reader)?
+        .file_names()
+        .map(Box::<str>::from)
+        .collect::<HashSet<_>>();
+    for name in keys.keys().filter(|name| !names.contains(*name)) {
+        eprintln!("No entry {name} for its key, the keys may be of another book");
+    }
+    let mut unmatched = names
+        .iter()
+        .map(|name| &**name)
+        .filter(|&name| {
+            !keys.contains_key(name) && name != "mimetype" && !name.starts_with("META-INF/")
+        })
+        .collect::<Vec<_>>();
+    unmatched.sort_unstable();
+    for name in unmatched {
+        eprintln!("No key for {name}, copied as it is");
+    }
+
+    let pb = default_bar(global.quiet());
+    pb.set_style(decrypt_style());
+    set_message(&pb, format!("Decrypting {}...", input.display()));
+    let options = kobodown::DecryptOptions {
+        compression: compression.unwrap_or_default(),
+        copy_unencrypted,
+        jobs: jobs
+            .or_else(|| std::thread::available_parallelism().ok())
+            .unwrap_or(NonZeroUsize::MIN),
+        validate: !no_validate,
+        clean_kepub,
+    };
+    let mut f = TempFile::from_parts(File::create(&output)?, output.clone());
+    reader.rewind()?;
+    kobodown::decrypt_book_with_progress(&keys, reader, &mut f, &options, |done, total| {
+        pb.update(|ps| {
+            ps.set_len(total as u64);
+            ps.set_pos(done as u64);
+        })
+    })?;
+    f.keep();
+    pb.finish_and_clear();
+    println!("{}", output.display());
+    Ok(())
+}
+
+fn status(global: &Global) -> Result<(), Report> {
+    use kobodown::Session;
+
+    let config = global.config();
+    println!("Status:   {}", kobodown::LoginStatus::of(&config));
+    println!(
+        "DeviceId: {}",
+        config.device_id().map_or("not set", |v| &**v)
+    );
     Ok(())
 }
 
-fn pick(Pick { output_dir, all }: Pick) -> Result<(), Report> {
-    let mut config = Config::load();
-    let mut kobo = Kobo::default();
-    let mut books = kobo.book_list(&mut config, all)?;
+fn devices(global: &Global, Devices { forget }: Devices) -> Result<(), Report> {
+    use kobodown::Session;
 
-    let selections = MultiSelect::with_theme(&dialoguer::theme::ColorfulTheme::default())
-        .items(&books)
-        .interact_opt()?;
-    let Some(mut selections) = selections else {
+    let mut config = global.config();
+    let Some(device_id) = config.device_id().map(|id| id.to_string()) else {
+        eprintln!("No device is registered for this profile");
         return Ok(());
     };
-    match selections.len() {
-        0 => return Ok(()),
-        1 => {
-            let book = books.remove(selections[0]);
-            return get(Get {
-                output_dir,
-                output_file: Some(mkname(book.authors.as_deref(), &book.title).into()),
-                id: book.revision_id,
-            });
-        }
-        _ => (),
+    println!("{device_id}");
+    if forget {
+        config.remove_access_token();
+        config.remove_refresh_token();
+        config.remove_user_id();
+        config.remove_user_key();
+        config.remove_device_id();
+        config.save()?;
+        eprintln!(
+            "Forgot device {device_id}, remove it from the Kobo account settings to free its slot"
+        );
     }
-    selections.sort_unstable();
+    Ok(())
+}
 
-    let pb = MultiProgress::new();
-    let file_pb = pb.add(ProgressBar::no_length().with_finish(indicatif::ProgressFinish::AndClear));
-    let global_pb = pb.add(
-        ProgressBar::new(selections.len() as u64 * 2)
-            .with_finish(indicatif::ProgressFinish::AndClear)
-            .with_style(global_style()),
-    );
-    pb.clear()?;
-    global_pb.enable_steady_tick(DEFAULT_TICK);
+fn wishlist(global: &Global, Wishlist { json }: Wishlist) -> Result<(), Report> {
+    let mut config = global.config();
+    let mut kobo = global.kobo();
 
-    for book in selections.into_iter().flat_map(|i| books.get(i)) {
-        let desc = kobo.access_book(&mut config, &book.revision_id)?;
-        let file = mkname(book.authors.as_deref(), &book.title);
-        download_zip(
-            &mut kobo,
-            &mut config,
-            desc,
-            output_dir.as_deref(),
-            PathBuf::from(file),
-            &file_pb,
-            DownloadProgress(Some(&global_pb)),
-        )?;
+    let items = kobo.wishlist(&mut config)?;
+    if items.is_empty() {
+        eprintln!("Wishlist is empty");
+    }
+    if json {
+        let mut w = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut w, &items)?;
+        writeln!(w)?;
+    } else {
+        for item in items {
+            println!("{} - {item}", item.product_id);
+        }
+    }
+    Ok(())
+}
+
+fn epub_files(path: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            epub_files(&entry?.path(), files)?;
+        }
+    } else if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("epub"))
+    {
+        files.push(path.into());
     }
     Ok(())
 }
 
-fn list(List { all }: List) -> Result<(), Report> {
-    let mut config = Config::load();
-    let mut kobo = Kobo::default();
+/// Entries that start with a tag once decrypted.
+const MARKUP_EXTENSIONS: [&str; 6] = ["xhtml", "html", "htm", "xml", "opf", "ncx"];
+
+fn looks_like_markup(head: &[u8]) -> bool {
+    if head.starts_with(b"\xff\xfe") || head.starts_with(b"\xfe\xff") {
+        return true;
+    }
+    let head = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    head.iter()
+        .find(|c| !c.is_ascii_whitespace())
+        .is_none_or(|&c| c == b'<')
+}
+
+/// Checks that `path` is a readable EPUB without entries left encrypted.
+fn verify_epub(path: &Path) -> Result<(), Report> {
+    let mut zip = ZipArchive::new(File::open(path)?)?;
+    for name in ["mimetype", "META-INF/container.xml"] {
+        if zip.index_for_name(name).is_none() {
+            color_eyre::eyre::bail!("missing {name}");
+        }
+    }
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let markup = Path::new(entry.name())
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                MARKUP_EXTENSIONS
+                    .iter()
+                    .any(|m| ext.eq_ignore_ascii_case(m))
+            });
+        let mut head = Vec::with_capacity(64);
+        (&mut entry).take(64).read_to_end(&mut head)?;
+        if markup && !looks_like_markup(&head) {
+            color_eyre::eyre::bail!("{} looks encrypted", entry.name());
+        }
+        // Reading to the end checks the CRC.
+        std::io::copy(&mut entry, &mut std::io::sink())?;
+    }
+    Ok(())
+}
 
-    for book in kobo.book_list(&mut config, all)? {
-        println!("{} - {book}", book.revision_id);
+fn verify(Verify { dir }: Verify) -> Result<(), Report> {
+    let mut files = Vec::new();
+    epub_files(&dir, &mut files)?;
+    files.sort();
+    if files.is_empty() {
+        eprintln!("No EPUB files found");
+    }
+    let mut failed = 0;
+    for path in &files {
+        match verify_epub(path) {
+            Ok(()) => println!("OK   {}", path.display()),
+            Err(err) => {
+                failed += 1;
+                println!("FAIL {}: {err}", path.display());
+            }
+        }
+    }
+    if failed > 0 {
+        color_eyre::eyre::bail!("{failed} of {} files failed verification", files.len());
     }
     Ok(())
 }
 
+/// Accepts a book id, surrounded by blanks or inside a url too.
+fn book_id_parser(raw: &str) -> Result<kobodown::ProductId, String> {
+    let id = raw.trim();
+    match url::Url::parse(id) {
+        Ok(url) => url
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .map(Cow::Borrowed)
+            .chain(url.query_pairs().map(|(_, v)| v))
+            .find_map(|s| s.parse().ok())
+            .ok_or_else(|| format!("no book id found in {id}")),
+        Err(_) => id.parse().map_err(|_| {
+            format!(
+                "invalid book id {raw:?}, expected a revision id like the ones printed by `list`"
+            )
+        }),
+    }
+}
+
+fn device_id_parser(raw: &str) -> Result<Box<str>, String> {
+    uuid::Uuid::parse_str(raw.trim())
+        .map(|id| id.hyphenated().to_string().into_boxed_str())
+        .map_err(|err| format!("invalid device id: {err}"))
+}
+
 fn password_parser(raw: &str) -> Result<Zeroizing<Box<str>>, Infallible> {
     Ok(Zeroizing::new(raw.to_string().into_boxed_str()))
 }
 
-fn main() -> Result<(), Report> {
+fn profile_parser(raw: &str) -> Result<Box<str>, &'static str> {
+    if raw.is_empty() {
+        Err("profile name cannot be empty")
+    } else if !raw
+        .bytes()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_'))
+    {
+        Err("profile name can only contain letters, digits, '-' and '_'")
+    } else {
+        Ok(raw.into())
+    }
+}
+
+fn locale_parser(raw: &str) -> Result<Box<str>, &'static str> {
+    if kobodown::is_locale(raw) {
+        Ok(raw.to_ascii_lowercase().into())
+    } else {
+        Err("expected a region and a language separated by '/', e.g. `ww/en`")
+    }
+}
+
+fn header_parser(raw: &str) -> Result<(::http::HeaderName, ::http::HeaderValue), String> {
+    let (name, value) = raw
+        .split_once(':')
+        .ok_or("expected a header like `Name: value`")?;
+    let name = ::http::HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name {:?}", name.trim()))?;
+    let value = ::http::HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid value for header {name}"))?;
+    Ok((name, value))
+}
+
+fn main() -> std::process::ExitCode {
+    let Err(err) = run() else {
+        return std::process::ExitCode::SUCCESS;
+    };
+    let exit = Exit::of(&err);
+    match exit {
+        Exit::Failure => eprintln!("Error: {err:?}"),
+        Exit::Cancelled => eprintln!("Cancelled"),
+        // Expected failures don't need a backtrace.
+        _ => eprintln!("Error: {err:#}"),
+    }
+    std::process::ExitCode::from(exit as u8)
+}
+
+fn run() -> Result<(), Report> {
     #[cfg(debug_assertions)]
     {
         use tracing_error::ErrorLayer;
@@ -277,16 +2049,36 @@ fn main() -> Result<(), Report> {
     }
     color_eyre::install()?;
 
-    match Cli::parse() {
-        Cli::Login(args) => login(args),
-        Cli::Get(args) => get(args),
-        Cli::List(args) => list(args),
-        Cli::Pick(args) => pick(args),
-    }?;
-    Ok(())
+    let Cli { global, command } = Cli::parse();
+    if global.insecure {
+        eprintln!(
+            "WARNING: TLS certificate verification is disabled, anyone on the network can read \
+             and tamper with the traffic, credentials included"
+        );
+    }
+    match command {
+        Command::Login(args) => login(&global, args),
+        Command::Get(args) => get(&global, args),
+        Command::List(args) => list(&global, args),
+        Command::Pick(args) => pick(&global, args),
+        Command::Wishlist(args) => wishlist(&global, args),
+        Command::Config(args) => show_config(&global, args),
+        Command::Verify(args) => verify(args),
+        Command::Status => status(&global),
+        Command::Decrypt(args) => decrypt(&global, args),
+        Command::Devices(args) => devices(&global, args),
+    }
+    .map_err(|err| match err.downcast_ref::<KoboError>() {
+        Some(kobodown::Error::SessionExpired) => {
+            err.wrap_err("Your session has expired, please run `login` again")
+        }
+        _ => err,
+    })
 }
 
 const DEFAULT_TICK: Duration = Duration::from_millis(100);
+/// Download progress more frequent than this would never be drawn.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(50);
 
 fn myperc(s: &ProgressState, w: &mut dyn core::fmt::Write) {
     write!(w, "{:.*}%", 1, s.fraction() * 100f32).unwrap();
@@ -320,15 +2112,31 @@ fn global_style() -> ProgressStyle {
     bar_style(TEMPLATE)
 }
 
-fn default_bar(length: Option<u64>) -> ProgressBar {
-    let pb = length
-        .map(ProgressBar::new)
-        .unwrap_or_else(ProgressBar::no_length)
-        .with_finish(indicatif::ProgressFinish::AndClear);
-    pb.enable_steady_tick(DEFAULT_TICK);
+fn default_bar(quiet: bool) -> ProgressBar {
+    let pb = if quiet {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::no_length()
+    }
+    .with_finish(indicatif::ProgressFinish::AndClear);
+    enable_tick(&pb);
     pb
 }
 
+fn enable_tick(pb: &ProgressBar) {
+    if !pb.is_hidden() {
+        pb.enable_steady_tick(DEFAULT_TICK);
+    }
+}
+
+/// Shows `msg` on the bar, or prints it when the bar is hidden.
+fn set_message(pb: &ProgressBar, msg: String) {
+    if pb.is_hidden() {
+        eprintln!("{msg}");
+    }
+    pb.set_message(msg);
+}
+
 #[derive(Debug)]
 pub struct TempFile {
     key: usize,
@@ -410,17 +2218,33 @@ impl Read for TempFile {
 mod tmp {
     use std::{
         collections::HashMap,
-        ffi::OsStr,
         fs::File,
         mem::ManuallyDrop,
         path::{Path, PathBuf},
-        sync::LazyLock,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, LazyLock,
+        },
     };
 
     use color_eyre::eyre::Report;
     use parking_lot::Mutex;
 
+    /// Set on the first interruption while downloading, the clients stop their downloads then.
+    pub static CANCEL: LazyLock<Arc<AtomicBool>> = LazyLock::new(Default::default);
+    static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Runs the download `f`, letting an interruption cancel it instead of exiting.
+    pub fn downloading<R>(f: impl FnOnce() -> R) -> R {
+        DOWNLOADS.fetch_add(1, Ordering::SeqCst);
+        let res = f();
+        DOWNLOADS.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+
     static CLEANUP_FILES: LazyLock<Mutex<Cleanup>> = LazyLock::new(|| {
+        // The `termination` feature of ctrlc extends this to SIGTERM and SIGHUP, and to the
+        // console being closed on Windows.
         ctrlc::set_handler(destroy).unwrap();
 
         Mutex::new(Cleanup {
@@ -430,6 +2254,10 @@ mod tmp {
     });
 
     fn destroy() {
+        // The downloads stop and clean up by themselves, a second interruption exits right away.
+        if DOWNLOADS.load(Ordering::SeqCst) != 0 && !CANCEL.swap(true, Ordering::SeqCst) {
+            return;
+        }
         unsafe { &mut *CLEANUP_FILES.data_ptr() }.finalize();
         std::process::exit(130);
     }
@@ -443,7 +2271,7 @@ mod tmp {
         pub fn insert(&mut self, path: PathBuf) -> usize {
             loop {
                 let index = self.index;
-                self.index += self.index.wrapping_add(1);
+                self.index = self.index.wrapping_add(1);
                 match self.files.entry(index) {
                     std::collections::hash_map::Entry::Occupied(_) => (),
                     std::collections::hash_map::Entry::Vacant(vacant_entry) => {
@@ -470,12 +2298,18 @@ mod tmp {
     }
 
     impl super::TempFile {
-        pub fn with_prefix_in<S: AsRef<OsStr>, P: AsRef<Path>>(
-            suffix: S,
-            dir: P,
-        ) -> Result<super::TempFile, Report> {
-            let (f, path) = tempfile::NamedTempFile::with_prefix_in(suffix, dir)?.keep()?;
-            Ok(Self::from_parts(f, path))
+        /// Opens (or creates) the file at `path` without truncating it, returning it along with
+        /// its current length so that an interrupted download can pick up where it left off.
+        pub fn resume<P: Into<PathBuf>>(path: P) -> Result<(super::TempFile, u64), Report> {
+            let path = path.into();
+            let f = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            let len = f.metadata()?.len();
+            Ok((Self::from_parts(f, path), len))
         }
 
         pub fn from_parts(f: File, path: PathBuf) -> Self {
@@ -494,6 +2328,37 @@ mod tmp {
             CLEANUP_FILES.lock().ignore(key);
             inner
         }
+
+        pub fn persist<P: AsRef<Path>>(self, path: P) -> Result<File, Report> {
+            let me = ManuallyDrop::new(self);
+            let key = me.key;
+            let inner = unsafe { core::ptr::read(&me.inner).unwrap() };
+            let mut cleanup = CLEANUP_FILES.lock();
+            let from = cleanup.files[&key].clone();
+            match std::fs::rename(&from, path.as_ref()) {
+                Ok(()) => {
+                    cleanup.ignore(key);
+                    Ok(inner)
+                }
+                // The temporary directory can be on another filesystem.
+                Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+                    drop(cleanup);
+                    drop(inner);
+                    let res = std::fs::copy(&from, path.as_ref())
+                        .and_then(|_| File::options().read(true).write(true).open(path.as_ref()));
+                    if res.is_err() {
+                        _ = std::fs::remove_file(path.as_ref());
+                    }
+                    CLEANUP_FILES.lock().remove(key);
+                    Ok(res?)
+                }
+                Err(err) => {
+                    drop(inner);
+                    cleanup.remove(key);
+                    Err(err.into())
+                }
+            }
+        }
     }
 
     impl Drop for super::TempFile {
@@ -502,6 +2367,76 @@ mod tmp {
             CLEANUP_FILES.lock().remove(self.key);
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{collections::HashSet, sync::Barrier};
+
+        use super::*;
+
+        #[test]
+        fn concurrent_temp_files() {
+            const THREADS: usize = 8;
+            const FILES: usize = 50;
+
+            let dir = tempfile::tempdir().unwrap();
+            let barrier = Barrier::new(THREADS);
+            let keys = Mutex::new(HashSet::new());
+            let used = Mutex::new(Vec::new());
+            std::thread::scope(|scope| {
+                for t in 0..THREADS {
+                    let (dir, barrier, keys, used) = (dir.path(), &barrier, &keys, &used);
+                    scope.spawn(move || {
+                        for i in 0..FILES {
+                            let path = dir.join(format!("{t}-{i}"));
+                            let f = super::super::TempFile::from_parts(
+                                File::create(&path).unwrap(),
+                                path.clone(),
+                            );
+                            assert!(keys.lock().insert(f.key), "key {} reused", f.key);
+                            used.lock().push(f.key);
+                            // Every thread holds a file while the others insert theirs.
+                            if i == FILES / 2 {
+                                barrier.wait();
+                            }
+                            let key = f.key;
+                            if i % 2 == 0 {
+                                drop(f);
+                                assert!(!path.exists());
+                            } else {
+                                drop(f.keep());
+                                assert!(path.exists());
+                            }
+                            keys.lock().remove(&key);
+                        }
+                    });
+                }
+            });
+            assert!(keys.into_inner().is_empty());
+            let cleanup = CLEANUP_FILES.lock();
+            assert!(used
+                .into_inner()
+                .iter()
+                .all(|key| !cleanup.files.contains_key(key)));
+            assert_eq!(
+                std::fs::read_dir(dir.path()).unwrap().count(),
+                THREADS * FILES / 2
+            );
+        }
+
+        #[test]
+        fn insert_skips_taken_keys() {
+            let mut cleanup = Cleanup {
+                index: usize::MAX,
+                files: HashMap::from([(0, PathBuf::from("taken"))]),
+            };
+            assert_eq!(cleanup.insert("a".into()), usize::MAX);
+            assert_eq!(cleanup.insert("b".into()), 1);
+            assert_eq!(cleanup.insert("c".into()), 2);
+            assert_eq!(cleanup.ignore(usize::MAX), PathBuf::from("a"));
+            assert_eq!(cleanup.files.len(), 3);
+        }
+    }
 }
 
 pub struct DownloadProgress<'a>(Option<&'a ProgressBar>);
@@ -537,6 +2472,7 @@ impl Drop for DecryptProgress<'_> {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn download_zip<T, S, P1, P2>(
     kobo: &mut Kobo<T>,
     session: &mut S,
@@ -547,123 +2483,519 @@ fn download_zip<T, S, P1, P2>(
     }: kobodown::AccessBook,
     dir: Option<P1>,
     name: P2,
+    options: &DownloadOptions,
     pb: &ProgressBar,
     progress: DownloadProgress<'_>,
-) -> Result<(), Report>
+) -> Result<Option<PathBuf>, Report>
 where
     T: kobodown::Transport,
     S: kobodown::Session,
     P1: AsRef<Path>,
     P2: AsRef<Path>,
 {
+    let encrypted = content_keys.is_some();
+    // Cleaning books without DRM goes through decrypting with no keys.
+    let content_keys = content_keys
+        .or_else(|| (options.clean_kepub && !options.raw).then(kobodown::ContentKeys::default));
+    let mut path = if let Some(dir) = dir.as_ref() {
+        dir.as_ref().join(name.as_ref())
+    } else {
+        name.as_ref().into()
+    };
+    let raw_target = |path: &Path| {
+        if content_keys.is_some() && options.raw {
+            path.with_extension("drm.zip")
+        } else {
+            path.to_path_buf()
+        }
+    };
+    let mut target = raw_target(&path);
+    // With --keep-name the name is only known once the download is over.
+    if !options.keep_name && !options.may_write(&target, pb)? {
+        return Ok(None);
+    }
+    if options.dry_run {
+        pb.suspend(|| println!("{} ({})", target.display(), HumanBytes(size)));
+        return Ok(Some(path));
+    }
+
     pb.disable_steady_tick();
     pb.reset();
-    pb.set_message(format!("Downloading {}...", name.as_ref().display()));
+    set_message(pb, format!("Downloading {}...", name.as_ref().display()));
     pb.set_style(download_style());
-
-    if let Some(content_keys) = content_keys {
-        pb.update(|ps| {
-            ps.set_len(size * 2);
-            ps.set_pos(0);
-        });
-        pb.enable_steady_tick(DEFAULT_TICK);
-
-        let mut tmp = if let Some(dir) = dir.as_ref() {
-            std::fs::create_dir_all(dir)?;
-            TempFile::with_prefix_in(name.as_ref(), dir)
+    pb.update(|ps| {
+        ps.set_len(if content_keys.is_some() && !options.raw {
+            size * 2
         } else {
-            TempFile::with_prefix_in(name.as_ref(), ".")
-        }?;
-        tmp.set_len(size)?;
+            size
+        });
+        ps.set_pos(0);
+    });
+    enable_tick(pb);
 
-        kobo.download(session, &url, pb.wrap_write(&mut tmp))?;
+    let temp_dir = options.temp_dir();
+    let partial = match temp_dir {
+        Some(ref dir) => dir.join(partial_path(&path).file_name().unwrap_or_default()),
+        None => partial_path(&path),
+    };
+    if !options.no_space_check {
+        let decrypt = content_keys.is_some() && !options.raw;
+        check_space(&path, &partial, size, decrypt)?;
+    }
+    // The name can add subdirectories.
+    if let Some(dir) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    if let Some(dir) = temp_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    if let Some(ref from) = options.from_temp {
+        let mut input = File::open(from)?;
+        let len = input.metadata()?.len();
+        if len != size {
+            return Err(kobodown::Error::<T::Error, S::Error>::SizeMismatch {
+                expected: size,
+                actual: len,
+            }
+            .into());
+        }
         let _progress = progress.step();
-        tmp.seek(std::io::SeekFrom::Start(0))?;
-        let path = if let Some(dir) = dir.as_ref() {
-            dir.as_ref().join(name.as_ref())
-        } else {
-            name.as_ref().into()
-        };
-        let f = File::create(&path)?;
-        f.set_len(size)?;
-        let mut f = TempFile::from_parts(f, path);
+        match content_keys {
+            Some(content_keys) => {
+                if options.dump_keys && encrypted {
+                    dump_keys(&path.with_extension("keys.json"), &content_keys)?;
+                }
+                decrypt_to(&content_keys, &mut input, &path, size, name, options, pb)?;
+            }
+            None => {
+                std::fs::copy(from, &path)?;
+            }
+        }
+        return Ok(Some(path));
+    }
+    let (mut tmp, mut offset) = TempFile::resume(&partial)?;
+    let mut file_name = None;
+    if offset > size {
+        tmp.set_len(0)?;
+        offset = 0;
+    }
+    if offset < size {
+        tmp.seek(std::io::SeekFrom::Start(offset))?;
+        pb.set_position(offset);
+        match tmp::downloading(|| {
+            kobo.download_with_name(
+                &mut *session,
+                &url,
+                Some(offset),
+                Some(size),
+                &mut tmp,
+                |position, _| pb.set_position(position),
+            )
+        }) {
+            Ok(downloaded) => file_name = downloaded.file_name,
+            // Keep what we got so far, the next run will resume from there.
+            Err(
+                err @ (kobodown::Error::Transport(_)
+                | kobodown::Error::Connection(_)
+                | kobodown::Error::Tls(_)
+                | kobodown::Error::Timeout
+                | kobodown::Error::Cancelled),
+            ) => {
+                tmp.keep();
+                return Err(err.into());
+            }
+            Err(err) => return Err(err.into()),
+        }
+        let len = tmp.stream_position()?;
+        tmp.set_len(len)?;
+        if len != size {
+            // The partial file is dropped here, resuming from a corrupted download makes no sense.
+            return Err(kobodown::Error::<T::Error, S::Error>::SizeMismatch {
+                expected: size,
+                actual: len,
+            }
+            .into());
+        }
+    }
+    let _progress = progress.step();
+
+    if options.keep_name {
+        // Resuming a complete download asks nothing to Kobo, the generated name is kept then.
+        if let Some(name) = file_name {
+            path.set_file_name(sanitize_filename::sanitize(&*name));
+            target = raw_target(&path);
+            if !options.may_write(&target, pb)? {
+                return Ok(None);
+            }
+        }
+    }
 
-        decrypt_zip(&content_keys, &mut tmp, &mut f, name, pb)?;
-        f.keep();
+    if let Some(content_keys) = content_keys {
+        if options.dump_keys && encrypted {
+            dump_keys(&path.with_extension("keys.json"), &content_keys)?;
+        }
+        if options.raw {
+            tmp.persist(target)?;
+            return Ok(Some(path));
+        }
+
+        tmp.seek(std::io::SeekFrom::Start(0))?;
+        let res = decrypt_to(&content_keys, &mut tmp, &path, size, name, options, pb);
+        if res.is_err() && options.keep_temp {
+            tmp.keep();
+            pb.suspend(|| {
+                eprintln!(
+                    "The download is kept in {}, retry with --from-temp",
+                    partial.display()
+                )
+            });
+        }
+        res?;
     } else {
-        pb.update(|ps| {
-            ps.set_len(size);
-            ps.set_pos(0);
-        });
-        pb.enable_steady_tick(DEFAULT_TICK);
+        tmp.persist(&path)?;
+    }
+    Ok(Some(path))
+}
 
-        let path = if let Some(dir) = dir.as_ref() {
-            dir.as_ref().join(name)
-        } else {
-            name.as_ref().into()
+/// Decrypts `input` into a new file at `path`, removed again if anything goes wrong.
+fn decrypt_to<R: Read + Seek, P: AsRef<Path>>(
+    keys: &kobodown::ContentKeys,
+    input: &mut R,
+    path: &Path,
+    size: u64,
+    name: P,
+    options: &DownloadOptions,
+    pb: &ProgressBar,
+) -> Result<(), Report> {
+    let f = File::create(path)?;
+    f.set_len(size)?;
+    let mut f = TempFile::from_parts(f, path.to_path_buf());
+
+    decrypt_zip(keys, input, &mut f, name, options, pb)?;
+    // The repacked book can be smaller than the download it was preallocated for.
+    let len = f.stream_position()?;
+    f.set_len(len)?;
+    f.keep();
+    Ok(())
+}
+
+/// Reads the keys written by [`dump_keys`].
+fn load_keys(path: &Path) -> Result<kobodown::ContentKeys, Report> {
+    use base64::Engine;
+
+    let keys: BTreeMap<Box<str>, String> =
+        serde_json::from_reader(std::io::BufReader::new(File::open(path)?))?;
+    keys.into_iter()
+        .map(|(name, key)| {
+            let key = base64::prelude::BASE64_STANDARD
+                .decode(key.trim())
+                .map_err(|err| color_eyre::eyre::eyre!("Invalid key for {name}: {err}"))?;
+            if key.len() != 16 {
+                color_eyre::eyre::bail!("Invalid key for {name}: {} bytes, expected 16", key.len());
+            }
+            Ok((name, *aes::cipher::Key::<aes::Aes128Dec>::from_slice(&key)))
+        })
+        .collect()
+}
+
+fn dump_keys(path: &Path, keys: &kobodown::ContentKeys) -> Result<(), Report> {
+    use base64::Engine;
+
+    let keys = keys
+        .iter()
+        .map(|(name, key)| (name, base64::prelude::BASE64_STANDARD.encode(key)))
+        .collect::<BTreeMap<_, _>>();
+    let mut w = std::io::BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut w, &keys)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Makes sure the filesystem of `path` can hold the rest of the download and, when decrypting,
+/// the final file too since both exist until the end.
+fn check_space(path: &Path, partial: &Path, size: u64, decrypt: bool) -> Result<(), Report> {
+    let downloaded = std::fs::metadata(partial)
+        .map(|m| m.len().min(size))
+        .unwrap_or(0);
+    // The directories might not exist yet.
+    let existing = |path: &Path| {
+        path.ancestors()
+            .skip(1)
+            .map(|p| {
+                if p.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    p
+                }
+            })
+            .find(|p| p.is_dir())
+            .map(Path::to_path_buf)
+    };
+    let dir = existing(path);
+    let temp_dir = existing(partial);
+    let mut checks = Vec::with_capacity(2);
+    if dir == temp_dir {
+        checks.push((dir, size - downloaded + if decrypt { size } else { 0 }));
+    } else {
+        // Persisting across filesystems copies the download, so the output always needs it all.
+        checks.push((temp_dir, size - downloaded));
+        checks.push((dir, size));
+    }
+    for (dir, needed) in checks {
+        let Some(dir) = dir else {
+            continue;
         };
-        let f = File::create(&path)?;
-        let mut f = TempFile::from_parts(f, path);
-        kobo.download(session, &url, pb.wrap_write(&mut f))?;
-        f.keep();
+        let available = fs2::available_space(&dir)?;
+        if available < needed {
+            return Err(NoSpace {
+                dir,
+                needed,
+                available,
+            }
+            .into());
+        }
     }
     Ok(())
 }
 
+fn partial_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(".part");
+    path.into()
+}
+
 fn decrypt_zip<R: Read + Seek, W: Write + Seek, P: AsRef<Path>>(
-    keys: &HashMap<Box<str>, aes::cipher::Key<aes::Aes128Dec>>,
+    keys: &kobodown::ContentKeys,
     input: &mut R,
     output: &mut W,
     name: P,
+    download: &DownloadOptions,
     pb: &ProgressBar,
 ) -> Result<(), Report> {
-    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::DEFLATE);
-    let mut ziparchive = ZipWriter::new(output);
-    let mut inzip = ZipArchive::new(input)?;
     pb.disable_steady_tick();
     pb.reset();
     pb.set_style(decrypt_style());
-    pb.set_message(format!("Decrypting {}...", name.as_ref().display()));
-    pb.update(|ps| {
-        ps.set_len(inzip.len() as u64 * 2);
-        ps.set_pos(inzip.len() as u64);
-    });
-    pb.enable_steady_tick(DEFAULT_TICK);
-
-    for i in 0..inzip.len() {
-        let mut infile = inzip.by_index(i)?;
-        ziparchive.start_file(infile.name(), options)?;
-        if let Some(key) = keys.get(infile.name()) {
-            // PERF: ???
-            let mut data = Vec::with_capacity(infile.size() as usize);
-            infile.read_to_end(&mut data)?;
-            ziparchive.write_all(aes::cipher::BlockDecryptMut::decrypt_padded_mut::<
-                aes::cipher::block_padding::Pkcs7,
-            >(
-                <ecb::Decryptor<aes::Aes128> as aes::cipher::KeyInit>::new(key),
-                &mut data,
-            )?)?;
-        } else {
-            std::io::copy(&mut infile, &mut ziparchive)?;
+    set_message(pb, format!("Decrypting {}...", name.as_ref().display()));
+    enable_tick(pb);
+
+    kobodown::decrypt_book_with_progress(
+        keys,
+        input,
+        output,
+        &download.decrypt_options(),
+        |done, total| {
+            pb.update(|ps| {
+                ps.set_len(total as u64 * 2);
+                ps.set_pos((total + done) as u64);
+            })
+        },
+    )?;
+    Ok(())
+}
+
+// Most filesystems limit names to 255 bytes, leave room for the extension and for the suffixes
+// added to partial and duplicate files.
+const MAX_NAME_LEN: usize = 200;
+
+fn truncate_name(name: &mut String) {
+    if name.len() > MAX_NAME_LEN {
+        let mut len = MAX_NAME_LEN;
+        while !name.is_char_boundary(len) {
+            len -= 1;
         }
-        pb.inc(1);
+        name.truncate(name[..len].trim_end_matches([' ', '.']).len());
     }
-    ziparchive.finish()?;
-    Ok(())
 }
 
-fn mkname(author: Option<&str>, title: &str) -> String {
-    let mut name;
-    if let Some(author) = author.and_then(|a| if a.is_empty() { None } else { Some(a) }) {
-        name = sanitize_filename::sanitize(author);
-        let title = sanitize_filename::sanitize(title);
-        name.reserve_exact(title.len() + 8);
-        name.push_str(" - ");
-        name.push_str(&title);
-    } else {
-        name = sanitize_filename::sanitize(title);
-        name.reserve_exact(5);
+fn mkname(template: Option<&str>, author: Option<&str>, title: &str, revision_id: &str) -> String {
+    let mut name = template
+        .map(|t| render_template(t, author, title, revision_id))
+        .unwrap_or_default();
+    if name.is_empty() {
+        if let Some(author) = author.and_then(|a| if a.is_empty() { None } else { Some(a) }) {
+            name = sanitize_filename::sanitize(author);
+            let title = sanitize_filename::sanitize(title);
+            name.reserve_exact(title.len() + 8);
+            name.push_str(" - ");
+            name.push_str(&title);
+        } else {
+            name = sanitize_filename::sanitize(title);
+            name.reserve_exact(5);
+        }
+        truncate_name(&mut name);
     }
     name.push_str(".epub");
     name
 }
+
+const PLACEHOLDERS: [&str; 3] = ["author", "title", "revision_id"];
+
+fn date_parser(raw: &str) -> Result<time::OffsetDateTime, String> {
+    use time::format_description::well_known::{Iso8601, Rfc3339};
+
+    let raw = raw.trim();
+    time::OffsetDateTime::parse(raw, &Rfc3339)
+        .or_else(|_| time::Date::parse(raw, &Iso8601::DATE).map(|d| d.midnight().assume_utc()))
+        .map_err(|_| format!("invalid date {raw:?}, expected YYYY-MM-DD or an RFC 3339 timestamp"))
+}
+
+fn template_parser(raw: &str) -> Result<Box<str>, String> {
+    let mut rest = raw;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            return Err("unclosed '{'".to_string());
+        };
+        let name = &rest[start + 1..start + len];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(format!(
+                "unknown placeholder {{{name}}}, use one of {{author}}, {{title}} or {{revision_id}}"
+            ));
+        }
+        rest = &rest[start + len + 1..];
+    }
+    if raw
+        .split(std::path::is_separator)
+        .all(|c| c.trim().is_empty())
+    {
+        return Err("template cannot be empty".to_string());
+    }
+    Ok(raw.into())
+}
+
+/// Fills in a template checked by [`template_parser`], path separators in it make subdirectories.
+///
+/// Returns an empty string if every component turns out empty.
+fn render_template(template: &str, author: Option<&str>, title: &str, revision_id: &str) -> String {
+    let field = |name: &str| match name {
+        "author" => author.unwrap_or(""),
+        "title" => title,
+        _ => revision_id,
+    };
+    let mut res = String::new();
+    for component in template.split(std::path::is_separator) {
+        let mut name = String::new();
+        let mut rest = component;
+        while let Some(start) = rest.find('{') {
+            let len = rest[start..].find('}').unwrap();
+            name.push_str(&rest[..start]);
+            name.push_str(&sanitize_filename::sanitize(field(
+                &rest[start + 1..start + len],
+            )));
+            rest = &rest[start + len + 1..];
+        }
+        name.push_str(rest);
+        let mut name = tidy_name(&sanitize_filename::sanitize(name));
+        truncate_name(&mut name);
+        if !name.is_empty() {
+            if !res.is_empty() {
+                res.push('/');
+            }
+            res.push_str(&name);
+        }
+    }
+    res
+}
+
+/// Cleans up what empty fields leave behind: empty brackets, dangling separators and blanks.
+fn tidy_name(name: &str) -> String {
+    const SEPARATORS: [&str; 4] = ["-", "_", ",", "|"];
+    let name = name.replace("()", "").replace("[]", "");
+    let mut words: Vec<&str> = Vec::new();
+    for word in name.split_whitespace() {
+        if SEPARATORS.contains(&word) && words.last().is_none_or(|w| SEPARATORS.contains(w)) {
+            continue;
+        }
+        words.push(word);
+    }
+    while words.last().is_some_and(|w| SEPARATORS.contains(w)) {
+        words.pop();
+    }
+    words.join(" ").trim_matches([' ', '.']).to_string()
+}
+
+/// Numbers `name` until it clashes neither with an existing file nor with the names in `taken`.
+fn unique_name_among(dir: Option<&Path>, name: String, taken: &HashSet<PathBuf>) -> PathBuf {
+    number_name(name, |name| {
+        taken.contains(Path::new(name))
+            || match dir {
+                Some(dir) => dir.join(name).exists(),
+                None => Path::new(name).exists(),
+            }
+    })
+}
+
+/// Appends ` (2)`, ` (3)`, ... to the stem of `name` until `exists` says it is free.
+fn number_name(name: String, exists: impl Fn(&str) -> bool) -> PathBuf {
+    if !exists(&name) {
+        return name.into();
+    }
+    let (stem, ext) = name.rsplit_once('.').unwrap_or((&name, ""));
+    (2..)
+        .map(|i| format!("{stem} ({i}).{ext}"))
+        .find(|name| !exists(name))
+        .unwrap()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_errors_keep_their_exit_code() {
+        let of = |err: kobodown::Error<
+            <DefaultAgent as kobodown::Transport>::Error,
+            std::convert::Infallible,
+        >| { Exit::of(&session_error::<DefaultAgent, Config>(err.into())) };
+        assert_eq!(of(kobodown::Error::Cancelled), Exit::Cancelled);
+        assert_eq!(of(kobodown::Error::Timeout), Exit::Network);
+        assert_eq!(of(kobodown::Error::NotReady), Exit::Network);
+        assert_eq!(
+            Exit::of(&session_error::<DefaultAgent, Config>(
+                NoMatch("book").into()
+            )),
+            Exit::NotFound
+        );
+    }
+
+    #[test]
+    fn reverse_keeps_undated_and_authorless_books_last() {
+        use clap::Parser;
+
+        #[derive(clap::Parser)]
+        struct Args {
+            #[command(flatten)]
+            filter: BookFilter,
+        }
+
+        let book = |title: &str, authors: Option<&str>, added: Option<&str>| {
+            serde_json::from_value::<kobodown::Book>(serde_json::json!({
+                "authors": authors,
+                "title": title,
+                "revision_id": "a0e6e4c5-8f3b-4c2e-9d2b-7f1a3c5e9b10",
+                "is_archived": false,
+                "date_added": added,
+            }))
+            .unwrap()
+        };
+        let sorted = |args: &[&str]| {
+            let mut books = vec![
+                book("a", None, Some("2024-01-01T00:00:00Z")),
+                book("b", Some("Y"), None),
+                book("c", Some("X"), Some("2025-01-01T00:00:00Z")),
+            ];
+            let args = Args::parse_from(std::iter::once("test").chain(args.iter().copied()));
+            args.filter.apply(&mut books);
+            books
+                .iter()
+                .map(|book| book.title.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(sorted(&["--sort", "added"]), ["c", "a", "b"]);
+        assert_eq!(sorted(&["--sort", "added", "--reverse"]), ["a", "c", "b"]);
+        assert_eq!(sorted(&["--sort", "author"]), ["c", "b", "a"]);
+        assert_eq!(sorted(&["--sort", "author", "--reverse"]), ["b", "c", "a"]);
+        assert_eq!(sorted(&["--reverse"]), ["c", "b", "a"]);
+    }
+}
@@ -33,6 +33,16 @@ pub trait Session {
 
     fn set_user_key<S: Into<String>>(&mut self, v: Option<S>);
 
+    /// Where the last complete library sync ended. Sessions not storing it always sync
+    /// the whole library.
+    fn library_sync_token(&self) -> Option<&NonEmptyStr> {
+        None
+    }
+
+    fn set_library_sync_token<S: Into<String>>(&mut self, v: Option<S>) {
+        let _ = v;
+    }
+
     fn save(&self) -> Result<(), Self::Error>;
 }
 
@@ -99,11 +109,85 @@ impl<'a, S: Session + 'a> Session for &'a mut S {
         <S as Session>::set_user_key(self, v)
     }
 
+    fn library_sync_token(&self) -> Option<&NonEmptyStr> {
+        <S as Session>::library_sync_token(self)
+    }
+
+    fn set_library_sync_token<Str: Into<String>>(&mut self, v: Option<Str>) {
+        <S as Session>::set_library_sync_token(self, v)
+    }
+
     fn save(&self) -> Result<(), Self::Error> {
         <S as Session>::save(self)
     }
 }
 
+/// What a session says about the login, found without asking Kobo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginStatus {
+    /// Some of the tokens are missing, a login is needed.
+    LoggedOut,
+    /// The access token has expired, the next request refreshes it.
+    Expired,
+    LoggedIn,
+}
+
+impl LoginStatus {
+    /// Checks which tokens `session` holds. The expiry of the access token is only known when
+    /// it is a JWT, otherwise it is assumed valid.
+    pub fn of<S: Session>(session: &S) -> Self {
+        if session.access_token().is_none()
+            || session.device_id().is_none()
+            || session.refresh_token().is_none()
+            || session.user_key().is_none()
+            || session.user_id().is_none()
+        {
+            return Self::LoggedOut;
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match session.access_token().and_then(|t| token_expiry(t)) {
+            Some(exp) if exp <= now => Self::Expired,
+            _ => Self::LoggedIn,
+        }
+    }
+}
+
+impl core::fmt::Display for LoginStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::LoggedOut => "logged out",
+            Self::Expired => "expired",
+            Self::LoggedIn => "logged in",
+        })
+    }
+}
+
+/// The `exp` claim of `token` if it is a JWT.
+fn token_expiry(token: &str) -> Option<u64> {
+    use base64::Engine;
+
+    #[derive(serde::Deserialize)]
+    struct Claims {
+        exp: u64,
+    }
+
+    let mut parts = token.split('.');
+    let (Some(_), Some(payload), Some(_), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    let payload = base64::prelude::BASE64_URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    serde_json::from_slice::<Claims>(&payload)
+        .ok()
+        .map(|c| c.exp)
+}
+
 pub struct SessionAdapter<S>(S);
 
 impl<S> SessionAdapter<S> {
@@ -232,3 +316,94 @@ impl<S: Session> SessionAdapter<S> {
         self.0
     }
 }
+
+/// A [`Session`] living only in memory, saving does nothing.
+#[derive(Debug, Default)]
+pub struct InMemorySession {
+    pub access_token: Option<Box<NonEmptyStr>>,
+    pub device_id: Option<Box<NonEmptyStr>>,
+    pub refresh_token: Option<Box<NonEmptyStr>>,
+    pub user_id: Option<Box<NonEmptyStr>>,
+    pub user_key: Option<Box<NonEmptyStr>>,
+    pub library_sync_token: Option<Box<NonEmptyStr>>,
+}
+
+fn non_empty<S: Into<String>>(v: Option<S>) -> Option<Box<NonEmptyStr>> {
+    v.map(Into::into).and_then(NonEmptyStr::from_string)
+}
+
+impl Session for InMemorySession {
+    type Error = core::convert::Infallible;
+
+    fn access_token(&self) -> Option<&NonEmptyStr> {
+        self.access_token.as_deref()
+    }
+
+    fn device_id(&self) -> Option<&NonEmptyStr> {
+        self.device_id.as_deref()
+    }
+
+    fn refresh_token(&self) -> Option<&NonEmptyStr> {
+        self.refresh_token.as_deref()
+    }
+
+    fn user_id(&self) -> Option<&NonEmptyStr> {
+        self.user_id.as_deref()
+    }
+
+    fn user_key(&self) -> Option<&NonEmptyStr> {
+        self.user_key.as_deref()
+    }
+
+    fn remove_access_token(&mut self) {
+        self.access_token = None;
+    }
+
+    fn remove_device_id(&mut self) {
+        self.device_id = None;
+    }
+
+    fn remove_refresh_token(&mut self) {
+        self.refresh_token = None;
+    }
+
+    fn remove_user_id(&mut self) {
+        self.user_id = None;
+    }
+
+    fn remove_user_key(&mut self) {
+        self.user_key = None;
+    }
+
+    fn set_access_token<S: Into<String>>(&mut self, v: Option<S>) {
+        self.access_token = non_empty(v);
+    }
+
+    fn set_device_id<S: Into<String>>(&mut self, v: Option<S>) {
+        self.device_id = non_empty(v);
+    }
+
+    fn set_refresh_token<S: Into<String>>(&mut self, v: Option<S>) {
+        self.refresh_token = non_empty(v);
+    }
+
+    fn set_user_id<S: Into<String>>(&mut self, v: Option<S>) {
+        self.user_id = non_empty(v);
+    }
+
+    fn set_user_key<S: Into<String>>(&mut self, v: Option<S>) {
+        self.user_key = non_empty(v);
+    }
+
+    fn library_sync_token(&self) -> Option<&NonEmptyStr> {
+        self.library_sync_token.as_deref()
+    }
+
+    fn set_library_sync_token<S: Into<String>>(&mut self, v: Option<S>) {
+        self.library_sync_token = non_empty(v);
+    }
+
+    fn save(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}